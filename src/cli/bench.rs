@@ -0,0 +1,252 @@
+//! Implementation of the benchmark CLI command
+
+use anyhow::Result;
+use colored::Colorize;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use super::Verbosity;
+use crate::model::{self, download::DownloadConfig, inference::ModelConfig, ModelStore};
+use crate::utils::format_file_size;
+
+/// Filler for the benchmark prompt, roughly ten tokens per repetition
+const PROMPT_SENTENCE: &str = "The quick brown fox jumps over the lazy dog. ";
+
+/// Options for a benchmark
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    /// Tokens to generate in each run
+    pub tokens: usize,
+    /// Approximate prompt length in tokens
+    pub prompt_tokens: usize,
+    /// Number of runs to average over
+    pub runs: usize,
+    pub verbosity: Verbosity,
+}
+
+/// Quantizations of one Hugging Face repository to benchmark side by side
+#[derive(Debug, Clone)]
+pub struct CompareOptions {
+    /// Repository holding the GGUF files, e.g. `TheBloke/Mistral-7B-v0.1-GGUF`
+    pub repo: String,
+    /// Quantization names matched against the file names, e.g. `q4_k_m`
+    pub quantizations: Vec<String>,
+    /// Delete the files downloaded for the comparison once it is done
+    pub cleanup: bool,
+}
+
+/// What one benchmark measured, averaged over its runs
+struct BenchResult {
+    load_time: Duration,
+    prompt_rate: f64,
+    generation_rate: f64,
+    /// Resident memory with the model loaded, where the platform reports it
+    memory: Option<u64>,
+}
+
+/// Load a model and measure prompt evaluation and generation speed
+pub async fn bench_command(
+    model_name: &str,
+    store: &dyn ModelStore,
+    model_config: ModelConfig,
+    options: BenchOptions,
+) -> Result<()> {
+    options.verbosity.info("RustLLM Benchmark".bold().green());
+    let result = run_bench(model_name, store, model_config, &options)?;
+
+    println!();
+    println!("{}", "Summary".bold().green());
+    println!("Load time:    {:.2}s", result.load_time.as_secs_f64());
+    println!("Prompt eval:  {:.2} tokens/s (average of {} runs)", result.prompt_rate, options.runs);
+    println!("Generation:   {:.2} tokens/s (average of {} runs)", result.generation_rate, options.runs);
+    if let Some(memory) = result.memory {
+        println!("Memory:       {} resident", format_file_size(memory));
+    }
+
+    Ok(())
+}
+
+/// Benchmark several quantizations of the same model and print them in one table
+///
+/// Each quantization is the repository's GGUF file whose name contains it.
+/// Files already in a models directory are used as they are; the others are
+/// downloaded first and, with `cleanup`, deleted after their benchmark.
+pub async fn compare_command(
+    store: &dyn ModelStore,
+    download_config: &DownloadConfig,
+    model_config: ModelConfig,
+    compare: CompareOptions,
+    options: BenchOptions,
+) -> Result<()> {
+    let verbosity = options.verbosity;
+    verbosity.info(format_args!("{} {}", "Comparing quantizations of".bold().green(), compare.repo.bold()));
+
+    // Match every quantization before downloading anything
+    let files = model::download::hf_repo_gguf_files(&compare.repo, download_config).await?;
+    let mut chosen = Vec::new();
+    for quantization in &compare.quantizations {
+        let needle = quantization.to_lowercase();
+        let matches: Vec<&String> = files
+            .iter()
+            .filter(|file| file.to_lowercase().contains(&needle))
+            .collect();
+        match matches.as_slice() {
+            [file] => chosen.push((quantization, file.to_string())),
+            [] => anyhow::bail!("No GGUF file in {} matches quantization {}", compare.repo, quantization),
+            _ => {
+                let names: Vec<&str> = matches.iter().map(|file| file.as_str()).collect();
+                anyhow::bail!("Quantization {} matches several files in {}: {}", quantization, compare.repo, names.join(", "))
+            }
+        }
+    }
+
+    let download_dir = model::download_dir(store.dirs());
+    let mut rows = Vec::new();
+    for (quantization, file) in chosen {
+        // Files in repository subdirectories are stored under their base name
+        let filename = Path::new(&file).file_name().map_or(file.clone(), |name| name.to_string_lossy().into_owned());
+        let existing = store.dirs().iter().map(|dir| dir.join(&filename)).find(|path| store.exists(path));
+        let (path, downloaded) = match existing {
+            Some(path) => {
+                verbosity.info(format_args!("{}: using {:?}", quantization.bold(), path));
+                (path, false)
+            }
+            None => {
+                let path = download_dir.join(&filename);
+                verbosity.info(format_args!("{}: downloading {}", quantization.bold(), file));
+                let url = model::download::hf_file_url(&compare.repo, &file);
+                model::download::download_model_file(&url, &path, "", download_config).await?;
+                (path, true)
+            }
+        };
+        let size = std::fs::metadata(&path).map(|metadata| metadata.len()).unwrap_or(0);
+
+        let result = run_bench(&store_model_name(store, &path), store, model_config.clone(), &options);
+        if downloaded && compare.cleanup {
+            match store.delete(&path) {
+                Ok(()) => verbosity.info(format_args!("Removed {:?}", path)),
+                Err(e) => println!("{} Failed to remove {:?}: {}", "Warning:".yellow().bold(), path, e),
+            }
+        }
+        rows.push((quantization, size, result?));
+    }
+
+    println!();
+    println!(
+        "{:<14} {:>12} {:>10} {:>18} {:>18} {:>12}",
+        "Quantization".bold(), "Size".bold(), "Load".bold(), "Prompt eval tok/s".bold(), "Generation tok/s".bold(), "Memory".bold()
+    );
+    println!("{}", "-".repeat(89));
+    for (quantization, size, result) in rows {
+        println!(
+            "{:<14} {:>12} {:>9.2}s {:>18.2} {:>18.2} {:>12}",
+            quantization,
+            format_file_size(size),
+            result.load_time.as_secs_f64(),
+            result.prompt_rate,
+            result.generation_rate,
+            result.memory.map_or_else(|| "-".to_string(), format_file_size)
+        );
+    }
+
+    Ok(())
+}
+
+/// The name `store` resolves to `path`, a file in one of its directories
+///
+/// The store refuses absolute paths, so models found or downloaded for a
+/// comparison are loaded by their path relative to their models directory.
+fn store_model_name(store: &dyn ModelStore, path: &Path) -> String {
+    model::model_display_name(path, store.dirs())
+}
+
+/// Load a model, print a line per run and return the averages
+fn run_bench(
+    model_name: &str,
+    store: &dyn ModelStore,
+    model_config: ModelConfig,
+    options: &BenchOptions,
+) -> Result<BenchResult> {
+    let verbosity = options.verbosity;
+    verbosity.info(format_args!("Loading model: {}", model_name.bold()));
+
+    let start_time = Instant::now();
+    let mut model = model::load_model(model_name, store, model_config)?;
+    let load_time = start_time.elapsed();
+    verbosity.info(format_args!("Model loaded in {:.2}s", load_time.as_secs_f64()));
+
+    // Greedy, fixed-length generation so every run does the same work
+    model.set_max_tokens(options.tokens);
+    model.set_temperature(0.0);
+    model.set_ignore_eos(true);
+    model.set_repeat_window(None);
+
+    let prompt = PROMPT_SENTENCE.repeat((options.prompt_tokens / 10).max(1));
+
+    verbosity.info("");
+    println!(
+        "{:<6} {:>14} {:>18} {:>14} {:>18}",
+        "Run".bold(), "Prompt tokens".bold(), "Prompt eval tok/s".bold(), "Gen tokens".bold(), "Generation tok/s".bold()
+    );
+    println!("{}", "-".repeat(74));
+
+    let mut prompt_rates = Vec::with_capacity(options.runs);
+    let mut generation_rates = Vec::with_capacity(options.runs);
+
+    for run in 1..=options.runs {
+        // Decode the whole prompt every run rather than reusing the KV cache
+        model.clear_cache();
+        let completion = model.complete(&prompt)?;
+
+        let prompt_rate = tokens_per_second(completion.prompt_tokens, completion.prompt_eval_time);
+        let generation_rate = tokens_per_second(completion.completion_tokens, completion.generation_time);
+        println!(
+            "{:<6} {:>14} {:>18.2} {:>14} {:>18.2}",
+            run, completion.prompt_tokens, prompt_rate, completion.completion_tokens, generation_rate
+        );
+
+        prompt_rates.push(prompt_rate);
+        generation_rates.push(generation_rate);
+    }
+
+    Ok(BenchResult {
+        load_time,
+        prompt_rate: mean(&prompt_rates),
+        generation_rate: mean(&generation_rates),
+        memory: crate::utils::resident_memory_bytes(),
+    })
+}
+
+fn tokens_per_second(tokens: usize, elapsed: Duration) -> f64 {
+    if elapsed.is_zero() {
+        0.0
+    } else {
+        tokens as f64 / elapsed.as_secs_f64()
+    }
+}
+
+fn mean(values: &[f64]) -> f64 {
+    if values.is_empty() {
+        0.0
+    } else {
+        values.iter().sum::<f64>() / values.len() as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::store::MemoryModelStore;
+
+    #[test]
+    fn compared_files_resolve_through_the_store() {
+        let store = MemoryModelStore::new(&["/fast", "/models"], &["mistral-7b.Q8_0.gguf"]);
+        store.add("/models/mistral-7b.Q4_K_M.gguf");
+        store.add("/models/mistral/mistral-7b.Q5_K_M.gguf");
+
+        for path in ["/fast/mistral-7b.Q8_0.gguf", "/models/mistral-7b.Q4_K_M.gguf", "/models/mistral/mistral-7b.Q5_K_M.gguf"] {
+            let name = store_model_name(&store, Path::new(path));
+            assert_eq!(store.resolve(&name).unwrap(), Path::new(path), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,24 @@
+//! Implementation of the config CLI commands
+
+use anyhow::Result;
+use colored::Colorize;
+
+use crate::config::{Config, Setting};
+
+/// Print the effective configuration and where each value came from
+pub fn show_config_command(settings: &[Setting]) -> Result<()> {
+    match Config::path() {
+        Some(path) if path.exists() => println!("Config file: {}", path.display()),
+        Some(path) => println!("Config file: {} (not found)", path.display()),
+        None => println!("Config file: (no home directory)"),
+    }
+    println!();
+
+    println!("{:<16} {:<40} {}", "Setting".bold(), "Value".bold(), "Source".bold());
+    println!("{}", "-".repeat(72));
+    for setting in settings {
+        println!("{:<16} {:<40} {}", setting.key, setting.value, setting.source);
+    }
+
+    Ok(())
+}
@@ -1,38 +1,189 @@
 //! CLI interface for the Rust-based LLM chat tool
 
+pub mod bench;
+pub mod config_commands;
+pub mod model_commands;
+pub mod ping;
+pub mod stop;
+
 use crate::model::{
     self, 
-    inference::{ChatContext, ChatMessage, Model}
+    inference::{ChatContext, ChatMessage, ChatRole, Completion, Model, ModelConfig, SamplerStep},
+    ModelStore,
 };
 use crate::utils::{format_message, format_duration};
-use anyhow::Result;
+use anyhow::{Context, Result};
 use colored::Colorize;
+use indicatif::{ProgressBar, ProgressStyle};
 use rustyline::DefaultEditor;
 use rustyline::error::ReadlineError;
+use std::io::Write;
 use std::path::Path;
-use std::time::Instant;
+use std::time::{Duration, Instant};
+
+/// How much informational output (banners, progress, status lines) commands print
+///
+/// Command results, warnings and errors are printed either way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Verbosity {
+    /// Only command output and errors
+    Quiet,
+    #[default]
+    Normal,
+}
+
+impl Verbosity {
+    pub fn is_quiet(self) -> bool {
+        self == Verbosity::Quiet
+    }
+    
+    /// Print an informational line unless quiet
+    pub fn info(self, message: impl std::fmt::Display) {
+        if !self.is_quiet() {
+            println!("{}", message);
+        }
+    }
+}
+
+/// Options for an interactive chat session
+#[derive(Debug, Clone, Default)]
+pub struct ChatOptions {
+    /// Replaces the default system prompt
+    pub system_prompt: Option<String>,
+    /// Overrides the default number of messages kept in context
+    pub max_messages: Option<usize>,
+    /// Earlier conversation the chat continues from
+    pub history: Vec<ChatMessage>,
+    /// Percentage of the context window the prompt may fill before a warning
+    /// is printed after a turn (0 never warns)
+    pub context_warning: usize,
+    /// Print the welcome text and command hints (also skipped when quiet)
+    pub banner: bool,
+    /// Print the token usage of each response
+    pub stats: bool,
+    /// Sampling overrides applied before the first message
+    pub max_tokens: Option<usize>,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub top_k: Option<usize>,
+    pub verbosity: Verbosity,
+}
+
+/// Sampling overrides for a one-shot completion
+#[derive(Debug, Clone, Default)]
+pub struct CompletionOptions {
+    pub max_tokens: Option<usize>,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub top_k: Option<usize>,
+}
+
+/// Continue `prompt` with the specified model and print the result
+///
+/// Only the generated text goes to stdout so the output can be piped.
+pub async fn run_completion(
+    model_name: &str,
+    store: &dyn ModelStore,
+    model_config: ModelConfig,
+    prompt: &str,
+    options: CompletionOptions,
+) -> Result<()> {
+    let mut model = model::load_model(model_name, store, model_config)?;
+    
+    if let Some(max_tokens) = options.max_tokens {
+        model.set_max_tokens(max_tokens);
+    }
+    if let Some(temperature) = options.temperature {
+        model.set_temperature(temperature);
+    }
+    if let Some(top_p) = options.top_p {
+        model.set_top_p(top_p);
+    }
+    if let Some(top_k) = options.top_k {
+        model.set_top_k(top_k);
+    }
+    
+    let completion = model.complete(prompt)?;
+    println!("{}", completion.text);
+    
+    Ok(())
+}
 
 /// Start the interactive chat CLI with the specified model
-pub async fn start_chat(model_name: &str, models_dir: &Path) -> Result<()> {
-    println!("{}", "Starting RustLLM Chat".bold().green());
-    println!("Loading model: {}", model_name.bold());
+pub async fn start_chat(
+    model_name: &str,
+    store: &dyn ModelStore,
+    model_config: ModelConfig,
+    options: ChatOptions,
+) -> Result<()> {
+    let verbosity = options.verbosity;
+    
+    // Check the sampling flags before spending time on loading, with the same
+    // limits as the slash commands
+    if options.temperature.is_some_and(|temp| !(0.0..=1.0).contains(&temp)) {
+        anyhow::bail!("Temperature must be between 0.0 and 1.0");
+    }
+    if options.max_tokens == Some(0) {
+        anyhow::bail!("Max tokens must be greater than 0");
+    }
+    if options.top_p.is_some_and(|top_p| !(top_p > 0.0 && top_p <= 1.0)) {
+        anyhow::bail!("Top-p must be greater than 0.0 and at most 1.0");
+    }
+    
+    verbosity.info("Starting RustLLM Chat".bold().green());
+    verbosity.info(format_args!("Loading model: {}", model_name.bold()));
     
     // Load the model
     let start_time = Instant::now();
-    let mut model = model::load_model(model_name, models_dir)?;
+    let mut model = model::load_model(model_name, store, model_config)?;
     let load_duration = start_time.elapsed();
-    println!("Model loaded in {}", format_duration(load_duration.as_secs()).bold());
+    verbosity.info(format_args!("Model loaded in {}", format_duration(load_duration.as_secs()).bold()));
+    if let Some(max_tokens) = options.max_tokens {
+        model.set_max_tokens(max_tokens);
+    }
+    if let Some(temperature) = options.temperature {
+        model.set_temperature(temperature);
+    }
+    if let Some(top_p) = options.top_p {
+        model.set_top_p(top_p);
+    }
+    if let Some(top_k) = options.top_k {
+        model.set_top_k(top_k);
+    }
+    if !model.is_chat_capable() {
+        println!("{}", "Warning: this model has no chat template and may not follow the conversation. Try `rustllm complete` instead.".yellow());
+    }
     
     // Initialize chat context
-    let mut context = ChatContext::default();
+    let mut context = match &options.system_prompt {
+        Some(prompt) => ChatContext::new(prompt),
+        None => ChatContext::default(),
+    };
+    context.context_size = model.get_config().context_size;
+    if let Some(max_messages) = options.max_messages {
+        context.set_max_messages(max_messages);
+    }
+    if !options.history.is_empty() {
+        let loaded = options.history.len();
+        for message in options.history {
+            context.add_message(message);
+        }
+        verbosity.info(format_args!(
+            "Continuing from {} earlier messages ({} kept in context)",
+            loaded,
+            context.messages.len()
+        ));
+    }
     
     // Print welcome message
-    println!("\n{}", "Welcome to RustLLM Chat!".bold().green());
-    println!("Type your messages to chat with the model.");
-    println!("Use {}, {}, or {} to exit the chat.", "/quit".bold(), "/exit".bold(), "Ctrl+D".bold());
-    println!("Use {} to change parameters (temperature, etc.)", "/params".bold());
-    println!("Use {} to clear the conversation history.", "/clear".bold());
-    println!("");
+    if options.banner && !verbosity.is_quiet() {
+        println!("\n{}", "Welcome to RustLLM Chat!".bold().green());
+        println!("Type your messages to chat with the model.");
+        println!("Use {}, {}, or {} to exit the chat.", "/quit".bold(), "/exit".bold(), "Ctrl+D".bold());
+        println!("Use {} to change parameters (temperature, etc.)", "/params".bold());
+        println!("Use {} to clear the conversation history.", "/clear".bold());
+        println!();
+    }
     
     // Start interactive prompt
     let mut rl = DefaultEditor::new()?;
@@ -70,13 +221,45 @@ pub async fn start_chat(model_name: &str, models_dir: &Path) -> Result<()> {
                 // Add the user message to context
                 context.add_message(ChatMessage::user(&line));
                 
-                // Generate a response
-                println!("\n{}: ", "Assistant".bold().blue());
-                match model.generate(&context) {
-                    Ok(response) => {
+                // Generate a response, printing it as it streams in. Until the
+                // first token arrives the prompt is still being evaluated, so a
+                // spinner stands in for the reply. The prefix is printed whole so
+                // its color codes are closed before any token.
+                let spinner = thinking_spinner(verbosity);
+                let print_prefix = || {
+                    spinner.finish_and_clear();
+                    print!("\n{} ", "Assistant:".bold().blue());
+                };
+                let mut first_token = true;
+                let mut ends_with_newline = false;
+                let result = model.generate_stream(&context, |token| {
+                    if std::mem::take(&mut first_token) {
+                        print_prefix();
+                    }
+                    print!("{}", token);
+                    let _ = std::io::stdout().flush();
+                    if !token.is_empty() {
+                        ends_with_newline = token.ends_with('\n');
+                    }
+                });
+                if first_token {
+                    print_prefix();
+                }
+                if !ends_with_newline {
+                    println!();
+                }
+                
+                match result {
+                    Ok(completion) => {
+                        if options.stats {
+                            print_token_usage(&completion);
+                        }
                         // Add the assistant's response to the context
-                        context.add_message(ChatMessage::assistant(&response));
-                        println!(); // Add a newline after the response
+                        context.add_message(ChatMessage::assistant(completion.text));
+                        if options.context_warning > 0 {
+                            warn_if_context_nearly_full(&model, &context, options.context_warning);
+                        }
+                        println!(); // Blank line before the next prompt
                     }
                     Err(e) => {
                         println!("{}: Failed to generate response: {}", "Error".bold().red(), e);
@@ -97,6 +280,59 @@ pub async fn start_chat(model_name: &str, models_dir: &Path) -> Result<()> {
     Ok(())
 }
 
+/// A "Thinking..." spinner on stderr, shown while the prompt is evaluated
+///
+/// Hidden when quiet; indicatif also hides it when stderr isn't a terminal.
+fn thinking_spinner(verbosity: Verbosity) -> ProgressBar {
+    if verbosity.is_quiet() {
+        return ProgressBar::hidden();
+    }
+    let spinner = ProgressBar::new_spinner();
+    spinner.set_style(ProgressStyle::default_spinner().template("{spinner:.green} {msg}").expect("valid template"));
+    spinner.set_message("Thinking...");
+    spinner.enable_steady_tick(Duration::from_millis(100));
+    spinner
+}
+
+/// Print a dim line with the token counts of a response, as the server
+/// reports them in `usage`
+fn print_token_usage(completion: &Completion) {
+    println!(
+        "{}",
+        format!(
+            "Tokens: {} prompt + {} completion = {} total",
+            completion.prompt_tokens,
+            completion.completion_tokens,
+            completion.prompt_tokens + completion.completion_tokens
+        )
+        .dimmed()
+    );
+}
+
+/// Print a dim warning when the next turn's prompt already fills at least
+/// `percent` of the context window
+fn warn_if_context_nearly_full(model: &Model, context: &ChatContext, percent: usize) {
+    let prompt = model.format_prompt(context);
+    let Ok(tokens) = model.count_tokens(&prompt) else {
+        return;
+    };
+    let context_size = model.get_config().context_size;
+    if context_size == 0 || tokens * 100 < context_size * percent {
+        return;
+    }
+    println!(
+        "{}",
+        format!(
+            "Context is {}% full ({}/{} tokens). Use /clear to start over; the oldest messages are only dropped once there are more than {}.",
+            tokens * 100 / context_size,
+            tokens,
+            context_size,
+            context.max_messages
+        )
+        .dimmed()
+    );
+}
+
 /// Handle chat commands that begin with "/"
 fn handle_command(
     command: &str, 
@@ -111,8 +347,8 @@ fn handle_command(
         }
         
         "/clear" => {
-            // Clear conversation history
-            *context = ChatContext::new(&context.system_prompt);
+            // Clear conversation history, keeping the system prompt and limits
+            context.messages.clear();
             println!("{}", "Conversation history cleared.".bold().green());
         }
         
@@ -122,20 +358,84 @@ fn handle_command(
             println!("  {} - Clear conversation history", "/clear".bold());
             println!("  {} - Show this help message", "/help".bold());
             println!("  {} - Show current parameters", "/params".bold());
+            println!("  {} - Show current model parameters as JSON", "/params json".bold());
             println!("  {} - Change temperature (0.0-1.0)", "/temp <value>".bold());
             println!("  {} - Change maximum response tokens", "/max_tokens <value>".bold());
+            println!("  {} - Change the sampler order, e.g. top_p,temp (or default)", "/sampler <steps>".bold());
             println!("  {} - Change system prompt", "/system <prompt>".bold());
+            println!("  {} - Start replies with this text, e.g. a persona's name (or clear it)", "/prefix [text]".bold());
+            println!("  {} - Change how many messages are kept in context", "/max_messages <value>".bold());
+            println!("  {} - Show message count and estimated tokens", "/history".bold());
+            println!("  {} - Show the full prompt sent to the model", "/preview".bold());
+            println!("  {} - Save the conversation (.md, .json or .txt)", "/export <file>".bold());
         }
         
         "/params" => {
             // Display current parameters
+            let params = model.parameter_snapshot();
             println!("{}", "Current parameters:".bold());
             println!("  System prompt: {}", context.system_prompt);
-            println!("  Temperature: {}", model.get_temperature());
-            println!("  Max tokens: {}", model.get_max_tokens());
+            if let Some(prefix) = &context.response_prefix {
+                println!("  Reply prefix: {}", prefix);
+            }
+            println!("  Temperature: {}", params.temperature);
+            println!("  Top-p: {}", params.top_p);
+            match params.top_k {
+                0 => println!("  Top-k: off"),
+                top_k => println!("  Top-k: {}", top_k),
+            }
+            println!("  Max tokens: {}", params.max_tokens);
+            println!("  Context size: {}", params.context_size);
+            match params.n_threads {
+                Some(threads) => println!("  Threads: {}", threads),
+                None => println!("  Threads: auto"),
+            }
+            println!("  GPU layers: {}", params.n_gpu_layers);
+            println!("  Template: {:?}", params.template);
+            let chain: Vec<&str> = params.sampler_chain.iter().map(|step| step.name()).collect();
+            println!("  Sampler chain: {}", chain.join(" -> "));
             println!("  Messages in context: {}/{}", context.messages.len(), context.max_messages);
         }
         
+        "/params json" => {
+            // Only the model's parameters, for scripts to parse
+            println!("{}", serde_json::to_string_pretty(&model.parameter_snapshot())?);
+        }
+        
+        "/preview" => {
+            // Show the rendered prompt, special tokens included
+            println!("{} ({:?} template):", "Prompt".bold(), model.template());
+            println!("{}", model.format_prompt(context));
+        }
+        
+        "/prefix" => {
+            context.response_prefix = None;
+            println!("Reply prefix cleared");
+        }
+        
+        "/history" => {
+            println!("{}", "Conversation history:".bold());
+            println!("  Messages: {}/{}", context.messages.len(), context.max_messages);
+            println!("  Estimated tokens: {}/{}", context.estimated_tokens(), context.context_size);
+        }
+        
+        _ if cmd.starts_with("/sampler ") => {
+            // Change the order of the sampling steps
+            let spec = cmd.trim_start_matches("/sampler ").trim();
+            let chain = if spec == "default" {
+                SamplerStep::DEFAULT_CHAIN.to_vec()
+            } else {
+                let names: Vec<&str> = spec
+                    .split([',', ' '])
+                    .filter(|name| !name.is_empty())
+                    .collect();
+                SamplerStep::parse_chain(&names).map_err(anyhow::Error::msg)?
+            };
+            let names: Vec<&str> = chain.iter().map(|step| step.name()).collect();
+            model.set_sampler_chain(chain);
+            println!("{} {}", "Sampler chain set to".bold().green(), names.join(" -> "));
+        }
+        
         _ if cmd.starts_with("/temp ") => {
             // Change temperature
             if let Some(temp_str) = cmd.strip_prefix("/temp ") {
@@ -172,6 +472,41 @@ fn handle_command(
             }
         }
         
+        _ if cmd.starts_with("/max_messages ") => {
+            // Change how many messages are kept
+            if let Some(value_str) = cmd.strip_prefix("/max_messages ") {
+                match value_str.trim().parse::<usize>() {
+                    Ok(max_messages) if max_messages > 0 => {
+                        context.set_max_messages(max_messages);
+                        println!("Max messages set to {}", max_messages);
+                    }
+                    Ok(_) => {
+                        println!("{}: Max messages must be greater than 0", "Error".bold().red());
+                    }
+                    Err(_) => {
+                        println!("{}: Invalid max messages value", "Error".bold().red());
+                    }
+                }
+            }
+        }
+        
+        _ if cmd.starts_with("/export ") => {
+            // Save the conversation to a file
+            if let Some(path) = cmd.strip_prefix("/export ") {
+                let path = Path::new(path.trim());
+                export_conversation(context, path)?;
+                println!("Conversation exported to {}", path.display());
+            }
+        }
+        
+        _ if cmd.starts_with("/prefix ") => {
+            // Make replies continue from this text, to keep a persona in character
+            if let Some(prefix) = cmd.strip_prefix("/prefix ") {
+                context.response_prefix = Some(prefix.to_string());
+                println!("Replies will start with {:?}", prefix);
+            }
+        }
+        
         _ if cmd.starts_with("/system ") => {
             // Change system prompt
             if let Some(prompt) = cmd.strip_prefix("/system ") {
@@ -191,20 +526,112 @@ fn handle_command(
 
 /// Format the chat history for display
 pub fn display_chat_history(context: &ChatContext) -> String {
+    format_chat_history(context, true)
+}
+
+/// Format the chat history, with or without ANSI colors
+fn format_chat_history(context: &ChatContext, colored: bool) -> String {
     let mut result = String::new();
     
-    result.push_str(&format!("{}\n", "System:".bold().yellow()));
+    if colored {
+        result.push_str(&format!("{}\n", "System:".bold().yellow()));
+    } else {
+        result.push_str("System:\n");
+    }
     result.push_str(&format!("{}\n\n", context.system_prompt));
     
     for message in &context.messages {
-        let role = match message.role {
-            crate::model::inference::ChatRole::User => "User",
-            crate::model::inference::ChatRole::Assistant => "Assistant",
-        };
+        let role = role_label(&message.role);
         
-        result.push_str(&format_message(role, &message.content));
+        if colored {
+            result.push_str(&format_message(role, &message.content));
+        } else {
+            result.push_str(&format!("{}: {}", role, message.content));
+        }
         result.push_str("\n\n");
     }
     
     result
-}
\ No newline at end of file
+}
+
+/// Format the chat history as a Markdown document
+fn format_chat_history_markdown(context: &ChatContext) -> String {
+    let mut result = String::from("# Conversation\n\n");
+    
+    result.push_str(&format!("## System\n\n{}\n\n", context.system_prompt));
+    
+    for message in &context.messages {
+        result.push_str(&format!("## {}\n\n{}\n\n", role_label(&message.role), message.content));
+    }
+    
+    result
+}
+
+/// Display name of a message role
+fn role_label(role: &ChatRole) -> &'static str {
+    match role {
+        ChatRole::User => "User",
+        ChatRole::Assistant => "Assistant",
+        ChatRole::System => "System",
+    }
+}
+
+/// Read the messages of an earlier conversation from a JSON file: either an
+/// array of `{ "role", "content" }` objects or a `/export` JSON file
+pub fn read_history_file(path: &Path) -> Result<Vec<ChatMessage>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read history file {:?}", path))?;
+    let json: serde_json::Value = serde_json::from_str(&contents)
+        .with_context(|| format!("History file {:?} is not valid JSON", path))?;
+    let entries = match json {
+        serde_json::Value::Array(entries) => entries,
+        serde_json::Value::Object(mut export) => match export.remove("messages") {
+            Some(serde_json::Value::Array(entries)) => entries,
+            _ => anyhow::bail!("History file {:?} has no \"messages\" array", path),
+        },
+        _ => anyhow::bail!("History file {:?} must hold an array of messages", path),
+    };
+    
+    entries
+        .into_iter()
+        .enumerate()
+        .map(|(index, entry)| {
+            serde_json::from_value(entry)
+                .with_context(|| format!("Invalid message at index {} in history file {:?}", index, path))
+        })
+        .collect()
+}
+
+/// Export the conversation to a file, choosing the format from the extension
+/// (`.md`, `.json` or `.txt`)
+///
+/// The file is written to a temporary file next to the target and renamed into
+/// place, so an interrupted export never leaves a half-written file behind.
+pub fn export_conversation(context: &ChatContext, path: &Path) -> Result<()> {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_lowercase());
+    
+    let contents = match extension.as_deref() {
+        Some("md") | Some("markdown") => format_chat_history_markdown(context),
+        Some("txt") => format_chat_history(context, false),
+        Some("json") => serde_json::to_string_pretty(&serde_json::json!({
+            "system_prompt": context.system_prompt,
+            "messages": context.messages,
+        }))?,
+        _ => anyhow::bail!("Unsupported export format for {:?}, use .md, .json or .txt", path),
+    };
+    
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let mut temp_file = tempfile::NamedTempFile::new_in(dir)
+        .with_context(|| format!("Failed to create temporary file in {:?}", dir))?;
+    temp_file.write_all(contents.as_bytes())?;
+    temp_file.persist(path)
+        .with_context(|| format!("Failed to write {:?}", path))?;
+    
+    Ok(())
+}
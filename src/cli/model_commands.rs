@@ -1,25 +1,46 @@
 //! Implementation of model management CLI commands (download, list, delete)
+//! and the interactive model picker
 
 use anyhow::{Context as AnyhowContext, Result};
 use colored::Colorize;
 use indicatif::{ProgressBar, ProgressStyle};
-use std::path::{Path, PathBuf};
+use std::io::{IsTerminal, Write};
+use std::path::Path;
 use std::time::Instant;
-use tracing::{error, info};
 
-use crate::model;
-use crate::utils::{format_duration, format_file_size};
+use super::Verbosity;
+use crate::model::{self, download::DownloadConfig, ModelSort, ModelStore, SortOrder};
+use crate::model::metadata::{GgufMetadata, MetadataCache};
+use crate::utils::{format_duration, format_file_size, format_parameter_count};
 
 /// Download a model using the CLI interface
-pub async fn download_model_command(model_name: &str, models_dir: &Path) -> Result<()> {
-    println!("{} {}", "Downloading model:".bold(), model_name.bold().green());
+///
+/// With `skip_hash` set, neither an existing file nor the fresh download is
+/// checked against the registry hash. With `force` set, an existing file is
+/// replaced without checking it. Companion files the registry lists, such as
+/// a vision model's projector, are downloaded next to the model if missing.
+/// With `output` set, the model is saved under that file name instead of the
+/// registry's.
+pub async fn download_model_command(
+    model_name: &str,
+    models_dir: &Path,
+    output: Option<&str>,
+    skip_hash: bool,
+    force: bool,
+    config: &DownloadConfig,
+    verbosity: Verbosity,
+) -> Result<()> {
+    verbosity.info(format_args!("{} {}", "Downloading model:".bold(), model_name.bold().green()));
     
     // Check if model already exists
-    let model_info = match model::download::get_model_info(model_name).await {
+    let mut model_info = match model::download::get_model_info(model_name, config).await {
         Ok(info) => {
-            println!("Found model: {} ({})", info.name.bold(), format_file_size(info.size_bytes));
+            verbosity.info(format_args!("Found model: {} ({})", info.name.bold(), format_file_size(info.size_bytes)));
             if let Some(desc) = &info.description {
-                println!("Description: {}", desc);
+                verbosity.info(format_args!("Description: {}", desc));
+            }
+            if let Some(revision) = &info.revision {
+                verbosity.info(format_args!("Revision: {}", revision.bold()));
             }
             info
         },
@@ -27,46 +48,82 @@ pub async fn download_model_command(model_name: &str, models_dir: &Path) -> Resu
             return Err(anyhow::anyhow!("Failed to get model information: {}", e));
         }
     };
+    if let Some(output) = output {
+        model_info.set_filename(output)?;
+    }
     
-    let model_path = models_dir.join(&model_info.filename);
+    let model_path = models_dir.join(model_info.relative_path()?);
     
     // Check if model already exists
-    if model_path.exists() {
-        println!("Model {} already exists at {:?}", model_name.bold(), model_path);
+    let stored_revision = model::download::stored_revision(&model_path);
+    if model_path.exists() && force {
+        verbosity.info(format_args!("Replacing existing model at {:?}", model_path));
+        std::fs::remove_file(&model_path)?;
+    } else if model_path.exists() && stored_revision != model_info.revision {
+        verbosity.info(format_args!(
+            "Model {} at {:?} is from revision {}, redownloading...",
+            model_name.bold(),
+            model_path,
+            stored_revision.as_deref().unwrap_or("main")
+        ));
+        std::fs::remove_file(&model_path)?;
+    } else if model_path.exists() {
+        verbosity.info(format_args!("Model {} already exists at {:?}", model_name.bold(), model_path));
+        if let Some(revision) = &stored_revision {
+            verbosity.info(format_args!("Revision: {}", revision.bold()));
+        }
         
         // Verify hash if available
-        if !model_info.sha256.is_empty() {
-            println!("Verifying model hash...");
+        if !skip_hash && !model_info.sha256.is_empty() {
+            verbosity.info("Verifying model hash...");
+            
+            let file_size = std::fs::metadata(&model_path)?.len();
+            let progress_bar = if verbosity.is_quiet() {
+                ProgressBar::hidden()
+            } else {
+                ProgressBar::new(file_size)
+            };
+            progress_bar.set_style(
+                ProgressStyle::default_bar()
+                    .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})")?
+                    .progress_chars("#>-"),
+            );
             
-            let file_hash = model::calculate_file_hash(&model_path)?;
+            let file_hash = model::calculate_file_hash_with_progress(&model_path, |hashed| {
+                progress_bar.set_position(hashed);
+            })?;
+            progress_bar.finish_and_clear();
             if file_hash == model_info.sha256 {
-                println!("{}", "Model hash verified successfully ✓".bold().green());
+                verbosity.info("Model hash verified successfully ✓".bold().green());
+                model::download::download_extra_files(&model_info, &model_path, !skip_hash, config).await?;
                 return Ok(());
             } else {
-                println!("{}", "Model hash verification failed, redownloading...".bold().yellow());
+                verbosity.info("Model hash verification failed, redownloading...".bold().yellow());
                 std::fs::remove_file(&model_path)?;
             }
         } else {
+            verbosity.info("Skipping hash verification for existing model");
+            model::download::download_extra_files(&model_info, &model_path, !skip_hash, config).await?;
             return Ok(());
         }
     }
     
+    if !model_info.extra_files.is_empty() {
+        verbosity.info(format_args!("Companion files: {}", model_info.extra_files.len()));
+    }
+    
     // Start timer for download
     let start_time = Instant::now();
     
-    // Download the model
-    match model::download::download_model_file(
-        &model_info.download_url, 
-        &model_path, 
-        &model_info.sha256
-    ).await {
+    // Download the model and its companion files
+    match model::download::download_model_with_options(&model_info, &model_path, !skip_hash, config).await {
         Ok(()) => {
             let duration = start_time.elapsed();
-            println!(
+            verbosity.info(format_args!(
                 "{} in {}",
                 "Download completed successfully ✓".bold().green(),
                 format_duration(duration.as_secs()).bold()
-            );
+            ));
             Ok(())
         },
         Err(e) => {
@@ -80,100 +137,308 @@ pub async fn download_model_command(model_name: &str, models_dir: &Path) -> Resu
 }
 
 /// List available models using the CLI interface
-pub async fn list_models_command(models_dir: &Path) -> Result<()> {
-    println!("{}", "Available Models".bold().green());
-    println!("Models directory: {:?}", models_dir);
-    println!();
-    
-    // Ensure the directory exists
-    if !models_dir.exists() {
-        println!("Models directory does not exist. No models available.");
-        return Ok(());
+///
+/// Models in subdirectories are listed by their path relative to the models
+/// directory containing them. With `details` set,
+/// each model's architecture, parameter count, quantization and context length
+/// are read from its GGUF header, through the metadata cache; a file whose
+/// header can't be read shows them as unknown.
+pub async fn list_models_command(
+    store: &dyn ModelStore,
+    sort: ModelSort,
+    order: SortOrder,
+    details: bool,
+    verbosity: Verbosity,
+) -> Result<()> {
+    verbosity.info("Available Models".bold().green());
+    for models_dir in store.dirs() {
+        verbosity.info(format_args!("Models directory: {:?}", models_dir));
     }
+    verbosity.info("");
     
     // Count and collect models
-    let mut models_found = false;
     let mut models_info = Vec::new();
     
-    for entry in std::fs::read_dir(models_dir)? {
-        let entry = entry?;
-        let path = entry.path();
+    for path in store.list()? {
+        let model_name = model::model_display_name(&path, store.dirs());
+        let metadata = std::fs::metadata(&path)?;
+        let size_bytes = metadata.len();
+        let modified = metadata.modified().ok();
         
-        if path.is_file() && path.extension().and_then(|ext| ext.to_str()) == Some("gguf") {
-            if let Some(model_name) = path.file_name().and_then(|n| n.to_str()) {
-                let metadata = entry.metadata()?;
-                let size_bytes = metadata.len();
-                let modified = metadata.modified()
-                    .unwrap_or_else(|_| std::time::SystemTime::UNIX_EPOCH);
-                
-                models_info.push((model_name.to_string(), size_bytes, modified));
-                models_found = true;
-            }
-        }
+        models_info.push((model_name, size_bytes, modified, path));
     }
+    let models_found = !models_info.is_empty();
+    // Only worth a column when models can live in more than one place
+    let show_directory = store.dirs().len() > 1;
     
-    // Sort models by name
-    models_info.sort_by(|a, b| a.0.cmp(&b.0));
+    model::sort_models(&mut models_info, sort, order, |(name, size, modified, _)| (name, *size, *modified));
     
     // Display models table
     if models_found {
-        println!("{:<40} {:<15} {}", "Model Name".bold(), "Size".bold(), "Last Modified".bold());
-        println!("{}", "-".repeat(70));
+        // Header metadata is cached so repeated listings don't re-read every file
+        let mut metadata_cache = details.then(MetadataCache::load);
+        
+        let mut header = format!("{:<40} {:<15} {:<20}", "Model Name".bold(), "Size".bold(), "Last Modified".bold());
+        let mut width = 77;
+        if details {
+            header.push_str(&format!(
+                " {:<14} {:<10} {:<10} {:<10}",
+                "Architecture".bold(), "Params".bold(), "Quant".bold(), "Context".bold()
+            ));
+            width += 48;
+        }
+        if show_directory {
+            header.push_str(&format!(" {}", "Directory".bold()));
+            width += 30;
+        }
+        println!("{}", header.trim_end());
+        println!("{}", "-".repeat(width));
         
-        for (name, size, modified) in models_info {
+        for (name, size, modified, path) in models_info {
             let size_str = format_file_size(size);
             
             // Format the modified time
-            let modified_str = match modified.duration_since(std::time::SystemTime::UNIX_EPOCH) {
-                Ok(duration) => {
+            let modified_str = match modified.map(|time| time.duration_since(std::time::SystemTime::UNIX_EPOCH)) {
+                Some(Ok(duration)) => {
                     use chrono::prelude::*;
                     let datetime = DateTime::<Utc>::from_timestamp(duration.as_secs() as i64, 0)
                         .unwrap_or_else(|| DateTime::<Utc>::from_timestamp(0, 0).unwrap());
                     datetime.format("%Y-%m-%d %H:%M:%S").to_string()
                 },
-                Err(_) => "Unknown".to_string(),
+                _ => "Unknown".to_string(),
             };
             
-            println!("{:<40} {:<15} {}", name, size_str, modified_str);
+            let mut line = format!("{:<40} {:<15} {:<20}", name, size_str, modified_str);
+            if let Some(cache) = metadata_cache.as_mut() {
+                let metadata = cache.get(&path).unwrap_or_else(|e| {
+                    eprintln!("{} {}", "Warning:".yellow().bold(), e);
+                    GgufMetadata::default()
+                });
+                let unknown = || "unknown".to_string();
+                line.push_str(&format!(
+                    " {:<14} {:<10} {:<10} {:<10}",
+                    metadata.architecture.clone().unwrap_or_else(unknown),
+                    metadata.parameter_count.map(format_parameter_count).unwrap_or_else(unknown),
+                    metadata.quantization().unwrap_or_else(unknown),
+                    metadata.context_length.map(|length| length.to_string()).unwrap_or_else(unknown),
+                ));
+            }
+            if show_directory {
+                line.push_str(&format!(" {}", path.parent().unwrap_or(&path).display()));
+            }
+            println!("{}", line.trim_end());
+        }
+        
+        if let Some(cache) = metadata_cache.as_mut() {
+            if let Err(e) = cache.save() {
+                eprintln!("{} failed to update the metadata cache: {:#}", "Warning:".yellow().bold(), e);
+            }
         }
     } else {
         println!("No models found. Use 'rustllm model pull <model>' to download a model.");
     }
     
     // List available models for download
-    println!("\n{}", "Models available for download:".bold().green());
-    println!("- llama2-7b       (Llama 2 7B quantized to 4-bit)");
-    println!("- mistral-7b      (Mistral 7B quantized to 4-bit)");
-    println!("- phi-2           (Phi-2 quantized to 4-bit)");
-    println!("- neural-chat-7b  (Neural Chat 7B v3.1 quantized to 4-bit)");
+    if !verbosity.is_quiet() {
+        println!("\n{}", "Models available for download:".bold().green());
+        for info in model::download::builtin_models() {
+            match &info.description {
+                Some(description) => println!("- {:<15} ({})", info.name, description),
+                None => println!("- {}", info.name),
+            }
+        }
+    }
     
     Ok(())
 }
 
-/// Delete a model using the CLI interface
-pub async fn delete_model_command(model_name: &str, models_dir: &Path) -> Result<()> {
-    println!("{} {}", "Deleting model:".bold(), model_name.bold().red());
+/// Print the absolute path `model_name` resolves to, the file any command
+/// given `--model model_name` would load
+///
+/// A name matching several models fails with the list of candidates.
+pub fn which_model_command(model_name: &str, store: &dyn ModelStore) -> Result<()> {
+    let model_path = store.resolve(model_name)?;
+    let model_path = std::fs::canonicalize(&model_path).unwrap_or(model_path);
+    println!("{}", model_path.display());
+    Ok(())
+}
+
+/// How `model add` puts the file into the models directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddMethod {
+    /// Copy the file with a progress bar
+    Copy,
+    /// Hardlink the file (same filesystem only)
+    Link,
+    /// Rename the file, or copy and delete it across filesystems. Asks for
+    /// confirmation first unless `skip_confirm` is set, since the original is removed.
+    Move { skip_confirm: bool },
+}
+
+/// Bring a GGUF file from elsewhere into the models directory, as `name` if
+/// given, with `.gguf` added when missing
+///
+/// An existing model is only replaced with `force`.
+pub fn add_model_command(
+    source: &Path,
+    models_dir: &Path,
+    name: Option<&str>,
+    force: bool,
+    method: AddMethod,
+    verbosity: Verbosity,
+) -> Result<()> {
+    let metadata = std::fs::metadata(source)
+        .with_context(|| format!("Failed to read {:?}", source))?;
+    if !metadata.is_file() {
+        anyhow::bail!("{:?} is not a file", source);
+    }
+    model::inference::check_gguf_file(source, metadata.len())?;
     
-    // Find the model path
-    let model_path = match find_model_path(model_name, models_dir) {
-        Ok(path) => path,
-        Err(_) => {
-            return Err(anyhow::anyhow!("Model {} not found in {:?}", model_name, models_dir));
+    let file_name = added_file_name(source, name)?;
+    let target = models_dir.join(&file_name);
+    if target.exists() {
+        if !force {
+            anyhow::bail!("Model {} already exists at {:?}, pass --force to replace it", file_name, target);
+        }
+        if std::fs::canonicalize(&target)? == std::fs::canonicalize(source)? {
+            anyhow::bail!("{:?} is already in the models directory", source);
+        }
+    }
+    
+    if let AddMethod::Move { skip_confirm: false } = method {
+        if !std::io::stdin().is_terminal() {
+            anyhow::bail!(
+                "Refusing to move {:?} without confirmation: stdin is not a terminal. Pass --yes to skip the prompt.",
+                source
+            );
         }
+        println!(
+            "{} {} will be removed from its current location. Continue? (y/N)",
+            "Warning:".yellow().bold(),
+            source.display().to_string().bold()
+        );
+        let mut input = String::new();
+        std::io::stdin().read_line(&mut input)?;
+        if input.trim().to_lowercase() != "y" {
+            println!("Move cancelled.");
+            return Ok(());
+        }
+    }
+    
+    std::fs::create_dir_all(models_dir)
+        .with_context(|| format!("Failed to create models directory {:?}", models_dir))?;
+    
+    verbosity.info(format_args!(
+        "{} {} ({})",
+        "Adding model:".bold(),
+        file_name.bold().green(),
+        format_file_size(metadata.len())
+    ));
+    match method {
+        AddMethod::Copy => copy_with_progress(source, &target, metadata.len(), verbosity)?,
+        AddMethod::Link => {
+            if target.exists() {
+                std::fs::remove_file(&target)?;
+            }
+            std::fs::hard_link(source, &target)
+                .with_context(|| format!("Failed to link {:?} to {:?}", source, target))?;
+        },
+        AddMethod::Move { .. } => {
+            std::fs::rename(source, &target)
+                .or_else(|_| -> Result<()> {
+                    // rename can't cross filesystems, so copy and remove the original instead
+                    copy_with_progress(source, &target, metadata.len(), verbosity)?;
+                    std::fs::remove_file(source)
+                        .with_context(|| format!("Copied to {:?} but failed to remove {:?}", target, source))?;
+                    Ok(())
+                })?;
+        },
+    }
+    
+    verbosity.info(format_args!("{} {:?}", "Model added ✓".bold().green(), target));
+    Ok(())
+}
+
+/// The file name `model add` stores `source` under: `name` if given, else the
+/// source's own name, with `.gguf` added when missing
+fn added_file_name(source: &Path, name: Option<&str>) -> Result<String> {
+    let file_name = match name {
+        Some(name) => crate::utils::sanitize_filename(name),
+        None => source
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .context("Source path has no file name")?,
     };
+    if file_name.ends_with(".gguf") {
+        Ok(file_name)
+    } else {
+        Ok(format!("{}.gguf", file_name))
+    }
+}
+
+/// Copy `source` to `target` through a temporary file next to it, so an
+/// interrupted copy never leaves a partial model behind
+fn copy_with_progress(source: &Path, target: &Path, size: u64, verbosity: Verbosity) -> Result<()> {
+    let progress_bar = if verbosity.is_quiet() {
+        ProgressBar::hidden()
+    } else {
+        ProgressBar::new(size)
+    };
+    progress_bar.set_style(
+        ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})")?
+            .progress_chars("#>-"),
+    );
+    
+    let dir = target.parent().unwrap_or(Path::new("."));
+    let mut temp_file = tempfile::NamedTempFile::new_in(dir)
+        .with_context(|| format!("Failed to create temporary file in {:?}", dir))?;
+    let mut reader = std::fs::File::open(source)?;
+    std::io::copy(&mut reader, &mut progress_bar.wrap_write(temp_file.as_file_mut()))
+        .with_context(|| format!("Failed to copy {:?}", source))?;
+    temp_file.persist(target)
+        .with_context(|| format!("Failed to write {:?}", target))?;
+    progress_bar.finish_and_clear();
+    Ok(())
+}
+
+/// Delete a model using the CLI interface
+///
+/// Prompts for confirmation unless `skip_confirm` is set. When stdin is not a
+/// terminal the prompt can't be answered, so deletion is refused instead.
+pub async fn delete_model_command(
+    model_name: &str,
+    store: &dyn ModelStore,
+    skip_confirm: bool,
+    verbosity: Verbosity,
+) -> Result<()> {
+    verbosity.info(format_args!("{} {}", "Deleting model:".bold(), model_name.bold().red()));
+    
+    // Find the model path
+    let model_path = store.resolve(model_name)?;
     
     // Confirm deletion
-    println!("Are you sure you want to delete {}? (y/N)", model_path.display().to_string().bold());
-    let mut input = String::new();
-    std::io::stdin().read_line(&mut input)?;
+    let confirmed = if skip_confirm {
+        true
+    } else if !std::io::stdin().is_terminal() {
+        anyhow::bail!(
+            "Refusing to delete {:?} without confirmation: stdin is not a terminal. Pass --yes to skip the prompt.",
+            model_path
+        );
+    } else {
+        println!("Are you sure you want to delete {}? (y/N)", model_path.display().to_string().bold());
+        let mut input = String::new();
+        std::io::stdin().read_line(&mut input)?;
+        input.trim().to_lowercase() == "y"
+    };
     
-    if input.trim().to_lowercase() == "y" {
+    if confirmed {
         // Delete the file
-        std::fs::remove_file(&model_path)
+        store.delete(&model_path)
             .with_context(|| format!("Failed to delete model file at {:?}", model_path))?;
         
-        println!("{} {}", "Model".bold(), model_name.bold().red());
-        println!("{}", "deleted successfully ✓".bold().green());
+        verbosity.info(format_args!("{} {}", "Model".bold(), model_name.bold().red()));
+        verbosity.info("deleted successfully ✓".bold().green());
         Ok(())
     } else {
         println!("Deletion cancelled.");
@@ -181,38 +446,52 @@ pub async fn delete_model_command(model_name: &str, models_dir: &Path) -> Result
     }
 }
 
-/// Helper function to find a model path from a model name
-fn find_model_path(model_name: &str, models_dir: &Path) -> Result<PathBuf> {
-    // Check if the exact filename exists
-    let exact_path = models_dir.join(model_name);
-    if exact_path.exists() {
-        return Ok(exact_path);
-    }
+/// Ask the user to pick one of the downloaded models from a numbered list
+///
+/// Returns None, after printing how to download one, if there are no models.
+pub fn pick_model(store: &dyn ModelStore) -> Result<Option<String>> {
+    let mut names: Vec<String> = store.list()?
+        .iter()
+        .map(|path| model::model_display_name(path, store.dirs()))
+        .collect();
+    names.sort();
     
-    // Check if model_name with .gguf extension exists
-    let with_extension = if model_name.ends_with(".gguf") {
-        models_dir.join(model_name)
-    } else {
-        models_dir.join(format!("{}.gguf", model_name))
-    };
+    if names.is_empty() {
+        println!("No models found. Download one first, for example:");
+        println!("  rustllm model pull mistral-7b");
+        return Ok(None);
+    }
     
-    if with_extension.exists() {
-        return Ok(with_extension);
+    println!("{}", "Pick a model to chat with:".bold().green());
+    for (i, name) in names.iter().enumerate() {
+        println!("  {:>2}) {}", i + 1, name);
     }
     
-    // Try to find a partial match
-    for entry in std::fs::read_dir(models_dir)? {
-        let entry = entry?;
-        let path = entry.path();
-        
-        if path.is_file() {
-            if let Some(file_name) = path.file_name().and_then(|n| n.to_str()) {
-                if file_name.contains(model_name) {
-                    return Ok(path);
-                }
-            }
+    loop {
+        print!("Model number (1-{}): ", names.len());
+        std::io::stdout().flush()?;
+        let mut input = String::new();
+        if std::io::stdin().read_line(&mut input)? == 0 {
+            anyhow::bail!("No model picked");
+        }
+        match input.trim().parse::<usize>() {
+            Ok(n) if (1..=names.len()).contains(&n) => return Ok(Some(names.swap_remove(n - 1))),
+            _ => println!("{}", format!("Enter a number between 1 and {}", names.len()).yellow()),
         }
     }
-    
-    anyhow::bail!("Model {} not found in {:?}", model_name, models_dir)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn added_files_get_the_gguf_extension() {
+        let source = Path::new("/downloads/llama-3.2-1b.Q4_K_M");
+
+        assert_eq!(added_file_name(source, None).unwrap(), "llama-3.2-1b.Q4_K_M.gguf");
+        assert_eq!(added_file_name(Path::new("/downloads/phi.gguf"), None).unwrap(), "phi.gguf");
+        assert_eq!(added_file_name(source, Some("llama")).unwrap(), "llama.gguf");
+        assert_eq!(added_file_name(source, Some("llama.gguf")).unwrap(), "llama.gguf");
+    }
+}
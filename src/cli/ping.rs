@@ -0,0 +1,69 @@
+//! Implementation of the ping CLI command
+
+use anyhow::{Context, Result};
+use colored::Colorize;
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+
+/// The `data` of a `GET /api/health` response
+#[derive(Deserialize)]
+struct Health {
+    status: String,
+    generations_in_flight: Option<usize>,
+    max_concurrent_generations: Option<usize>,
+}
+
+/// Envelope of server responses
+#[derive(Deserialize)]
+struct HealthResponse {
+    success: bool,
+    data: Option<Health>,
+    error: Option<String>,
+}
+
+/// URL of an endpoint on a server listening on `host`
+pub fn server_url(host: &str, port: u16, path: &str) -> String {
+    // A server bound to every interface is reachable on loopback
+    let host = match host {
+        "0.0.0.0" => "127.0.0.1",
+        "::" | "[::]" => "[::1]",
+        host => host,
+    };
+    format!("http://{}:{}{}", host, port, path)
+}
+
+/// Check that a server is up and healthy, failing if it can't be reached
+/// within `timeout` or reports a problem
+pub async fn ping_command(host: &str, port: u16, timeout: Duration) -> Result<()> {
+    let url = server_url(host, port, "/api/health");
+
+    let client = reqwest::Client::builder().timeout(timeout).build()?;
+    let start = Instant::now();
+    let response = client
+        .get(&url)
+        .send()
+        .await
+        .with_context(|| format!("Server at {} is unreachable", url))?;
+    let elapsed = start.elapsed();
+
+    let status = response.status();
+    let health: HealthResponse = response
+        .json()
+        .await
+        .with_context(|| format!("Invalid health response from {} (status {})", url, status))?;
+    let health = match health {
+        HealthResponse { success: true, data: Some(health), .. } if status.is_success() && health.status == "OK" => health,
+        HealthResponse { error, .. } => anyhow::bail!(
+            "Server at {} is unhealthy (status {}): {}",
+            url,
+            status,
+            error.unwrap_or_else(|| "no details".to_string())
+        ),
+    };
+
+    println!("{} {} responded in {}ms", "✓".bold().green(), url, elapsed.as_millis());
+    if let (Some(running), Some(limit)) = (health.generations_in_flight, health.max_concurrent_generations) {
+        println!("Generations running: {}/{}", running, limit);
+    }
+    Ok(())
+}
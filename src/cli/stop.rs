@@ -0,0 +1,60 @@
+//! Implementation of the stop CLI command
+
+use super::ping::server_url;
+use anyhow::{Context, Result};
+use colored::Colorize;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+
+/// Envelope of server responses
+#[derive(Deserialize)]
+struct ShutdownResponse {
+    success: bool,
+    error: Option<String>,
+}
+
+/// Ask a server started with `--allow-remote-shutdown` to stop, then wait up
+/// to `timeout` for it to stop answering
+pub async fn stop_command(host: &str, port: u16, timeout: Duration) -> Result<()> {
+    let url = server_url(host, port, "/api/shutdown");
+    let client = reqwest::Client::builder().timeout(Duration::from_secs(5)).build()?;
+    let response = client
+        .post(&url)
+        .send()
+        .await
+        .with_context(|| format!("Server at {} is unreachable", url))?;
+
+    let status = response.status();
+    if status == StatusCode::NOT_FOUND {
+        anyhow::bail!(
+            "Server at {} doesn't accept shutdown requests, start it with --allow-remote-shutdown",
+            url
+        );
+    }
+    let body: ShutdownResponse = response
+        .json()
+        .await
+        .with_context(|| format!("Invalid shutdown response from {} (status {})", url, status))?;
+    if !status.is_success() || !body.success {
+        anyhow::bail!(
+            "Server at {} refused to shut down (status {}): {}",
+            url,
+            status,
+            body.error.unwrap_or_else(|| "no details".to_string())
+        );
+    }
+
+    // The server finishes in-flight requests first, so wait until it's gone
+    let health_url = server_url(host, port, "/api/health");
+    let start = Instant::now();
+    while client.get(&health_url).send().await.is_ok() {
+        if start.elapsed() >= timeout {
+            anyhow::bail!("Server at {} is still running after {} seconds", health_url, timeout.as_secs());
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    println!("{} Server on {}:{} stopped", "✓".bold().green(), host, port);
+    Ok(())
+}
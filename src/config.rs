@@ -0,0 +1,153 @@
+//! User configuration read from `~/.rustllm/config.toml`
+//!
+//! Values in the file are defaults: a command-line flag (or its environment
+//! variable) always wins, and built-in defaults apply to keys left out.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::model::inference::ModelConfig;
+
+/// Address the server binds when neither a flag nor the config sets one
+pub const DEFAULT_HOST: &str = "127.0.0.1";
+/// Port the server binds when neither a flag nor the config sets one
+pub const DEFAULT_PORT: u16 = 8000;
+
+/// Environment variable that sets the default model
+pub const DEFAULT_MODEL_ENV: &str = "RUSTLLM_DEFAULT_MODEL";
+
+/// Settings from the config file; every key is optional
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Models directory, or several separated like `--models-path`
+    pub models_path: Option<String>,
+    /// Model used when a command or request doesn't name one
+    pub default_model: Option<String>,
+    /// Levels below each models directory searched for models
+    pub scan_depth: Option<usize>,
+    /// Address `serve` binds
+    pub host: Option<String>,
+    /// Port `serve` binds
+    pub port: Option<u16>,
+    /// Default sampling temperature
+    pub temperature: Option<f32>,
+    /// Context window size in tokens
+    pub context_size: Option<u32>,
+    /// Prompt tokens evaluated per decode call
+    pub batch_size: Option<u32>,
+    /// Layers to offload to the GPU
+    pub gpu_layers: Option<u32>,
+    /// Threads used for CPU inference
+    pub threads: Option<u32>,
+}
+
+/// Where the effective value of a setting came from
+#[derive(Debug, Clone, Copy)]
+pub enum Source {
+    CommandLine,
+    Env(&'static str),
+    ConfigFile,
+    Default,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::CommandLine => write!(f, "command line"),
+            Source::Env(name) => write!(f, "env {}", name),
+            Source::ConfigFile => write!(f, "config file"),
+            Source::Default => write!(f, "default"),
+        }
+    }
+}
+
+/// The effective value of one setting
+#[derive(Debug)]
+pub struct Setting {
+    pub key: &'static str,
+    pub value: String,
+    pub source: Source,
+}
+
+impl Config {
+    /// Location of the config file (`~/.rustllm/config.toml`)
+    pub fn path() -> Option<PathBuf> {
+        home::home_dir().map(|home| home.join(".rustllm").join("config.toml"))
+    }
+
+    /// Load the config file, or the defaults if there isn't one
+    pub fn load() -> Result<Self> {
+        let Some(path) = Self::path().filter(|path| path.exists()) else {
+            return Ok(Self::default());
+        };
+
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read config file {:?}", path))?;
+        toml::from_str(&contents).with_context(|| format!("Invalid config file {:?}", path))
+    }
+
+    /// Every setting with its effective value, without subcommand flags
+    ///
+    /// `models_path` and `scan_depth` are the global `--models-path` and
+    /// `--scan-depth` values and where clap got them.
+    pub fn effective_settings(
+        &self,
+        models_path: Option<(String, Source)>,
+        default_models_path: String,
+        scan_depth: Option<(String, Source)>,
+    ) -> Vec<Setting> {
+        let defaults = ModelConfig::default();
+        let threads_default = match defaults.n_threads {
+            Some(threads) => threads.to_string(),
+            None => "auto".to_string(),
+        };
+        let context_size_default = if defaults.auto_context_size {
+            "auto".to_string()
+        } else {
+            defaults.context_size.to_string()
+        };
+
+        let models_path = match models_path {
+            Some((value, source)) => Setting { key: "models_path", value, source },
+            None => setting("models_path", None, self.models_path.as_ref(), default_models_path),
+        };
+        let scan_depth = match scan_depth {
+            Some((value, source)) => Setting { key: "scan_depth", value, source },
+            None => setting("scan_depth", None, self.scan_depth.as_ref(), crate::model::DEFAULT_SCAN_DEPTH),
+        };
+
+        vec![
+            models_path,
+            scan_depth,
+            setting("default_model", Some(DEFAULT_MODEL_ENV), self.default_model.as_ref(), "(none)"),
+            setting("host", None, self.host.as_ref(), DEFAULT_HOST),
+            setting("port", None, self.port.as_ref(), DEFAULT_PORT),
+            setting("temperature", None, self.temperature.as_ref(), defaults.temperature),
+            setting("context_size", None, self.context_size.as_ref(), context_size_default),
+            setting("batch_size", None, self.batch_size.as_ref(), defaults.batch_size),
+            setting("gpu_layers", None, self.gpu_layers.as_ref(), defaults.n_gpu_layers),
+            setting("threads", None, self.threads.as_ref(), threads_default),
+        ]
+    }
+}
+
+/// Resolve one setting from its environment variable, the file and the default
+fn setting(
+    key: &'static str,
+    env: Option<&'static str>,
+    file_value: Option<&impl fmt::Display>,
+    default: impl fmt::Display,
+) -> Setting {
+    if let Some((name, value)) = env.and_then(|name| Some((name, std::env::var(name).ok()?))) {
+        if !value.is_empty() {
+            return Setting { key, value, source: Source::Env(name) };
+        }
+    }
+    match file_value {
+        Some(value) => Setting { key, value: value.to_string(), source: Source::ConfigFile },
+        None => Setting { key, value: default.to_string(), source: Source::Default },
+    }
+}
@@ -1,47 +1,320 @@
+mod config;
 mod model;
 mod server;
 mod cli;
 mod utils;
 
-use anyhow::Result;
-use clap::{Parser, Subcommand};
+use anyhow::{Context, Result};
+use clap::{parser::ValueSource, Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
 use tracing::{info, Level};
-use tracing_subscriber::FmtSubscriber;
+use tracing_subscriber::{filter::Targets, fmt::writer::BoxMakeWriter, layer::SubscriberExt, FmtSubscriber};
+use std::ffi::OsString;
+use std::io::IsTerminal;
 use std::path::PathBuf;
 
+use model::ModelStore;
+
 #[derive(Parser)]
 #[clap(author, version, about)]
 struct Cli {
     #[clap(subcommand)]
     command: Commands,
 
-    /// Path to models directory
+    /// Models directory, or several separated by ':' (';' on Windows); downloads go to the first writable one
+    /// [default: models_path in the config file, else ~/.rustllm/models]
     #[clap(long, env = "RUSTLLM_MODELS_PATH", global = true)]
-    models_path: Option<PathBuf>,
+    models_path: Option<OsString>,
+
+    /// Levels below each models directory searched for models; 2 also finds models one subdirectory down
+    /// [default: scan_depth in the config file, else 2]
+    #[clap(long, env = "RUSTLLM_SCAN_DEPTH", global = true, value_parser = clap::value_parser!(u32).range(1..))]
+    scan_depth: Option<u32>,
+
+    /// Mirror to download models from instead of huggingface.co
+    #[clap(long, env = "RUSTLLM_HF_ENDPOINT", global = true, value_parser = model::download::parse_http_url)]
+    endpoint: Option<reqwest::Url>,
+
+    /// Remote model registry queried for names that aren't built in
+    #[clap(long, env = "RUSTLLM_REGISTRY_URL", global = true, value_parser = model::download::parse_http_url)]
+    registry_url: Option<reqwest::Url>,
+
+    /// Give up on a download or registry request after this many seconds, including the transfer (0 = never)
+    #[clap(long, env = "RUSTLLM_DOWNLOAD_TIMEOUT_SECS", global = true, default_value = "0")]
+    download_timeout_secs: u64,
+
+    /// Abort a download when no data arrives for this many seconds
+    #[clap(long, env = "RUSTLLM_STALL_TIMEOUT_SECS", global = true, default_value = "60", value_parser = clap::value_parser!(u64).range(1..))]
+    stall_timeout_secs: u64,
+
+    /// Bearer token for the remote model registry
+    #[clap(long, env = "RUSTLLM_REGISTRY_TOKEN", global = true, hide_env_values = true)]
+    registry_token: Option<String>,
 
     /// Enable verbose logging
-    #[clap(short, long, global = true)]
+    #[clap(short, long, global = true, conflicts_with = "quiet")]
     verbose: bool,
+
+    /// Only print command output and errors (no banners, progress or logs)
+    #[clap(short, long, global = true)]
+    quiet: bool,
+
+    /// When to color output (auto disables it for pipes and when NO_COLOR is set)
+    #[clap(long, value_enum, default_value = "auto", global = true)]
+    color: ColorMode,
+
+    /// Log as plain text or as one JSON object per line
+    #[clap(long, value_enum, default_value = "text", global = true)]
+    log_format: LogFormat,
+}
+
+/// Values for `--color`
+#[derive(Clone, Copy, ValueEnum)]
+enum ColorMode {
+    Auto,
+    Always,
+    Never,
+}
+
+/// Values for `--log-format`
+#[derive(Clone, Copy, ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Model loading and generation options shared by the commands that run inference
+#[derive(Args)]
+struct ModelFlags {
+    /// Context window size in tokens, clamped to the model's trained maximum (default: chosen from the model size and free memory)
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    context_size: Option<u32>,
+
+    /// Prompt tokens evaluated per decode call; higher is faster but uses more memory
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    batch_size: Option<u32>,
+
+    /// Number of model layers to offload to the GPU (0 = CPU only)
+    #[clap(long)]
+    gpu_layers: Option<u32>,
+
+    /// Threads used for CPU inference (default: chosen from the model size)
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    threads: Option<u32>,
+
+    /// Stop generating when the last N tokens form a repeating cycle (off by default)
+    #[clap(long, value_parser = clap::value_parser!(u32).range(2..))]
+    anti_repeat_window: Option<u32>,
+
+    /// Log each step's top candidate tokens and probabilities and the token sampled (shown with --verbose)
+    #[clap(long)]
+    debug_sampling: bool,
+    
+    /// Also stop generating at this token id (repeatable), for finetunes with
+    /// their own end-of-turn token
+    #[clap(long = "stop-token", value_name = "ID")]
+    stop_tokens: Vec<i32>,
+    
+    /// Load the model even if it looks too big for the available memory
+    #[clap(long)]
+    force_load: bool,
+    
+    /// Let llama.cpp print its own diagnostics to stderr instead of logging them
+    #[clap(long)]
+    llama_verbose: bool,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     /// Start the local LLM server
     Serve {
-        /// Host address to bind the server
-        #[clap(long, default_value = "127.0.0.1")]
-        host: String,
+        /// Host address to bind the server [default: 127.0.0.1]
+        #[clap(long)]
+        host: Option<String>,
+
+        /// Port to bind the server [default: 8000]
+        #[clap(long)]
+        port: Option<u16>,
+
+        /// Listen on a Unix domain socket at this path instead of host and port
+        #[clap(long, conflicts_with_all = ["host", "port"])]
+        unix_socket: Option<PathBuf>,
+
+        #[clap(flatten)]
+        model_flags: ModelFlags,
+
+        /// Watch the models directory and evict cached models whose file is removed
+        #[clap(long)]
+        watch_models: bool,
+
+        /// Maximum number of completions a single chat request may ask for with `n`
+        #[clap(long, default_value = "4", value_parser = clap::value_parser!(u32).range(1..))]
+        max_completions: u32,
+
+        /// Unload cached models after this many seconds without use (0 = never)
+        #[clap(long, default_value = "0")]
+        model_idle_timeout_secs: u64,
+
+        /// Serve a browser chat UI at /
+        #[clap(long)]
+        ui: bool,
+
+        /// Seconds a scan of the models directories is reused by GET /api/models
+        #[clap(long, default_value = "5")]
+        model_list_ttl_secs: u64,
+
+        /// Model used by requests that don't name one (default: `default_model` in the config file)
+        #[clap(long, env = "RUSTLLM_DEFAULT_MODEL")]
+        default_model: Option<String>,
+
+        /// Generations allowed to run at once across all models; others queue [default: number of CPUs]
+        #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+        max_concurrent_gen: Option<u32>,
+
+        /// Replies each model generates side by side in one context, each with the full context size, at most 256 (a model's registry entry can set its own)
+        #[clap(long, value_name = "N", default_value = "1", value_parser = clap::value_parser!(u32).range(1..=model::inference::MAX_PARALLEL as i64))]
+        parallel: u32,
+
+        /// Seconds a queued generation waits for a free slot before failing with 503 (0 = forever)
+        #[clap(long, default_value = "0")]
+        queue_timeout_secs: u64,
+
+        /// Requests per minute allowed from each client IP; more get 429 (off by default)
+        #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+        rate_limit: Option<u32>,
 
-        /// Port to bind the server
-        #[clap(long, default_value = "8000")]
-        port: u16,
+        /// Trust X-Forwarded-For for the client IP (only behind a reverse proxy that sets it)
+        #[clap(long)]
+        trust_proxy: bool,
+
+        /// Run generation at this nice value (-20 to 19, higher yields more CPU to other programs)
+        #[clap(long, value_name = "PRIORITY", allow_negative_numbers = true, value_parser = clap::value_parser!(i32).range(-20..=19))]
+        nice: Option<i32>,
+
+        /// Accept POST /api/shutdown, so `rustllm stop` can stop the server
+        #[clap(long)]
+        allow_remote_shutdown: bool,
+
+        /// Fetch http(s) image URLs in chat requests (by default only data: URIs are accepted)
+        #[clap(long)]
+        allow_remote_images: bool,
     },
     
     /// Run the interactive chat CLI
     Chat {
-        /// Model to use for chat
+        /// Model to use for chat (default: `default_model` in the config file)
+        #[clap(long, env = "RUSTLLM_DEFAULT_MODEL")]
+        model: Option<String>,
+
+        /// System prompt to start the conversation with
+        #[clap(long, conflicts_with = "system_file")]
+        system: Option<String>,
+
+        /// Read the system prompt from a file
+        #[clap(long)]
+        system_file: Option<PathBuf>,
+
+        #[clap(flatten)]
+        model_flags: ModelFlags,
+
+        /// Maximum number of messages kept in the conversation history
+        #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+        max_messages: Option<u32>,
+
+        /// Continue the conversation in a JSON file of `{role, content}` messages
+        #[clap(long)]
+        history_file: Option<PathBuf>,
+        
+        /// Warn once the prompt fills this percentage of the context window (0 = off)
+        #[clap(long, default_value = "80", value_parser = clap::value_parser!(u32).range(0..=100))]
+        context_warning: u32,
+        
+        /// Go straight to the prompt without the welcome text and command hints
+        #[clap(long)]
+        no_banner: bool,
+
+        /// Show the prompt, completion and total token counts after each response
+        #[clap(long)]
+        stats: bool,
+
+        /// Maximum number of tokens in each response
+        #[clap(long)]
+        max_tokens: Option<usize>,
+
+        /// Sampling temperature (0.0-1.0, 0 for greedy decoding)
+        #[clap(long)]
+        temperature: Option<f32>,
+
+        /// Nucleus sampling threshold (above 0.0, up to 1.0)
+        #[clap(long)]
+        top_p: Option<f32>,
+
+        /// Sample from only the K most likely tokens (0 = off)
+        #[clap(long)]
+        top_k: Option<usize>,
+    },
+    
+    /// Continue a prompt without a chat template (for base and code models)
+    Complete {
+        /// Model to use for the completion
         #[clap(long)]
         model: String,
+
+        /// Prompt to continue (read from stdin if omitted)
+        #[clap(long)]
+        prompt: Option<String>,
+
+        /// Maximum number of tokens to generate
+        #[clap(long)]
+        max_tokens: Option<usize>,
+
+        /// Sampling temperature (0 for greedy decoding)
+        #[clap(long)]
+        temperature: Option<f32>,
+
+        /// Nucleus sampling threshold
+        #[clap(long)]
+        top_p: Option<f32>,
+
+        /// Sample from only the K most likely tokens (0 = off)
+        #[clap(long)]
+        top_k: Option<usize>,
+
+        #[clap(flatten)]
+        model_flags: ModelFlags,
+    },
+    
+    /// Measure prompt evaluation and generation speed
+    Bench {
+        /// Model to benchmark
+        #[clap(long, required_unless_present = "compare")]
+        model: Option<String>,
+
+        /// Benchmark these quantizations of --repo side by side, e.g. q4_k_m,q5_k_m,q8_0
+        #[clap(long, value_delimiter = ',', requires = "repo", conflicts_with = "model")]
+        compare: Vec<String>,
+
+        /// Hugging Face repository holding the quantizations, e.g. TheBloke/Mistral-7B-v0.1-GGUF
+        #[clap(long, requires = "compare")]
+        repo: Option<String>,
+
+        /// Delete the quantizations downloaded for --compare afterwards
+        #[clap(long, requires = "compare")]
+        cleanup: bool,
+
+        /// Tokens to generate in each run
+        #[clap(long, default_value = "256")]
+        tokens: usize,
+
+        /// Approximate prompt length in tokens
+        #[clap(long, default_value = "512")]
+        prompt_tokens: usize,
+
+        /// Number of runs to average over
+        #[clap(long, default_value = "3", value_parser = clap::value_parser!(u32).range(1..))]
+        runs: u32,
+
+        #[clap(flatten)]
+        model_flags: ModelFlags,
     },
     
     /// Manage models (download, list, delete)
@@ -49,6 +322,48 @@ enum Commands {
         #[clap(subcommand)]
         action: ModelAction,
     },
+    
+    /// Check that a running server is up and healthy
+    Ping {
+        /// Host the server listens on [default: host in the config file, else 127.0.0.1]
+        #[clap(long)]
+        host: Option<String>,
+
+        /// Port the server listens on [default: port in the config file, else 8000]
+        #[clap(long)]
+        port: Option<u16>,
+
+        /// Seconds to wait for the server to answer
+        #[clap(long, default_value = "5")]
+        timeout_secs: u64,
+    },
+    
+    /// Stop a server started with --allow-remote-shutdown
+    Stop {
+        /// Host the server listens on [default: host in the config file, else 127.0.0.1]
+        #[clap(long)]
+        host: Option<String>,
+
+        /// Port the server listens on [default: port in the config file, else 8000]
+        #[clap(long)]
+        port: Option<u16>,
+
+        /// Seconds to wait for the server to finish in-flight requests and exit
+        #[clap(long, default_value = "30")]
+        timeout_secs: u64,
+    },
+    
+    /// Inspect the configuration (~/.rustllm/config.toml)
+    Config {
+        #[clap(subcommand)]
+        action: ConfigAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigAction {
+    /// Show the effective configuration and where each value comes from
+    Show,
 }
 
 #[derive(Subcommand)]
@@ -61,77 +376,416 @@ enum ModelAction {
         /// Skip hash verification (use with caution)
         #[clap(long)]
         skip_hash: bool,
+        
+        /// Number of parallel connections to download with
+        #[clap(long, default_value = "1")]
+        connections: usize,
+
+        /// Download again even if the model already exists
+        #[clap(long)]
+        force: bool,
+
+        /// File name to save the model as, instead of the registry's
+        #[clap(long, value_name = "FILENAME")]
+        output: Option<String>,
     },
     
     /// List all available models
-    List,
+    ///
+    /// With --details, also shows each model's architecture, parameter count,
+    /// quantization and context length from its GGUF header.
+    List {
+        /// What to sort the models by
+        #[clap(long, value_enum, default_value_t = model::ModelSort::Name)]
+        sort: model::ModelSort,
+
+        /// Sort direction
+        #[clap(long, value_enum, default_value_t = model::SortOrder::Asc)]
+        order: model::SortOrder,
+
+        /// Also show each model's architecture, parameter count, quantization and context length
+        #[clap(long, short)]
+        details: bool,
+    },
+    
+    /// Print the file a model name resolves to
+    Which {
+        /// Model name, as passed to --model
+        model: String,
+    },
+    
+    /// Copy a GGUF file from elsewhere into the models directory
+    Add {
+        /// Path of the GGUF file
+        path: PathBuf,
+
+        /// Name to store the model under (default: the file's own name)
+        #[clap(long)]
+        name: Option<String>,
+
+        /// Replace an existing model with the same name
+        #[clap(long)]
+        force: bool,
+
+        /// Hardlink the file instead of copying it (same filesystem only)
+        #[clap(long)]
+        link: bool,
+        
+        /// Move the file instead of copying it; the original is removed
+        #[clap(long = "move", conflicts_with = "link")]
+        move_file: bool,
+        
+        /// Skip the confirmation prompt for --move
+        #[clap(short, long)]
+        yes: bool,
+    },
     
     /// Delete a model
     Delete {
         /// Model name to delete
         model: String,
+        
+        /// Skip the confirmation prompt
+        #[clap(short, long)]
+        yes: bool,
     },
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Parse command-line arguments
-    let cli = Cli::parse();
+    // Parse command-line arguments, keeping the matches to tell where values came from
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches)?;
+    
+    // Decide on colors before anything is printed
+    let use_color = match cli.color {
+        ColorMode::Always => true,
+        ColorMode::Never => false,
+        ColorMode::Auto => {
+            std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+                && std::io::stdout().is_terminal()
+        }
+    };
+    colored::control::set_override(use_color);
     
-    // Set up logging
-    let log_level = if cli.verbose { Level::DEBUG } else { Level::INFO };
+    // Set up logging; when quiet only errors are logged, and to stderr
+    let (log_level, log_writer) = if cli.quiet {
+        (Level::ERROR, BoxMakeWriter::new(std::io::stderr))
+    } else if cli.verbose {
+        (Level::DEBUG, BoxMakeWriter::new(std::io::stdout))
+    } else {
+        (Level::INFO, BoxMakeWriter::new(std::io::stdout))
+    };
     let subscriber = FmtSubscriber::builder()
         .with_max_level(log_level)
-        .finish();
-    tracing::subscriber::set_global_default(subscriber)
-        .expect("Failed to set tracing subscriber");
-    
-    // Get models path, default to ~/.rustllm/models if not specified
-    let models_path = match cli.models_path {
-        Some(path) => path,
-        None => {
-            let mut home_dir = home::home_dir().expect("Could not find home directory");
-            home_dir.push(".rustllm");
-            home_dir.push("models");
-            home_dir
-        }
+        .with_writer(log_writer);
+    // llama.cpp's load-time chatter is logged at INFO, so keep it for --verbose
+    let backend_level = if cli.verbose { Level::DEBUG } else { log_level.min(Level::WARN) };
+    let filter = Targets::new()
+        .with_default(log_level)
+        .with_target(model::inference::BACKEND_LOG_TARGET, backend_level);
+    let verbosity = if cli.quiet { cli::Verbosity::Quiet } else { cli::Verbosity::Normal };
+    match cli.log_format {
+        LogFormat::Text => tracing::subscriber::set_global_default(subscriber.with_ansi(use_color).finish().with(filter)),
+        LogFormat::Json => tracing::subscriber::set_global_default(subscriber.json().finish().with(filter)),
+    }
+    .expect("Failed to set tracing subscriber");
+    
+    let config = config::Config::load()?;
+    
+    // Where --models-path came from, for `config show`
+    let models_path_flag = cli.models_path.as_ref().map(|paths| {
+        let source = match matches.value_source("models_path") {
+            Some(ValueSource::EnvVariable) => config::Source::Env("RUSTLLM_MODELS_PATH"),
+            _ => config::Source::CommandLine,
+        };
+        (paths.to_string_lossy().into_owned(), source)
+    });
+    let scan_depth_flag = cli.scan_depth.map(|depth| {
+        let source = match matches.value_source("scan_depth") {
+            Some(ValueSource::EnvVariable) => config::Source::Env("RUSTLLM_SCAN_DEPTH"),
+            _ => config::Source::CommandLine,
+        };
+        (depth.to_string(), source)
+    });
+    let scan_depth = cli.scan_depth
+        .map(|depth| depth as usize)
+        .or(config.scan_depth)
+        .unwrap_or(model::DEFAULT_SCAN_DEPTH);
+    
+    // Get models paths from the flag, then the config file, default to ~/.rustllm/models
+    let default_models_path = {
+        let mut home_dir = home::home_dir().expect("Could not find home directory");
+        home_dir.push(".rustllm");
+        home_dir.push("models");
+        home_dir
+    };
+    let models_paths: Vec<PathBuf> = match cli.models_path.or_else(|| config.models_path.clone().map(OsString::from)) {
+        Some(paths) => std::env::split_paths(&paths)
+            .filter(|path| !path.as_os_str().is_empty())
+            .collect(),
+        None => vec![default_models_path.clone()],
     };
+    if models_paths.is_empty() {
+        anyhow::bail!("--models-path doesn't contain any directory");
+    }
     
-    // Create models directory if it doesn't exist
+    // Create the first models directory if it doesn't exist, the others are optional
+    let models_path = &models_paths[0];
     if !models_path.exists() {
-        std::fs::create_dir_all(&models_path)?;
+        std::fs::create_dir_all(models_path)?;
         info!("Created models directory at {:?}", models_path);
     }
+    for models_path in &models_paths[1..] {
+        if !models_path.is_dir() {
+            tracing::warn!("Models directory {:?} does not exist, skipping it", models_path);
+        }
+    }
+    
+    let store = model::FsModelStore::new(models_paths, scan_depth);
+    
+    let download_config = model::download::DownloadConfig {
+        endpoint: cli.endpoint,
+        registry_url: cli.registry_url,
+        registry_token: cli.registry_token,
+        quiet: cli.quiet,
+        client: model::download::http_client(
+            (cli.download_timeout_secs > 0).then(|| std::time::Duration::from_secs(cli.download_timeout_secs)),
+        )
+        .with_context(|| format!("Failed to create the HTTP client (is {} valid?)", model::download::USER_AGENT_ENV))?,
+        stall_timeout: std::time::Duration::from_secs(cli.stall_timeout_secs),
+        ..Default::default()
+    };
     
     // Process command
     match cli.command {
-        Commands::Serve { host, port } => {
-            info!("Starting server on {}:{}", host, port);
-            server::start_server(host, port, models_path).await?;
+        Commands::Serve {
+            host,
+            port,
+            unix_socket,
+            model_flags,
+            watch_models,
+            max_completions,
+            model_idle_timeout_secs,
+            ui,
+            model_list_ttl_secs,
+            default_model,
+            max_concurrent_gen,
+            parallel,
+            queue_timeout_secs,
+            rate_limit,
+            trust_proxy,
+            nice,
+            allow_remote_shutdown,
+            allow_remote_images,
+        } => {
+            let model_config = model::inference::ModelConfig {
+                parallel: parallel as usize,
+                ..model_config_from_flags(&model_flags, &config)
+            };
+            let host = host.or(config.host).unwrap_or_else(|| config::DEFAULT_HOST.to_string());
+            let port = port.or(config.port).unwrap_or(config::DEFAULT_PORT);
+            let options = server::ServerOptions {
+                watch_models,
+                max_completions: max_completions as usize,
+                model_idle_timeout: (model_idle_timeout_secs > 0)
+                    .then(|| std::time::Duration::from_secs(model_idle_timeout_secs)),
+                ui,
+                model_list_ttl: std::time::Duration::from_secs(model_list_ttl_secs),
+                reload_default_model: default_model.is_none(),
+                default_model: default_model.or(config.default_model),
+                max_concurrent_generations: max_concurrent_gen
+                    .map_or_else(num_cpus::get, |limit| limit as usize),
+                generation_queue_timeout: (queue_timeout_secs > 0)
+                    .then(|| std::time::Duration::from_secs(queue_timeout_secs)),
+                unix_socket,
+                rate_limit,
+                trust_proxy,
+                nice,
+                allow_remote_shutdown,
+                allow_remote_images,
+            };
+            match &options.unix_socket {
+                Some(path) => info!("Starting server on unix:{}", path.display()),
+                None => info!("Starting server on {}:{}", host, port),
+            }
+            server::start_server(host, port, std::sync::Arc::new(store), download_config, model_config, options).await?;
         },
         
-        Commands::Chat { model } => {
+        Commands::Chat { model, system, system_file, model_flags, max_messages, history_file, context_warning, no_banner, stats, max_tokens, temperature, top_p, top_k } => {
+            // Without a model or a default, let the user pick one if they can answer
+            let model = match model.or_else(|| config.default_model.clone()) {
+                Some(model) => model,
+                None if std::io::stdin().is_terminal() => {
+                    match cli::model_commands::pick_model(&store)? {
+                        Some(model) => model,
+                        None => return Ok(()),
+                    }
+                }
+                None => anyhow::bail!(
+                    "No model given: pass --model, set RUSTLLM_DEFAULT_MODEL, or set default_model in {}",
+                    config::Config::path().map_or("~/.rustllm/config.toml".into(), |path| path.display().to_string())
+                ),
+            };
+
+            // Resolve the system prompt from the flag or the file
+            let system_prompt = match system_file {
+                Some(path) => Some(
+                    std::fs::read_to_string(&path)
+                        .with_context(|| format!("Failed to read system prompt file {:?}", path))?
+                        .trim()
+                        .to_string(),
+                ),
+                None => system,
+            };
+            let history = match history_file {
+                Some(path) => cli::read_history_file(&path)?,
+                None => Vec::new(),
+            };
+            
             info!("Starting chat with model: {}", model);
-            cli::start_chat(&model, &models_path).await?;
+            let model_config = model_config_from_flags(&model_flags, &config);
+            let options = cli::ChatOptions {
+                system_prompt,
+                max_messages: max_messages.map(|n| n as usize),
+                history,
+                context_warning: context_warning as usize,
+                banner: !no_banner,
+                stats,
+                max_tokens,
+                temperature,
+                top_p,
+                top_k,
+                verbosity,
+            };
+            cli::start_chat(&model, &store, model_config, options).await?;
+        },
+        
+        Commands::Complete { model, prompt, max_tokens, temperature, top_p, top_k, model_flags } => {
+            let temperature = temperature.or(config.temperature);
+            let prompt = match prompt {
+                Some(prompt) => prompt,
+                None => std::io::read_to_string(std::io::stdin())
+                    .context("Failed to read the prompt from stdin")?,
+            };
+            
+            info!("Running completion with model: {}", model);
+            let model_config = model_config_from_flags(&model_flags, &config);
+            let options = cli::CompletionOptions { max_tokens, temperature, top_p, top_k };
+            cli::run_completion(&model, &store, model_config, &prompt, options).await?;
+        },
+        
+        Commands::Bench { model, compare, repo, cleanup, tokens, prompt_tokens, runs, model_flags } => {
+            let model_config = model_config_from_flags(&model_flags, &config);
+            let options = cli::bench::BenchOptions {
+                tokens,
+                prompt_tokens,
+                runs: runs as usize,
+                verbosity,
+            };
+            match (model, repo) {
+                (Some(model), _) => {
+                    info!("Benchmarking model: {}", model);
+                    cli::bench::bench_command(&model, &store, model_config, options).await?;
+                }
+                (None, Some(repo)) => {
+                    info!("Comparing quantizations {:?} of {}", compare, repo);
+                    let compare = cli::bench::CompareOptions { repo, quantizations: compare, cleanup };
+                    cli::bench::compare_command(&store, &download_config, model_config, compare, options).await?;
+                }
+                (None, None) => anyhow::bail!("Pass --model, or --compare with --repo"),
+            }
         },
         
         Commands::Model { action } => match action {
-            ModelAction::Pull { model, skip_hash } => {
+            ModelAction::Pull { model, skip_hash, connections, force, output } => {
                 info!("Downloading model: {}", model);
-                model::download_model_with_options(&model, &models_path, skip_hash).await?;
+                let download_config = model::download::DownloadConfig {
+                    connections: connections.max(1),
+                    ..download_config
+                };
+                cli::model_commands::download_model_command(&model, model::download_dir(store.dirs()), output.as_deref(), skip_hash, force, &download_config, verbosity).await?;
             },
             
-            ModelAction::List => {
+            ModelAction::List { sort, order, details } => {
                 info!("Listing available models");
-                model::list_models(&models_path).await?;
+                cli::model_commands::list_models_command(&store, sort, order, details, verbosity).await?;
+            },
+            
+            ModelAction::Which { model } => {
+                cli::model_commands::which_model_command(&model, &store)?;
+            },
+            
+            ModelAction::Add { path, name, force, link, move_file, yes } => {
+                info!("Adding model from {:?}", path);
+                let models_dir = model::download_dir(store.dirs());
+                let method = if link {
+                    cli::model_commands::AddMethod::Link
+                } else if move_file {
+                    cli::model_commands::AddMethod::Move { skip_confirm: yes }
+                } else {
+                    cli::model_commands::AddMethod::Copy
+                };
+                cli::model_commands::add_model_command(&path, models_dir, name.as_deref(), force, method, verbosity)?;
             },
             
-            ModelAction::Delete { model } => {
+            ModelAction::Delete { model, yes } => {
                 info!("Deleting model: {}", model);
-                model::delete_model(&model, &models_path).await?;
+                cli::model_commands::delete_model_command(&model, &store, yes, verbosity).await?;
+            },
+        },
+        
+        Commands::Ping { host, port, timeout_secs } => {
+            let host = host.or(config.host).unwrap_or_else(|| config::DEFAULT_HOST.to_string());
+            let port = port.or(config.port).unwrap_or(config::DEFAULT_PORT);
+            cli::ping::ping_command(&host, port, std::time::Duration::from_secs(timeout_secs)).await?;
+        },
+        
+        Commands::Stop { host, port, timeout_secs } => {
+            let host = host.or(config.host).unwrap_or_else(|| config::DEFAULT_HOST.to_string());
+            let port = port.or(config.port).unwrap_or(config::DEFAULT_PORT);
+            cli::stop::stop_command(&host, port, std::time::Duration::from_secs(timeout_secs)).await?;
+        },
+        
+        Commands::Config { action } => match action {
+            ConfigAction::Show => {
+                let settings = config.effective_settings(
+                    models_path_flag,
+                    default_models_path.display().to_string(),
+                    scan_depth_flag,
+                );
+                cli::config_commands::show_config_command(&settings)?;
             },
         },
     }
     
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Build the model configuration from command-line overrides, falling back to
+/// the config file
+fn model_config_from_flags(flags: &ModelFlags, file: &config::Config) -> model::inference::ModelConfig {
+    let mut config = model::inference::ModelConfig::default();
+    if let Some(context_size) = flags.context_size.or(file.context_size) {
+        config.context_size = context_size as usize;
+        config.auto_context_size = false;
+    }
+    if let Some(batch_size) = flags.batch_size.or(file.batch_size) {
+        config.batch_size = batch_size as usize;
+    }
+    if let Some(gpu_layers) = flags.gpu_layers.or(file.gpu_layers) {
+        config.n_gpu_layers = gpu_layers as i32;
+    }
+    if let Some(threads) = flags.threads.or(file.threads) {
+        config.n_threads = Some(threads as usize);
+    }
+    if let Some(temperature) = file.temperature {
+        config.temperature = temperature;
+    }
+    config.anti_repeat_window = flags.anti_repeat_window.map(|n| n as usize);
+    config.debug_sampling = flags.debug_sampling;
+    config.stop_tokens = flags.stop_tokens.clone();
+    config.force_load = flags.force_load;
+    config.llama_verbose = flags.llama_verbose;
+    config
+}
@@ -1,16 +1,157 @@
 use anyhow::{Context, Result};
 use futures::StreamExt;
 use indicatif::{ProgressBar, ProgressStyle};
-use reqwest::Client;
+use reqwest::{Client, Url};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::io::Write;
-use std::path::Path;
-use tokio::io::AsyncWriteExt;
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
 use tracing::{debug, info};
 
+use super::ModelError;
+
+/// Host that registry download URLs point at, rewritten by `DownloadConfig::endpoint`
+const HF_HOST: &str = "huggingface.co";
+
+/// How long to wait for a connection, so unreachable hosts fail fast
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Interval of TCP keepalive probes on idle pooled connections
+const TCP_KEEPALIVE: Duration = Duration::from_secs(60);
+
+/// Environment variable that replaces the `User-Agent` sent with requests
+pub const USER_AGENT_ENV: &str = "RUSTLLM_USER_AGENT";
+
+/// Options controlling how a model file is downloaded
+#[derive(Debug, Clone)]
+pub struct DownloadConfig {
+    /// Number of concurrent HTTP range requests (1 = single stream)
+    pub connections: usize,
+    /// Mirror to use in place of huggingface.co
+    pub endpoint: Option<Url>,
+    /// Remote registry queried for model names that aren't built in
+    pub registry_url: Option<Url>,
+    /// Bearer token sent to the remote registry
+    pub registry_token: Option<String>,
+    /// Hide progress bars and status messages
+    pub quiet: bool,
+    /// Updated with the byte counts as the download proceeds
+    pub progress: Option<DownloadProgress>,
+    /// HTTP client used for every request, so connections are pooled
+    pub client: Client,
+    /// Abort a download that receives no data for this long
+    pub stall_timeout: Duration,
+}
+
+impl Default for DownloadConfig {
+    fn default() -> Self {
+        Self {
+            connections: 1,
+            endpoint: None,
+            registry_url: None,
+            registry_token: None,
+            quiet: false,
+            progress: None,
+            // An invalid RUSTLLM_USER_AGENT is reported where the real client is built
+            client: http_client(None).unwrap_or_default(),
+            stall_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Build the HTTP client for downloads and registry lookups
+///
+/// `timeout` bounds each whole request, including transferring the body, so
+/// it must leave room for the largest model to download. Requests identify
+/// themselves as `rustllm/<version>` unless `RUSTLLM_USER_AGENT` is set, since
+/// Hugging Face and some CDNs reject requests without a user agent.
+pub fn http_client(timeout: Option<Duration>) -> reqwest::Result<Client> {
+    let user_agent = std::env::var(USER_AGENT_ENV)
+        .ok()
+        .filter(|agent| !agent.trim().is_empty())
+        .unwrap_or_else(|| concat!("rustllm/", env!("CARGO_PKG_VERSION")).to_string());
+    let mut headers = reqwest::header::HeaderMap::new();
+    headers.insert(reqwest::header::ACCEPT, reqwest::header::HeaderValue::from_static("*/*"));
+    
+    let mut builder = Client::builder()
+        .user_agent(user_agent)
+        .default_headers(headers)
+        .connect_timeout(CONNECT_TIMEOUT)
+        .tcp_keepalive(TCP_KEEPALIVE);
+    if let Some(timeout) = timeout {
+        builder = builder.timeout(timeout);
+    }
+    builder.build()
+}
+
+/// Byte counts of a download, shared with whoever is watching it
+#[derive(Debug, Clone, Default)]
+pub struct DownloadProgress {
+    downloaded: Arc<AtomicU64>,
+    total: Arc<AtomicU64>,
+}
+
+impl DownloadProgress {
+    /// Bytes received so far
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    /// Size of the file, if the server reported it
+    pub fn total(&self) -> Option<u64> {
+        Some(self.total.load(Ordering::Relaxed)).filter(|&total| total > 0)
+    }
+
+    /// Count another file's size in the total, once its download starts
+    fn add_total(&self, total: u64) {
+        self.total.fetch_add(total, Ordering::Relaxed);
+    }
+
+    fn add(&self, bytes: u64) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+/// Parse and validate an http(s) URL given on the command line, such as a
+/// mirror endpoint (`https://hf-mirror.com`) or registry URL
+pub fn parse_http_url(value: &str) -> Result<Url> {
+    let url = Url::parse(value).with_context(|| format!("Invalid URL: {}", value))?;
+    
+    if !matches!(url.scheme(), "http" | "https") {
+        anyhow::bail!("URL must use http or https: {}", value);
+    }
+    if url.host_str().is_none() {
+        anyhow::bail!("URL has no host: {}", value);
+    }
+    
+    Ok(url)
+}
+
+/// Rewrite a huggingface.co URL to point at the mirror endpoint
+///
+/// URLs on other hosts are returned unchanged.
+pub fn apply_endpoint(url: &str, endpoint: &Url) -> Result<String> {
+    let parsed = Url::parse(url).with_context(|| format!("Invalid download URL: {}", url))?;
+    if parsed.host_str() != Some(HF_HOST) {
+        return Ok(url.to_string());
+    }
+    
+    // Keep any path prefix the mirror is mounted under
+    let mut rewritten = endpoint.clone();
+    let path = format!("{}{}", endpoint.path().trim_end_matches('/'), parsed.path());
+    rewritten.set_path(&path);
+    rewritten.set_query(parsed.query());
+    
+    Ok(rewritten.to_string())
+}
+
 // Model information structure
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ModelInfo {
     pub name: String,
     pub filename: String,
@@ -18,90 +159,538 @@ pub struct ModelInfo {
     pub sha256: String,
     pub size_bytes: u64,
     pub description: Option<String>,
+    /// Directory under the models directory to download into, e.g. `llama`
+    /// (registry entries only; None downloads to the top level)
+    #[serde(default)]
+    pub subdir: Option<String>,
+    /// Hugging Face revision (branch, tag or commit) the download URL is
+    /// pinned to, from a `name@revision` identifier
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revision: Option<String>,
+    /// Files the model needs next to it, such as the `mmproj` projector of a
+    /// vision model, downloaded along with it
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_files: Vec<ExtraFile>,
+    /// Generations the server runs side by side for the model, overriding
+    /// `serve --parallel`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parallel: Option<usize>,
+}
+
+/// A companion file of a model, stored in the same directory as the model
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExtraFile {
+    pub url: String,
+    pub filename: String,
+    /// Expected SHA256; empty skips verification
+    #[serde(default)]
+    pub sha256: String,
+}
+
+impl ExtraFile {
+    /// Where the file goes, next to the model at `model_path`
+    ///
+    /// Fails if `filename` isn't a plain file name.
+    pub fn path_next_to(&self, model_path: &Path) -> Result<PathBuf> {
+        let filename = Path::new(&self.filename);
+        if !matches!(filename.components().collect::<Vec<_>>()[..], [Component::Normal(_)]) {
+            anyhow::bail!("Invalid companion file name {:?}", self.filename);
+        }
+        Ok(model_path.with_file_name(filename))
+    }
+}
+
+impl ModelInfo {
+    /// Where the model goes relative to the models directory
+    ///
+    /// Fails if `subdir` would escape the models directory or `filename`
+    /// isn't a plain file name.
+    pub fn relative_path(&self) -> Result<PathBuf> {
+        let mut path = PathBuf::new();
+        if let Some(subdir) = &self.subdir {
+            let subdir = Path::new(subdir);
+            if !subdir.components().all(|component| matches!(component, Component::Normal(_))) {
+                anyhow::bail!("Invalid subdirectory {:?} for model {}", subdir, self.name);
+            }
+            path.push(subdir);
+        }
+        let filename = Path::new(&self.filename);
+        if !matches!(filename.components().collect::<Vec<_>>()[..], [Component::Normal(_)]) {
+            anyhow::bail!("Invalid file name {:?} for model {}", self.filename, self.name);
+        }
+        path.push(filename);
+        Ok(path)
+    }
+
+    /// Save the model under `filename` instead of the registry's name
+    ///
+    /// The name is sanitized and given a `.gguf` extension if it lacks one,
+    /// so the model is still listed; the hash to verify stays the same.
+    pub fn set_filename(&mut self, filename: &str) -> Result<()> {
+        let mut sanitized = crate::utils::sanitize_filename(filename.trim());
+        if sanitized.is_empty() || sanitized == "." || sanitized == ".." {
+            anyhow::bail!("Invalid output file name {:?}", filename);
+        }
+        if !sanitized.ends_with(".gguf") {
+            sanitized.push_str(".gguf");
+        }
+        self.filename = sanitized;
+        Ok(())
+    }
 }
 
 /// Get information about a model by name or URL
-pub async fn get_model_info(model_identifier: &str) -> Result<ModelInfo> {
-    // This is a simplified implementation - in a real-world scenario, 
-    // we would query an API to get model information
+///
+/// An identifier ending in `@<revision>`, such as `mistral-7b@<commit>`, pins
+/// a Hugging Face download to that branch, tag or commit instead of `main`.
+pub async fn get_model_info(model_identifier: &str, config: &DownloadConfig) -> Result<ModelInfo> {
+    let (model_identifier, revision) = split_revision(model_identifier);
+    let mut model_info = lookup_model_info(model_identifier, config).await?;
+    if let Some(revision) = revision {
+        // The registry's hashes and size describe the file it points at, which
+        // another revision needn't match, so only its own pin keeps them
+        if model_info.revision.as_deref() != Some(revision) {
+            model_info.sha256.clear();
+            model_info.size_bytes = 0;
+            for extra_file in &mut model_info.extra_files {
+                extra_file.sha256.clear();
+            }
+        }
+        model_info.download_url = pin_revision(&model_info.download_url, revision)?;
+        for extra_file in &mut model_info.extra_files {
+            extra_file.url = pin_revision(&extra_file.url, revision)?;
+        }
+        model_info.revision = Some(revision.to_string());
+    }
+    Ok(model_info)
+}
+
+/// The file next to a model recording the revision it was pinned to
+fn revision_path(model_path: &Path) -> PathBuf {
+    let mut path = model_path.as_os_str().to_owned();
+    path.push(".revision");
+    PathBuf::from(path)
+}
+
+/// The Hugging Face revision the model at `model_path` was downloaded from,
+/// if the download was pinned to one
+pub fn stored_revision(model_path: &Path) -> Option<String> {
+    let revision = std::fs::read_to_string(revision_path(model_path)).ok()?;
+    Some(revision.trim().to_string()).filter(|revision| !revision.is_empty())
+}
+
+/// Record the revision the model at `model_path` was downloaded from, or
+/// forget an earlier one if the download wasn't pinned
+pub fn store_revision(model_path: &Path, revision: Option<&str>) -> std::io::Result<()> {
+    match revision {
+        Some(revision) => std::fs::write(revision_path(model_path), revision),
+        None => match std::fs::remove_file(revision_path(model_path)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        },
+    }
+}
+
+/// Split a trailing `@<revision>` off a model identifier
+///
+/// An `@` before the last `/` belongs to the URL (e.g. `user@host`), not a revision.
+fn split_revision(identifier: &str) -> (&str, Option<&str>) {
+    match identifier.rsplit_once('@') {
+        Some((base, revision)) if !base.is_empty() && !revision.contains('/') => (base, Some(revision)),
+        _ => (identifier, None),
+    }
+}
+
+/// Point a Hugging Face `.../resolve/<revision>/<file>` URL at another revision
+fn pin_revision(url: &str, revision: &str) -> Result<String> {
+    if revision.is_empty()
+        || !revision.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        anyhow::bail!("Invalid revision: {:?}", revision);
+    }
+    
+    let mut parsed = Url::parse(url).with_context(|| format!("Invalid download URL: {}", url))?;
+    let mut segments: Vec<String> = parsed
+        .path_segments()
+        .map(|segments| segments.map(str::to_string).collect())
+        .unwrap_or_default();
+    let resolve = segments
+        .iter()
+        .position(|segment| segment == "resolve")
+        .filter(|&index| index + 2 < segments.len())
+        .with_context(|| format!("Can't pin a revision for {}: only Hugging Face resolve/ URLs have revisions", url))?;
+    segments[resolve + 1] = revision.to_string();
+    parsed.set_path(&segments.join("/"));
+    
+    Ok(parsed.to_string())
+}
+
+/// Look up a model by name or URL, without a revision
+///
+/// Built-in registry names are checked first, then the remote registry (if
+/// configured), then direct URLs, and finally a fuzzy match on built-in names.
+async fn lookup_model_info(model_identifier: &str, config: &DownloadConfig) -> Result<ModelInfo> {
+    if let Some(model_info) = builtin_model_info(model_identifier) {
+        return Ok(model_info);
+    }
+    
+    // A URL can't be a registry name, so only names are sent to the remote registry
+    let is_url = model_identifier.starts_with("http");
+    
+    if !is_url {
+        if let Some(registry_url) = &config.registry_url {
+            let remote = fetch_remote_model_info(
+                &config.client,
+                registry_url,
+                config.registry_token.as_deref(),
+                model_identifier,
+            )
+            .await?;
+            if let Some(model_info) = remote {
+                return Ok(model_info);
+            }
+        }
+    }
     
-    // For now, we'll handle a few known models or assume it's a direct URL
-    let model_info = if model_identifier.starts_with("http") {
+    if is_url {
         // Direct URL
         let url = model_identifier;
-        let filename = url
-            .split('/')
-            .last()
-            .context("Invalid URL format")?
-            .to_string();
+        let filename = filename_from_url(url)?;
         
-        ModelInfo {
+        return Ok(ModelInfo {
             name: filename.clone(),
             filename,
             download_url: url.to_string(),
             sha256: String::new(), // No hash verification for direct URLs
             size_bytes: 0, // Unknown size
             description: None,
-        }
+            subdir: None,
+            revision: None,
+            extra_files: Vec::new(),
+            parallel: None,
+        });
+    }
+    
+    // Unknown model - try to normalize the name and guess
+    let normalized = model_identifier.to_lowercase();
+    let guess = if normalized.contains("llama") {
+        "llama2-7b"
+    } else if normalized.contains("mistral") {
+        "mistral-7b"
+    } else if normalized.contains("phi") {
+        "phi-2"
+    } else if normalized.contains("neural") || normalized.contains("chat") {
+        "neural-chat-7b"
     } else {
-        // Known model names (in a real implementation, this would come from an API)
-        match model_identifier {
-            "llama2-7b" => ModelInfo {
-                name: "llama2-7b".to_string(),
-                filename: "llama2-7b.Q4_K_M.gguf".to_string(),
-                download_url: "https://huggingface.co/TheBloke/Llama-2-7B-GGUF/resolve/main/llama-2-7b.Q4_K_M.gguf".to_string(),
-                sha256: "6d8bbd42948f56e7b2d68e92b976deaae03d2f7e8a8da8432f8487b8237dafcc".to_string(),
-                size_bytes: 4_000_000_000, // Approximate size
-                description: Some("Llama 2 7B quantized to 4-bit".to_string()),
-            },
-            "mistral-7b" => ModelInfo {
-                name: "mistral-7b".to_string(),
-                filename: "mistral-7b.Q4_K_M.gguf".to_string(),
-                download_url: "https://huggingface.co/TheBloke/Mistral-7B-v0.1-GGUF/resolve/main/mistral-7b-v0.1.Q4_K_M.gguf".to_string(),
-                sha256: "121e7a20a0a5e4db86f57d5ffabb534d6e1efa8c11ed0692a74987787580a6c5".to_string(),
-                size_bytes: 4_200_000_000, // Approximate size
-                description: Some("Mistral 7B quantized to 4-bit".to_string()),
-            },
-            "phi-2" => ModelInfo {
-                name: "phi-2".to_string(),
-                filename: "phi-2.Q4_K_M.gguf".to_string(),
-                download_url: "https://huggingface.co/TheBloke/phi-2-GGUF/resolve/main/phi-2.Q4_K_M.gguf".to_string(),
-                sha256: "324356668fa5ba9f4135de348447bb2bbe2467eaa1b8fcfb53719de62fbd2499".to_string(),
-                size_bytes: 1_800_000_000, // Approximate size
-                description: Some("Phi-2 quantized to 4-bit".to_string()),
-            },
-            "neural-chat-7b" => ModelInfo {
-                name: "neural-chat-7b".to_string(),
-                filename: "neural-chat-7b.Q4_K_M.gguf".to_string(),
-                download_url: "https://huggingface.co/TheBloke/neural-chat-7B-v3-1-GGUF/resolve/main/neural-chat-7b-v3-1.Q4_K_M.gguf".to_string(),
-                sha256: "e7eb44a9c9a3ccbc92fc0bdcf5a9575d4c6e2f98f5e160e4283c0c3d627a9e50".to_string(),
-                size_bytes: 4_300_000_000, // Approximate size
-                description: Some("Neural Chat 7B v3.1 quantized to 4-bit".to_string()),
-            },
-            _ => {
-                // Unknown model - try to normalize the name and guess
-                let normalized = model_identifier.to_lowercase();
-                if normalized.contains("llama") {
-                    Box::pin(get_model_info("llama2-7b")).await?
-                } else if normalized.contains("mistral") {
-                    Box::pin(get_model_info("mistral-7b")).await?
-                } else if normalized.contains("phi") {
-                    Box::pin(get_model_info("phi-2")).await?
-                } else if normalized.contains("neural") || normalized.contains("chat") {
-                    Box::pin(get_model_info("neural-chat-7b")).await?
-                } else {
-                    anyhow::bail!("Unknown model: {}. Please provide a URL or a supported model name.", model_identifier);
-                }
-            }
-        }
+        anyhow::bail!("Unknown model: {}. Please provide a URL or a supported model name.", model_identifier);
     };
     
-    Ok(model_info)
+    builtin_model_info(guess).context("Built-in model missing from registry")
+}
+
+/// The models `model pull` knows by name, in the order they are listed
+pub fn builtin_models() -> Vec<ModelInfo> {
+    vec![
+        ModelInfo {
+            name: "llama2-7b".to_string(),
+            filename: "llama2-7b.Q4_K_M.gguf".to_string(),
+            download_url: "https://huggingface.co/TheBloke/Llama-2-7B-GGUF/resolve/main/llama-2-7b.Q4_K_M.gguf".to_string(),
+            sha256: "6d8bbd42948f56e7b2d68e92b976deaae03d2f7e8a8da8432f8487b8237dafcc".to_string(),
+            size_bytes: 4_000_000_000, // Approximate size
+            description: Some("Llama 2 7B quantized to 4-bit".to_string()),
+            subdir: None,
+            revision: None,
+            extra_files: Vec::new(),
+            parallel: None,
+        },
+        ModelInfo {
+            name: "mistral-7b".to_string(),
+            filename: "mistral-7b.Q4_K_M.gguf".to_string(),
+            download_url: "https://huggingface.co/TheBloke/Mistral-7B-v0.1-GGUF/resolve/main/mistral-7b-v0.1.Q4_K_M.gguf".to_string(),
+            sha256: "121e7a20a0a5e4db86f57d5ffabb534d6e1efa8c11ed0692a74987787580a6c5".to_string(),
+            size_bytes: 4_200_000_000, // Approximate size
+            description: Some("Mistral 7B quantized to 4-bit".to_string()),
+            subdir: None,
+            revision: None,
+            extra_files: Vec::new(),
+            parallel: None,
+        },
+        ModelInfo {
+            name: "phi-2".to_string(),
+            filename: "phi-2.Q4_K_M.gguf".to_string(),
+            download_url: "https://huggingface.co/TheBloke/phi-2-GGUF/resolve/main/phi-2.Q4_K_M.gguf".to_string(),
+            sha256: "324356668fa5ba9f4135de348447bb2bbe2467eaa1b8fcfb53719de62fbd2499".to_string(),
+            size_bytes: 1_800_000_000, // Approximate size
+            description: Some("Phi-2 quantized to 4-bit".to_string()),
+            subdir: None,
+            revision: None,
+            extra_files: Vec::new(),
+            parallel: None,
+        },
+        ModelInfo {
+            name: "neural-chat-7b".to_string(),
+            filename: "neural-chat-7b.Q4_K_M.gguf".to_string(),
+            download_url: "https://huggingface.co/TheBloke/neural-chat-7B-v3-1-GGUF/resolve/main/neural-chat-7b-v3-1.Q4_K_M.gguf".to_string(),
+            sha256: "e7eb44a9c9a3ccbc92fc0bdcf5a9575d4c6e2f98f5e160e4283c0c3d627a9e50".to_string(),
+            size_bytes: 4_300_000_000, // Approximate size
+            description: Some("Neural Chat 7B v3.1 quantized to 4-bit".to_string()),
+            subdir: None,
+            revision: None,
+            extra_files: Vec::new(),
+            parallel: None,
+        },
+    ]
+}
+
+/// Find the built-in model that downloads to `filename`, if any
+pub fn builtin_model_for_file(filename: &str) -> Option<ModelInfo> {
+    builtin_models().into_iter().find(|info| info.filename == filename)
+}
+
+/// The registry entry a downloaded model file came from: a built-in model, or
+/// an entry the remote registry returned earlier in this process
+pub fn registry_entry_for_file(filename: &str) -> Option<ModelInfo> {
+    builtin_model_for_file(filename).or_else(|| {
+        let cache = REMOTE_REGISTRY_CACHE.get()?.lock().unwrap();
+        cache.values().find(|info| info.filename == filename).cloned()
+    })
+}
+
+/// Look up one of the built-in models by its exact name
+fn builtin_model_info(model_name: &str) -> Option<ModelInfo> {
+    builtin_models().into_iter().find(|info| info.name == model_name)
+}
+
+/// Remote registry responses, cached for the lifetime of the process
+static REMOTE_REGISTRY_CACHE: OnceLock<Mutex<HashMap<String, ModelInfo>>> = OnceLock::new();
+
+/// Drop every cached remote registry response so the next lookup asks the
+/// registry again, returning how many were dropped
+pub fn clear_remote_registry_cache() -> usize {
+    REMOTE_REGISTRY_CACHE.get().map_or(0, |cache| {
+        let mut cache = cache.lock().unwrap();
+        let cleared = cache.len();
+        cache.clear();
+        cleared
+    })
+}
+
+/// Query the remote registry at `<registry_url>/<model_name>`
+///
+/// Returns `Ok(None)` when the registry doesn't know the model.
+async fn fetch_remote_model_info(
+    client: &Client,
+    registry_url: &Url,
+    token: Option<&str>,
+    model_name: &str,
+) -> Result<Option<ModelInfo>> {
+    let cache = REMOTE_REGISTRY_CACHE.get_or_init(|| Mutex::new(HashMap::new()));
+    if let Some(model_info) = cache.lock().unwrap().get(model_name) {
+        debug!("Using cached registry entry for {}", model_name);
+        return Ok(Some(model_info.clone()));
+    }
+    
+    let mut url = registry_url.clone();
+    url.path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("Registry URL cannot be a base: {}", registry_url))?
+        .pop_if_empty()
+        .push(model_name);
+    
+    debug!("Querying model registry at {}", url);
+    let mut request = client.get(url.clone());
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    
+    let response = request
+        .send()
+        .await
+        .with_context(|| format!("Failed to query model registry at {}", url))?;
+    
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    
+    let model_info: ModelInfo = response
+        .error_for_status()
+        .with_context(|| format!("Model registry returned an error for {}", model_name))?
+        .json()
+        .await
+        .with_context(|| format!("Invalid model registry response for {}", model_name))?;
+    
+    cache.lock().unwrap().insert(model_name.to_string(), model_info.clone());
+    Ok(Some(model_info))
+}
+
+/// Names of the GGUF files in a Hugging Face repository such as
+/// `TheBloke/Mistral-7B-v0.1-GGUF`, listed through the Hub API
+pub async fn hf_repo_gguf_files(repo: &str, config: &DownloadConfig) -> Result<Vec<String>> {
+    #[derive(Deserialize)]
+    struct RepoInfo {
+        siblings: Vec<RepoFile>,
+    }
+    #[derive(Deserialize)]
+    struct RepoFile {
+        rfilename: String,
+    }
+    
+    let url = format!("https://{}/api/models/{}", HF_HOST, repo);
+    let url = match &config.endpoint {
+        Some(endpoint) => apply_endpoint(&url, endpoint)?,
+        None => url,
+    };
+    debug!("Listing files of {} at {}", repo, url);
+    let info: RepoInfo = config.client
+        .get(&url)
+        .send()
+        .await
+        .with_context(|| format!("Failed to list the files of {}", repo))?
+        .error_for_status()
+        .with_context(|| format!("Hugging Face returned an error for repository {}", repo))?
+        .json()
+        .await
+        .with_context(|| format!("Invalid file listing for repository {}", repo))?;
+    
+    Ok(info.siblings
+        .into_iter()
+        .map(|file| file.rfilename)
+        .filter(|name| name.ends_with(".gguf"))
+        .collect())
+}
+
+/// Download URL of a file on the main branch of a Hugging Face repository
+pub fn hf_file_url(repo: &str, filename: &str) -> String {
+    format!("https://{}/{}/resolve/main/{}", HF_HOST, repo, filename)
 }
 
 /// Download a model file from the given URL to the target path
-pub async fn download_model_file(url: &str, target_path: &Path, expected_hash: &str) -> Result<()> {
-    let client = Client::new();
+///
+/// When `config.connections` is greater than one and the server advertises
+/// range support, the file is fetched as that many concurrent byte ranges.
+pub async fn download_model_file(
+    url: &str,
+    target_path: &Path,
+    expected_hash: &str,
+    config: &DownloadConfig,
+) -> Result<(), ModelError> {
+    download_and_verify(url, target_path, expected_hash, config)
+        .await
+        .map_err(|e| match e.downcast::<ModelError>() {
+            Ok(model_error) => model_error,
+            Err(e) => ModelError::DownloadFailed(format!("{:#}", e)),
+        })
+}
+
+/// Download a model and its companion files
+///
+/// The model goes to `model_path` and its `extra_files` next to it, each
+/// checked against its hash unless `verify_hash` is off. A pinned revision is
+/// recorded next to the model (see `stored_revision`).
+pub async fn download_model_with_options(
+    model_info: &ModelInfo,
+    model_path: &Path,
+    verify_hash: bool,
+    config: &DownloadConfig,
+) -> Result<(), ModelError> {
+    let expected_hash = if verify_hash { model_info.sha256.as_str() } else { "" };
+    download_model_file(&model_info.download_url, model_path, expected_hash, config).await?;
+    store_revision(model_path, model_info.revision.as_deref())?;
+    download_extra_files(model_info, model_path, verify_hash, config).await
+}
+
+/// Download the companion files of the model at `model_path` that aren't
+/// there yet
+///
+/// A file that is already there is kept if it matches its hash (or has none,
+/// or `verify_hash` is off) and downloaded again otherwise.
+pub async fn download_extra_files(
+    model_info: &ModelInfo,
+    model_path: &Path,
+    verify_hash: bool,
+    config: &DownloadConfig,
+) -> Result<(), ModelError> {
+    let count = model_info.extra_files.len();
+    for (index, extra_file) in model_info.extra_files.iter().enumerate() {
+        let path = extra_file.path_next_to(model_path)
+            .map_err(|e| ModelError::DownloadFailed(e.to_string()))?;
+        let expected_hash = if verify_hash { extra_file.sha256.as_str() } else { "" };
+        
+        if path.is_file() {
+            let matches = expected_hash.is_empty() || {
+                let hash_path = path.clone();
+                tokio::task::spawn_blocking(move || super::calculate_file_hash_with_progress(&hash_path, |_| {}))
+                    .await
+                    .map_err(|e| ModelError::DownloadFailed(e.to_string()))?
+                    .map_err(|e| ModelError::DownloadFailed(format!("{:#}", e)))?
+                    == expected_hash
+            };
+            if matches {
+                debug!("Companion file {:?} is already downloaded", path);
+                continue;
+            }
+            info!("Companion file {:?} doesn't match its hash, downloading it again", path);
+        }
+        
+        if !config.quiet {
+            println!("Downloading companion file {}/{}: {}", index + 1, count, extra_file.filename);
+        }
+        download_model_file(&extra_file.url, &path, expected_hash, config).await?;
+    }
+    Ok(())
+}
+
+/// Derive a local filename from the last path segment of a download URL
+///
+/// The query string and fragment are dropped, and characters that aren't
+/// allowed in filenames on every platform are replaced.
+fn filename_from_url(url: &str) -> Result<String> {
+    let parsed = Url::parse(url).with_context(|| format!("Invalid URL: {}", url))?;
+    let last_segment = parsed
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|segment| !segment.is_empty())
+        .with_context(|| format!("URL has no file name: {}", url))?;
+    
+    Ok(crate::utils::sanitize_filename(last_segment))
+}
+
+/// Use the extended-length (`\\?\`) form of the path on Windows, so files
+/// can be written past the 260 character `MAX_PATH` limit
+#[cfg(windows)]
+fn extended_length_path(path: &Path) -> PathBuf {
+    let absolute = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    let prefixed = match absolute.to_str() {
+        Some(path) if path.starts_with(r"\\?\") => None,
+        Some(path) => match path.strip_prefix(r"\\") {
+            // UNC paths (\\server\share) use their own prefix
+            Some(unc) => Some(PathBuf::from(format!(r"\\?\UNC\{}", unc))),
+            None => Some(PathBuf::from(format!(r"\\?\{}", path))),
+        },
+        None => None,
+    };
+    prefixed.unwrap_or(absolute)
+}
+
+#[cfg(not(windows))]
+fn extended_length_path(path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
+
+/// Body of `download_model_file`; typed failures are raised as `ModelError`
+/// and everything else is reported as `DownloadFailed`
+async fn download_and_verify(
+    url: &str,
+    target_path: &Path,
+    expected_hash: &str,
+    config: &DownloadConfig,
+) -> Result<()> {
+    let target_path = &extended_length_path(target_path);
+    let url = match &config.endpoint {
+        Some(endpoint) => apply_endpoint(url, endpoint)?,
+        None => url.to_string(),
+    };
+    let url = url.as_str();
+    let client = &config.client;
     
     // Get content length for progress bar
     let response = client
@@ -117,12 +706,30 @@ pub async fn download_model_file(url: &str, target_path: &Path, expected_hash: &
         .and_then(|cl_str| cl_str.parse::<u64>().ok())
         .unwrap_or(0);
     
+    let supports_ranges = response
+        .headers()
+        .get(reqwest::header::ACCEPT_RANGES)
+        .and_then(|ar| ar.to_str().ok())
+        .map(|ar| ar.eq_ignore_ascii_case("bytes"))
+        .unwrap_or(false);
+    
+    if let Some(progress) = &config.progress {
+        progress.add_total(total_size);
+    }
+    
+    let use_ranges = config.connections > 1 && supports_ranges && total_size > 0;
+    if config.connections > 1 && !use_ranges {
+        info!("Server does not support range requests, falling back to a single connection");
+    }
+    
     // Create a temporary file
     let temp_dir = tempfile::tempdir()?;
     let temp_path = temp_dir.path().join("model_download.tmp");
     
     // Set up progress bar
-    let progress_bar = if total_size > 0 {
+    let progress_bar = if config.quiet {
+        ProgressBar::hidden()
+    } else if total_size > 0 {
         let pb = ProgressBar::new(total_size);
         pb.set_style(
             ProgressStyle::default_bar()
@@ -141,56 +748,54 @@ pub async fn download_model_file(url: &str, target_path: &Path, expected_hash: &
     
     // Start the download
     info!("Downloading model from {}", url);
-    println!("Downloading model from {}", url);
-    
-    let response = client
-        .get(url)
-        .send()
-        .await
-        .context("Failed to send GET request")?;
-    
-    let mut stream = response.bytes_stream();
-    let mut file = tokio::fs::File::create(&temp_path).await?;
-    let mut downloaded_bytes = 0u64;
-    let mut hasher = Sha256::new();
-    
-    while let Some(item) = stream.next().await {
-        let chunk = item.context("Error while downloading file")?;
-        file.write_all(&chunk).await?;
-        hasher.update(&chunk);
-        
-        downloaded_bytes += chunk.len() as u64;
-        progress_bar.set_position(downloaded_bytes);
+    if !config.quiet {
+        println!("Downloading model from {}", url);
     }
     
-    // Close the file
-    file.flush().await?;
-    drop(file);
-    
-    progress_bar.finish_with_message("Download completed");
+    let hash = if use_ranges {
+        download_ranges(client, url, &temp_path, total_size, config, &progress_bar).await?;
+        progress_bar.finish_with_message("Download completed");
+        
+        // Chunks arrive out of order, so hash the assembled file instead of the stream
+        if expected_hash.is_empty() {
+            String::new()
+        } else {
+            if !config.quiet {
+                println!("Computing hash of downloaded file...");
+            }
+            let path = temp_path.clone();
+            tokio::task::spawn_blocking(move || super::calculate_file_hash_with_progress(&path, |_| {}))
+                .await??
+        }
+    } else {
+        let hash = download_single(client, url, &temp_path, config, &progress_bar).await?;
+        progress_bar.finish_with_message("Download completed");
+        hash
+    };
     
     // Verify hash if provided
     if !expected_hash.is_empty() {
-        let hash = hex::encode(hasher.finalize());
         if hash != expected_hash {
-            println!("⚠️  Hash verification failed!");
-            println!("   Expected: {}", expected_hash);
-            println!("   Got:      {}", hash);
-            println!("   This usually means the model file has been updated.");
-            println!("   You can either:");
-            println!("   1. Report this issue if you believe the hash in the code is wrong");
-            println!("   2. Use a direct URL download which skips hash verification");
-            println!("   3. Continue anyway if you trust the source (not recommended)");
+            eprintln!("⚠️  Hash verification failed!");
+            eprintln!("   Expected: {}", expected_hash);
+            eprintln!("   Got:      {}", hash);
+            eprintln!("   This usually means the model file has been updated.");
+            eprintln!("   You can either:");
+            eprintln!("   1. Report this issue if you believe the hash in the code is wrong");
+            eprintln!("   2. Use a direct URL download which skips hash verification");
+            eprintln!("   3. Continue anyway if you trust the source (not recommended)");
             
-            anyhow::bail!(
-                "Hash verification failed. Expected {}, got {}. See above for solutions.",
-                expected_hash,
-                hash
-            );
+            return Err(ModelError::HashMismatch {
+                expected: expected_hash.to_string(),
+                actual: hash,
+            }
+            .into());
+        }
+        if !config.quiet {
+            println!("✅ Hash verification successful");
         }
-        println!("✅ Hash verification successful");
         debug!("Hash verification successful");
-    } else {
+    } else if !config.quiet {
         println!("⚠️  Skipping hash verification (no expected hash provided)");
     }
     
@@ -209,7 +814,266 @@ pub async fn download_model_file(url: &str, target_path: &Path, expected_hash: &
         })?;
     
     info!("Model downloaded and saved to {:?}", target_path);
-    println!("Model downloaded and saved to {:?}", target_path);
+    if !config.quiet {
+        println!("Model downloaded and saved to {:?}", target_path);
+    }
     
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Stream the whole file over a single connection, hashing as it arrives
+async fn download_single(
+    client: &Client,
+    url: &str,
+    temp_path: &Path,
+    config: &DownloadConfig,
+    progress_bar: &ProgressBar,
+) -> Result<String> {
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .context("Failed to send GET request")?;
+    
+    let expected_size = response.content_length();
+    let mut stream = response.bytes_stream();
+    let mut file = tokio::fs::File::create(temp_path).await?;
+    let mut downloaded_bytes = 0u64;
+    let mut hasher = Sha256::new();
+    
+    while let Some(chunk) = next_chunk(&mut stream, config.stall_timeout).await? {
+        file.write_all(&chunk).await?;
+        hasher.update(&chunk);
+        
+        downloaded_bytes += chunk.len() as u64;
+        progress_bar.set_position(downloaded_bytes);
+        if let Some(progress) = &config.progress {
+            progress.add(chunk.len() as u64);
+        }
+    }
+    
+    // Close the file
+    file.flush().await?;
+    
+    // A connection closed early still ends the stream cleanly, and without a
+    // hash to check the file would be kept
+    if let Some(expected) = expected_size {
+        check_download_size(expected, downloaded_bytes)?;
+    }
+    
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Download the file as `connections` concurrent byte ranges written into a
+/// preallocated file at their respective offsets
+async fn download_ranges(
+    client: &Client,
+    url: &str,
+    temp_path: &Path,
+    total_size: u64,
+    config: &DownloadConfig,
+    progress_bar: &ProgressBar,
+) -> Result<()> {
+    let connections = config.connections;
+    // Preallocate the file so every range can seek to its offset
+    let file = tokio::fs::File::create(temp_path).await?;
+    file.set_len(total_size).await?;
+    drop(file);
+    
+    let range_size = total_size.div_ceil(connections as u64);
+    debug!("Downloading {} bytes as {} ranges of {} bytes", total_size, connections, range_size);
+    
+    let ranges = (0..connections as u64)
+        .map(|i| i * range_size)
+        .take_while(|&start| start < total_size)
+        .map(|start| {
+            let end = (start + range_size).min(total_size) - 1;
+            download_range(client, url, temp_path, start, end, config, progress_bar)
+        });
+    
+    futures::future::try_join_all(ranges).await?;
+    Ok(())
+}
+
+/// Download the inclusive byte range `start..=end` into the file at `start`
+async fn download_range(
+    client: &Client,
+    url: &str,
+    temp_path: &Path,
+    start: u64,
+    end: u64,
+    config: &DownloadConfig,
+    progress_bar: &ProgressBar,
+) -> Result<()> {
+    let response = client
+        .get(url)
+        .header(reqwest::header::RANGE, format!("bytes={}-{}", start, end))
+        .send()
+        .await
+        .context("Failed to send ranged GET request")?;
+    
+    if response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+        anyhow::bail!(
+            "Server did not honor range request bytes={}-{} (status {})",
+            start,
+            end,
+            response.status()
+        );
+    }
+    
+    let mut file = tokio::fs::OpenOptions::new().write(true).open(temp_path).await?;
+    file.seek(std::io::SeekFrom::Start(start)).await?;
+    
+    let mut stream = response.bytes_stream();
+    let mut downloaded_bytes = 0u64;
+    while let Some(chunk) = next_chunk(&mut stream, config.stall_timeout).await? {
+        file.write_all(&chunk).await?;
+        downloaded_bytes += chunk.len() as u64;
+        progress_bar.inc(chunk.len() as u64);
+        if let Some(progress) = &config.progress {
+            progress.add(chunk.len() as u64);
+        }
+    }
+    
+    file.flush().await?;
+    check_download_size(end - start + 1, downloaded_bytes)
+}
+
+/// Fail with `ModelError::TruncatedDownload` unless `got` bytes arrived of the
+/// `expected` ones
+fn check_download_size(expected: u64, got: u64) -> Result<()> {
+    if got != expected {
+        return Err(ModelError::TruncatedDownload { expected, got }.into());
+    }
+    Ok(())
+}
+
+/// Wait for the next chunk of a response body, failing with
+/// `ModelError::DownloadStalled` if none arrives within `stall_timeout`
+async fn next_chunk<S, T>(stream: &mut S, stall_timeout: Duration) -> Result<Option<T>>
+where
+    S: futures::Stream<Item = reqwest::Result<T>> + Unpin,
+{
+    match tokio::time::timeout(stall_timeout, stream.next()).await {
+        Ok(Some(item)) => Ok(Some(item.context("Error while downloading file")?)),
+        Ok(None) => Ok(None),
+        Err(_) => Err(ModelError::DownloadStalled { seconds: stall_timeout.as_secs() }.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filename_from_url_drops_query_and_fragment() {
+        let filename = filename_from_url(
+            "https://huggingface.co/org/repo/resolve/main/model.Q4_K_M.gguf?download=true#section",
+        )
+        .unwrap();
+
+        assert_eq!(filename, "model.Q4_K_M.gguf");
+    }
+
+    #[test]
+    fn filename_from_url_replaces_reserved_characters() {
+        let filename = filename_from_url("https://example.com/files/a:b*c%22d%3F|e.gguf.").unwrap();
+
+        assert_eq!(filename, "a_b_c%22d%3F_e.gguf");
+        assert!(!filename.contains(['/', '\\', ':', '*', '?', '"', '<', '>', '|']));
+    }
+
+    #[test]
+    fn filename_from_url_rejects_urls_without_a_file_name() {
+        assert!(filename_from_url("https://example.com/models/").is_err());
+    }
+
+    #[test]
+    fn pin_revision_replaces_the_resolved_branch() {
+        let (name, revision) = split_revision("https://huggingface.co/org/repo/resolve/main/model.gguf@0123abc");
+        let pinned = pin_revision(name, revision.unwrap()).unwrap();
+
+        assert_eq!(pinned, "https://huggingface.co/org/repo/resolve/0123abc/model.gguf");
+        assert!(pin_revision("https://example.com/model.gguf", "0123abc").is_err());
+    }
+
+    #[tokio::test]
+    async fn pinned_builtin_models_skip_the_registry_hash() {
+        let config = DownloadConfig::default();
+        let model_info = get_model_info("mistral-7b@0123abc", &config).await.unwrap();
+
+        assert_eq!(model_info.revision.as_deref(), Some("0123abc"));
+        assert!(model_info.download_url.contains("/resolve/0123abc/"));
+        assert!(model_info.sha256.is_empty());
+        assert!(!get_model_info("mistral-7b", &config).await.unwrap().sha256.is_empty());
+    }
+
+    #[test]
+    fn revision_is_recorded_next_to_the_model() {
+        let dir = tempfile::tempdir().unwrap();
+        let model_path = dir.path().join("model.gguf");
+
+        assert_eq!(stored_revision(&model_path), None);
+        store_revision(&model_path, Some("0123abc")).unwrap();
+        assert_eq!(stored_revision(&model_path).as_deref(), Some("0123abc"));
+        store_revision(&model_path, None).unwrap();
+        store_revision(&model_path, None).unwrap();
+        assert_eq!(stored_revision(&model_path), None);
+    }
+
+    #[test]
+    fn extra_files_go_next_to_the_model() {
+        let extra_file = ExtraFile {
+            url: "https://example.com/mmproj-f16.gguf".to_string(),
+            filename: "mmproj-f16.gguf".to_string(),
+            sha256: String::new(),
+        };
+        let path = extra_file.path_next_to(Path::new("models/llava/llava-v1.5-7b.Q4_K_M.gguf")).unwrap();
+
+        assert_eq!(path, Path::new("models/llava/mmproj-f16.gguf"));
+        for filename in ["../mmproj.gguf", "sub/mmproj.gguf", ""] {
+            let extra_file = ExtraFile { filename: filename.to_string(), ..extra_file.clone() };
+            assert!(extra_file.path_next_to(Path::new("models/model.gguf")).is_err(), "{:?}", filename);
+        }
+    }
+
+    #[test]
+    fn relative_path_stays_in_the_models_dir() {
+        let mut model_info = builtin_models().remove(0);
+        model_info.subdir = Some("llama".to_string());
+        let path = model_info.relative_path().unwrap();
+
+        assert_eq!(path, Path::new("llama").join(&model_info.filename));
+        for filename in ["../../.bashrc", "/etc/hosts", "sub/model.gguf", ""] {
+            let model_info = ModelInfo { filename: filename.to_string(), ..model_info.clone() };
+            assert!(model_info.relative_path().is_err(), "{:?}", filename);
+        }
+    }
+
+    #[test]
+    fn output_file_name_is_sanitized() {
+        let mut model_info = builtin_models().remove(0);
+        let sha256 = model_info.sha256.clone();
+
+        model_info.set_filename("mistral:q8").unwrap();
+        assert_eq!(model_info.filename, "mistral_q8.gguf");
+        assert_eq!(model_info.sha256, sha256);
+
+        model_info.set_filename("../escape/model.gguf").unwrap();
+        assert_eq!(model_info.filename, ".._escape_model.gguf");
+        for filename in ["", "..", " . "] {
+            assert!(model_info.set_filename(filename).is_err(), "{:?}", filename);
+        }
+    }
+
+    #[test]
+    fn short_download_is_truncated() {
+        assert!(check_download_size(1024, 1024).is_ok());
+
+        let error = check_download_size(1024, 512).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ModelError>(),
+            Some(ModelError::TruncatedDownload { expected: 1024, got: 512 })
+        ));
+    }
+}
@@ -0,0 +1,116 @@
+//! Error types for model management and inference
+
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Errors returned by the model module
+///
+/// Callers can match on the variant to tell, for example, a missing model
+/// apart from a disk failure.
+#[derive(Debug, Error)]
+pub enum ModelError {
+    /// No model matched the given name
+    #[error("Model {name} not found in {dirs:?}")]
+    NotFound { name: String, dirs: Vec<PathBuf> },
+
+    /// The name partially matched more than one model file
+    #[error("Model name {name} is ambiguous, it matches: {}", .candidates.join(", "))]
+    MultipleMatches { name: String, candidates: Vec<String> },
+
+    /// Filesystem error
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// The model file could not be downloaded
+    #[error("Download failed: {0}")]
+    DownloadFailed(String),
+
+    /// The server stopped sending data without closing the connection
+    #[error("Download stalled: no data received for {seconds} seconds")]
+    DownloadStalled { seconds: u64 },
+
+    /// The connection closed before the whole body announced by
+    /// `Content-Length` arrived
+    #[error("Download ended early: expected {expected} bytes, got {got}")]
+    TruncatedDownload { expected: u64, got: u64 },
+
+    /// The downloaded file doesn't match the expected SHA256
+    #[error("Hash verification failed. Expected {expected}, got {actual}")]
+    HashMismatch { expected: String, actual: String },
+
+    /// llama.cpp could not load the model or create its context
+    #[cfg_attr(not(feature = "inference"), allow(dead_code))]
+    #[error("Failed to load model: {0}")]
+    LoadFailed(String),
+
+    /// llama.cpp could not parse the grammar generation was constrained to
+    #[cfg_attr(not(feature = "inference"), allow(dead_code))]
+    #[error("Invalid grammar: {0}")]
+    InvalidGrammar(String),
+
+    /// llama.cpp failed while decoding or sampling
+    #[error("Generation failed: {0}")]
+    GenerationFailed(String),
+
+    /// rustllm was built without the `inference` feature
+    #[cfg_attr(feature = "inference", allow(dead_code))]
+    #[error("rustllm was built without inference support (enable the `inference` feature)")]
+    InferenceDisabled,
+
+    /// The request has images but the model can't read them
+    #[error("Image input is not available: {0}")]
+    VisionUnsupported(String),
+
+    /// An image couldn't be decoded, or the prompt's image markers don't match the images
+    #[cfg_attr(not(feature = "vision"), allow(dead_code))]
+    #[error("Invalid image: {0}")]
+    InvalidImage(String),
+
+    /// The file doesn't start with the GGUF magic number
+    #[cfg_attr(not(feature = "inference"), allow(dead_code))]
+    #[error("{path:?} is not a GGUF model file (it doesn't start with the GGUF magic number)")]
+    NotAGgufFile { path: PathBuf },
+
+    /// The file is much smaller than the registry says it should be
+    #[cfg_attr(not(feature = "inference"), allow(dead_code))]
+    #[error(
+        "Model file {path:?} is only {size} bytes but {name} should be about {expected} bytes, \
+         the download was probably interrupted. Download it again with `rustllm model pull {name} --force`"
+    )]
+    IncompleteDownload { path: PathBuf, name: String, size: u64, expected: u64 },
+
+    /// The GGUF header is truncated or malformed
+    #[error("{path:?} has an invalid GGUF header: {reason}")]
+    InvalidGgufHeader { path: PathBuf, reason: String },
+
+    /// The file uses a GGUF version the bundled llama.cpp can't read
+    #[cfg_attr(not(feature = "inference"), allow(dead_code))]
+    #[error(
+        "{path:?} is a GGUF v{found} file, but at least v{minimum} is required. \
+         Download a current quantization of the model instead"
+    )]
+    UnsupportedGgufVersion { path: PathBuf, found: u32, minimum: u32 },
+
+    /// The model's weights and KV cache won't fit in the available memory
+    #[cfg_attr(not(feature = "inference"), allow(dead_code))]
+    #[error(
+        "Loading {path:?} needs about {} of memory but only {} is available, \
+         so it would likely be killed for running out of memory. Use a smaller quantization \
+         or context size, or pass --force-load to load it anyway",
+        crate::utils::format_file_size(*.required),
+        crate::utils::format_file_size(*.available)
+    )]
+    InsufficientMemory { path: PathBuf, required: u64, available: u64 },
+
+    /// The prompt doesn't fit in the model's context window
+    #[cfg_attr(not(feature = "inference"), allow(dead_code))]
+    #[error("Prompt of {prompt_tokens} tokens exceeds the context window of {context_size} tokens")]
+    ContextOverflow { prompt_tokens: usize, context_size: usize },
+
+    /// The prompt leaves too little of the context window for the completion
+    #[error(
+        "Prompt of {prompt_tokens} tokens plus {max_tokens} tokens to generate exceeds \
+         the context window of {context_size} tokens"
+    )]
+    PromptTooLong { prompt_tokens: usize, max_tokens: usize, context_size: usize },
+}
@@ -1,12 +1,29 @@
-use anyhow::Result;
+use super::ModelError;
+#[cfg(feature = "inference")]
 use llama_cpp_2::{
-    context::LlamaContext,
-    model::LlamaModel,
+    context::{params::LlamaContextParams, LlamaContext},
+    llama_batch::LlamaBatch,
+    model::{params::LlamaModelParams, LlamaModel},
     llama_backend::LlamaBackend,
+    sampling::LlamaSampler,
+    token::LlamaToken,
 };
-use std::path::Path;
+#[cfg(feature = "vision")]
+use llama_cpp_2::mtmd::{MtmdBitmap, MtmdContext, MtmdContextParams, MtmdInputText};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "inference")]
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, Sender};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+#[cfg(feature = "inference")]
+use std::time::Instant;
+#[cfg(feature = "inference")]
 use std::sync::Arc;
-use tracing::{debug, info, warn};
+use tracing::info;
+#[cfg(feature = "inference")]
+use tracing::{debug, warn};
 
 /// Context structure for maintaining conversation history
 #[derive(Debug, Clone)]
@@ -19,66 +36,536 @@ pub struct ChatContext {
     pub max_messages: usize,
     /// Maximum token context window size for the model
     pub context_size: usize,
+    /// Template to format this conversation with instead of the model's own
+    pub template: Option<PromptTemplate>,
+    /// Text the assistant's reply starts with, such as a persona's name
+    ///
+    /// It is appended to the prompt, so the model continues from it, and
+    /// generation stops if the model then opens a user turn.
+    pub response_prefix: Option<String>,
 }
 
 /// Chat message representation
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatMessage {
     /// Role of the message sender (user or assistant)
     pub role: ChatRole,
     /// Content of the message
     pub content: String,
+    /// Participant who sent the message, for conversations with several users or agents
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
 }
 
-/// Message role (user or assistant)
-#[derive(Debug, Clone, PartialEq)]
+/// Message role (user, assistant, or system for instructions given mid-conversation)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ChatRole {
     User,
     Assistant,
+    /// A system instruction at its place in the conversation; the one leading
+    /// the conversation is `ChatContext::system_prompt` instead
+    System,
 }
 
 /// Prompt template formats for different model types
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum PromptTemplate {
     /// ChatML format (OpenAI style)
+    #[serde(rename = "chatml")]
     ChatML,
     /// Alpaca instruction format
     Alpaca,
     /// Llama2 chat format
     Llama2,
+    /// Mistral instruct format (no system role, so it is folded into the first turn)
+    MistralInstruct,
+    /// Phi-3 chat format
+    Phi3,
+}
+
+impl PromptTemplate {
+    /// Guess the template from a model's file name, falling back to ChatML
+    ///
+    /// This is only a heuristic based on common naming conventions, so a
+    /// request can always override it.
+    pub fn guess_from_filename(name: &str) -> PromptTemplate {
+        let name = name.to_lowercase();
+        let words: Vec<&str> = name
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .collect();
+        let compact: String = words.concat();
+        let has_word = |prefix: &str| words.iter().any(|word| word.starts_with(prefix));
+        
+        if (has_word("mistral") || has_word("mixtral")) && has_word("instruct") {
+            PromptTemplate::MistralInstruct
+        } else if compact.contains("llama2") {
+            PromptTemplate::Llama2
+        } else if has_word("phi") {
+            PromptTemplate::Phi3
+        } else if has_word("alpaca") {
+            PromptTemplate::Alpaca
+        } else {
+            PromptTemplate::ChatML
+        }
+    }
+    
+    /// Special tokens that close an assistant turn in this format
+    ///
+    /// Finetunes often end turns with these instead of the model's EOS, so
+    /// generation stops on them too.
+    #[cfg_attr(not(feature = "inference"), allow(dead_code))]
+    pub fn end_of_turn_markers(&self) -> &'static [&'static str] {
+        match self {
+            PromptTemplate::ChatML => &["<|im_end|>"],
+            PromptTemplate::Phi3 => &["<|end|>"],
+            // These close turns with EOS itself
+            PromptTemplate::Alpaca | PromptTemplate::Llama2 | PromptTemplate::MistralInstruct => &[],
+        }
+    }
+    
+    /// Special tokens of this format that can leak into generated text, when
+    /// a model writes them out as plain text instead of emitting the token
+    pub fn artifacts(&self) -> &'static [&'static str] {
+        match self {
+            PromptTemplate::ChatML => &["<|im_end|>", "<|im_start|>", "<|endoftext|>"],
+            PromptTemplate::Alpaca => &["</s>", "<s>"],
+            PromptTemplate::Llama2 => &["</s>", "<s>", "[INST]", "[/INST]", "<<SYS>>", "<</SYS>>"],
+            PromptTemplate::MistralInstruct => &["</s>", "<s>", "[INST]", "[/INST]"],
+            PromptTemplate::Phi3 => &["<|end|>", "<|endoftext|>", "<|system|>", "<|user|>", "<|assistant|>"],
+        }
+    }
+    
+    /// Text that opens a user turn in this format
+    ///
+    /// A reply that starts with a prefix stops at these, so a model continuing
+    /// in character doesn't go on to write the user's next message too.
+    pub fn user_turn_markers(&self) -> &'static [&'static str] {
+        match self {
+            PromptTemplate::ChatML => &["<|im_start|>user"],
+            PromptTemplate::Alpaca => &["### Instruction:", "### Input:"],
+            PromptTemplate::Llama2 | PromptTemplate::MistralInstruct => &["[INST]"],
+            PromptTemplate::Phi3 => &["<|user|>"],
+        }
+    }
+    
+    /// Remove this format's `artifacts` from a reply and trim it
+    pub fn strip_artifacts(&self, text: &str) -> String {
+        let mut filter = ArtifactFilter::new(self);
+        let mut stripped = filter.push(text);
+        stripped.push_str(&filter.finish());
+        stripped
+    }
+}
+
+/// Strips a template's artifacts, and leading and trailing whitespace, from a
+/// reply as it streams in. Text that may be the start of an artifact or of
+/// trailing whitespace is held back until a later piece settles it.
+struct ArtifactFilter {
+    artifacts: &'static [&'static str],
+    pending: String,
+    started: bool,
+}
+
+impl ArtifactFilter {
+    fn new(template: &PromptTemplate) -> Self {
+        Self { artifacts: template.artifacts(), pending: String::new(), started: false }
+    }
+    
+    /// Add the next piece of the reply and return the text that is now final
+    fn push(&mut self, piece: &str) -> String {
+        self.pending.push_str(piece);
+        for artifact in self.artifacts {
+            if self.pending.contains(artifact) {
+                self.pending = self.pending.replace(artifact, "");
+            }
+        }
+        if !self.started {
+            self.pending = self.pending.trim_start().to_string();
+        }
+        
+        // Hold back the longest ending that begins an artifact, and any
+        // whitespace before it
+        let partial = partial_match_len(&self.pending, self.artifacts);
+        let settled = self.pending[..self.pending.len() - partial].trim_end().len();
+        let text: String = self.pending.drain(..settled).collect();
+        self.started |= !text.is_empty();
+        text
+    }
+    
+    /// The held back text, once the reply has ended
+    fn finish(&mut self) -> String {
+        let rest = std::mem::take(&mut self.pending);
+        let rest = if self.started { rest.trim_end() } else { rest.trim() };
+        rest.to_string()
+    }
+}
+
+/// Length of the longest ending of `text` that is the start of one of
+/// `patterns`, and so may turn into a match once more text arrives
+fn partial_match_len(text: &str, patterns: &[&str]) -> usize {
+    patterns.iter()
+        .flat_map(|pattern| pattern.char_indices().skip(1).map(|(end, _)| &pattern[..end]))
+        .filter(|prefix| text.ends_with(prefix))
+        .map(str::len)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Turns generated text into a chat reply: the reply starts with the
+/// conversation's response prefix and, unless raw output is on, is cleaned of
+/// template artifacts as it streams
+struct ReplyShaper {
+    template: PromptTemplate,
+    prefix: String,
+    filter: Option<ArtifactFilter>,
+}
+
+impl ReplyShaper {
+    fn new(model: &Model, context: &ChatContext) -> Self {
+        let template = model.template_for(context).clone();
+        let filter = (!model.raw_output).then(|| ArtifactFilter::new(&template));
+        let prefix = context.response_prefix.clone().unwrap_or_default();
+        Self { template, prefix, filter }
+    }
+    
+    /// Text generation stops at: the start of a user turn, which a model
+    /// writes after a prefilled reply more often than an end-of-turn token
+    fn stop_sequences(&self) -> &'static [&'static str] {
+        if self.prefix.is_empty() { &[] } else { self.template.user_turn_markers() }
+    }
+    
+    /// Emit the response prefix, before any generated text
+    fn start(&mut self, on_token: &mut dyn FnMut(&str)) {
+        let prefix = self.prefix.clone();
+        self.push(&prefix, on_token);
+    }
+    
+    /// Pass on the part of the next generated piece that is final
+    fn push(&mut self, piece: &str, on_token: &mut dyn FnMut(&str)) {
+        let text = match self.filter.as_mut() {
+            Some(filter) => filter.push(piece),
+            None => piece.to_string(),
+        };
+        if !text.is_empty() {
+            on_token(&text);
+        }
+    }
+    
+    /// Pass on the held back text and turn the completion's text into the reply
+    fn finish(&mut self, mut completion: Completion, on_token: &mut dyn FnMut(&str)) -> Completion {
+        if let Some(filter) = self.filter.as_mut() {
+            let rest = filter.finish();
+            if !rest.is_empty() {
+                on_token(&rest);
+            }
+        }
+        completion.text.insert_str(0, &self.prefix);
+        if self.filter.is_some() {
+            completion.text = self.template.strip_artifacts(&completion.text);
+        }
+        completion
+    }
 }
 
 /// Model wrapper for LLM inference using llama-cpp-2
 pub struct Model {
     /// Model path for reference
     model_path: std::path::PathBuf,
-    /// Loaded llama model (None if not loaded)
-    llama_model: Option<LlamaModel>,
-    /// Llama context for inference
+    /// Multimodal projector for image input (borrows `llama_model`, so must be dropped first)
+    #[cfg(feature = "vision")]
+    mtmd_context: Option<MtmdContext>,
+    /// Llama context for inference (borrows `llama_model`, so must be dropped first)
+    #[cfg(feature = "inference")]
     llama_context: Option<LlamaContext<'static>>,
-    /// Backend instance
-    backend: Arc<LlamaBackend>,
+    /// Loaded llama model (None if not loaded)
+    #[cfg(feature = "inference")]
+    llama_model: Option<Box<LlamaModel>>,
+    /// Backend instance (None for mock models)
+    #[cfg(feature = "inference")]
+    backend: Option<Arc<LlamaBackend>>,
+    /// Tokens whose keys and values are currently in the context's KV cache
+    #[cfg(feature = "inference")]
+    evaluated_tokens: Vec<LlamaToken>,
+    /// Replies being generated side by side in their own sequences of the
+    /// context (see `start_sequence`)
+    #[cfg(feature = "inference")]
+    sequences: Vec<RunningSequence>,
     /// Model parameters
     temperature: f32,
     max_tokens: usize,
     top_p: f32,
+    /// Keep only the `top_k` most likely tokens (0 = off)
+    top_k: usize,
+    /// Subtracted from a token's logit once per time it was already generated
+    frequency_penalty: f32,
+    /// Subtracted from a token's logit if it was generated at all
+    presence_penalty: f32,
+    repeat_window: Option<usize>,
+    /// Order the sampling steps apply in
+    sampler_chain: Vec<SamplerStep>,
+    /// GBNF grammar the output must match, starting from its `root` rule
+    grammar: Option<String>,
+    /// Return chat replies as generated, without stripping template artifacts
+    raw_output: bool,
+    ignore_eos: bool,
+    /// Token ids that end generation besides the model's end-of-generation tokens
+    stop_tokens: Vec<i32>,
+    /// Ids of the template's end-of-turn markers, replaced when the template changes
+    template_stop_tokens: Vec<i32>,
     /// Model state
     loaded: bool,
     /// Model configuration
     config: ModelConfig,
+    /// Whether the model was tuned for chat (see `is_chat_capable`)
+    chat_capable: bool,
+    /// Template used to format chat prompts
+    template: PromptTemplate,
+    /// Stops generation at the next token once cancelled
+    cancellation: Option<CancellationToken>,
+    /// Return canned responses instead of running llama.cpp
+    #[cfg(feature = "mock")]
+    mock: bool,
 }
 
+// SAFETY: llama.cpp contexts aren't thread-safe, but they can move between
+// threads. Every use of the context goes through `&mut Model`, so the server's
+// `Mutex<Model>` ensures only one thread touches it at a time.
+#[cfg(feature = "inference")]
+unsafe impl Send for Model {}
+
 /// Configuration for model loading and inference
 #[derive(Debug, Clone)]
 pub struct ModelConfig {
     /// Context window size
     pub context_size: usize,
+    /// Replace `context_size` on load with one sized for the model and the
+    /// free memory; cleared when the user picks a size
+    pub auto_context_size: bool,
     /// Number of GPU layers to offload (0 = CPU only)
     pub n_gpu_layers: i32,
-    /// Number of threads for CPU inference
+    /// Number of threads for CPU inference (None = chosen from the model size on load)
+    pub n_threads: Option<usize>,
+    /// Prompt tokens submitted to llama.cpp per decode call
+    ///
+    /// Larger batches evaluate long prompts faster but need a bigger compute
+    /// buffer, so lower this if loading runs out of memory.
+    pub batch_size: usize,
+    /// Stop when the last N generated tokens form a repeating cycle (None = off)
+    pub anti_repeat_window: Option<usize>,
+    /// Sampling temperature models start with
+    pub temperature: f32,
+    /// Log the top candidate tokens and the sampled one at every step (DEBUG level)
+    pub debug_sampling: bool,
+    /// Extra token ids that end generation, on top of the template's end-of-turn
+    /// tokens (applied by `load_model`)
+    pub stop_tokens: Vec<i32>,
+    /// Load even when the model looks too big for the available memory
+    pub force_load: bool,
+    /// Leave llama.cpp printing its diagnostics to stderr instead of routing
+    /// them into `tracing`
+    pub llama_verbose: bool,
+    /// Multimodal projector (mmproj) for image input, loaded with the model
+    /// when rustllm is built with the `vision` feature
+    #[cfg_attr(not(feature = "vision"), allow(dead_code))]
+    pub mmproj_path: Option<PathBuf>,
+    /// Sequences the context holds, each with a window of `context_size`, so
+    /// up to this many replies can be generated at once (see `start_sequence`)
+    pub parallel: usize,
+}
+
+/// Approximate memory a loaded model uses (see `Model::memory_footprint`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct MemoryFootprint {
+    pub ram_bytes: u64,
+    pub vram_bytes: u64,
+}
+
+/// Why generation stopped
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FinishReason {
+    /// The model emitted an end-of-generation token
+    #[cfg_attr(not(feature = "inference"), allow(dead_code))]
+    Stop,
+    /// `max_tokens` or the end of the context window was reached
+    #[cfg_attr(not(feature = "inference"), allow(dead_code))]
+    Length,
+    /// The output got stuck in a loop (see `ModelConfig::anti_repeat_window`)
+    #[cfg_attr(not(feature = "inference"), allow(dead_code))]
+    Repetition,
+    /// The model's cancellation token was cancelled (see `Model::set_cancellation`)
+    #[cfg_attr(not(feature = "inference"), allow(dead_code))]
+    Cancelled,
+}
+
+/// Generated text and why generation ended
+#[derive(Debug, Clone)]
+pub struct Completion {
+    pub text: String,
+    pub finish_reason: FinishReason,
+    /// Tokens in the prompt, including any reused from the KV cache
+    pub prompt_tokens: usize,
+    /// Tokens generated
+    pub completion_tokens: usize,
+    /// Time spent decoding the part of the prompt that wasn't cached
+    pub prompt_eval_time: Duration,
+    /// Time spent generating tokens
+    pub generation_time: Duration,
+}
+
+/// The settings a reply is sampled with, taken when its generation is set up
+/// so it keeps them while later requests change the model's
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "inference"), allow(dead_code))]
+struct SamplingSettings {
+    temperature: f32,
+    top_p: f32,
+    top_k: usize,
+    frequency_penalty: f32,
+    presence_penalty: f32,
+    sampler_chain: Vec<SamplerStep>,
+    grammar: Option<String>,
+    max_tokens: usize,
+    repeat_window: Option<usize>,
+    ignore_eos: bool,
+}
+
+/// A generation waiting for a sequence of the model's context (see
+/// `Model::chat_sequence` and `Model::start_sequence`)
+pub struct SequenceJob {
+    prompt: String,
+    seed: u32,
+    #[cfg_attr(not(feature = "inference"), allow(dead_code))]
+    settings: SamplingSettings,
+    #[cfg_attr(not(feature = "inference"), allow(dead_code))]
+    cancellation: Option<CancellationToken>,
+    output: SequenceOutput,
+}
+
+/// What a sequence sends back to the request that queued it
+pub enum SequenceEvent {
+    /// The next piece of the reply
+    Token(String),
+    /// The whole reply, or why it failed; nothing follows
+    Done(Result<Completion, ModelError>),
+}
+
+/// Where a sequence's reply goes: shaped into a chat reply if it is one, then
+/// sent to the request waiting for it. Sends only fail once that request has
+/// gone away, which its cancellation token takes care of.
+struct SequenceOutput {
+    shaper: Option<ReplyShaper>,
+    events: Sender<SequenceEvent>,
+}
+
+impl SequenceOutput {
+    /// Send the response prefix of a chat reply
+    fn start(&mut self) {
+        let Self { shaper, events } = self;
+        if let Some(shaper) = shaper {
+            shaper.start(&mut |text| send_token(events, text));
+        }
+    }
+    
+    #[cfg_attr(not(feature = "inference"), allow(dead_code))]
+    fn token(&mut self, piece: &str) {
+        let Self { shaper, events } = self;
+        match shaper {
+            Some(shaper) => shaper.push(piece, &mut |text| send_token(events, text)),
+            None => send_token(events, piece),
+        }
+    }
+    
+    fn done(self, result: Result<Completion, ModelError>) {
+        let Self { shaper, events } = self;
+        let result = result.map(|completion| match shaper {
+            Some(mut shaper) => shaper.finish(completion, &mut |text| send_token(&events, text)),
+            None => completion,
+        });
+        let _ = events.send(SequenceEvent::Done(result));
+    }
+}
+
+fn send_token(events: &Sender<SequenceEvent>, text: &str) {
+    let _ = events.send(SequenceEvent::Token(text.to_string()));
+}
+
+/// A step of the sampler chain; steps apply in the order they are listed,
+/// before the final random (or greedy) pick
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SamplerStep {
+    /// Frequency and presence penalties (skipped while both are 0)
+    Penalties,
+    /// Keep the `top_k` most likely tokens (skipped while `top_k` is 0)
+    TopK,
+    /// Nucleus sampling with `top_p`
+    TopP,
+    /// Temperature scaling
+    #[serde(rename = "temp")]
+    Temperature,
+}
+
+impl SamplerStep {
+    /// Order the steps run in unless a chain is given
+    pub const DEFAULT_CHAIN: [SamplerStep; 4] =
+        [SamplerStep::Penalties, SamplerStep::TopK, SamplerStep::TopP, SamplerStep::Temperature];
+    
+    /// Name of the step in a chain spec
+    pub fn name(self) -> &'static str {
+        match self {
+            SamplerStep::Penalties => "penalties",
+            SamplerStep::TopK => "top_k",
+            SamplerStep::TopP => "top_p",
+            SamplerStep::Temperature => "temp",
+        }
+    }
+    
+    /// Parse an ordered list of step names such as `["top_p", "temp"]`
+    ///
+    /// Fails on unknown or repeated names. Steps left out are skipped.
+    pub fn parse_chain<S: AsRef<str>>(names: &[S]) -> Result<Vec<SamplerStep>, String> {
+        let mut chain = Vec::new();
+        for name in names {
+            let name = name.as_ref().trim();
+            let step = Self::DEFAULT_CHAIN
+                .into_iter()
+                .find(|step| step.name() == name)
+                .ok_or_else(|| {
+                    let known: Vec<&str> = Self::DEFAULT_CHAIN.iter().map(|step| step.name()).collect();
+                    format!("Unknown sampler step {:?}, expected one of: {}", name, known.join(", "))
+                })?;
+            if chain.contains(&step) {
+                return Err(format!("Sampler step {:?} is listed twice", name));
+            }
+            chain.push(step);
+        }
+        Ok(chain)
+    }
+}
+
+/// Snapshot of the parameters a model currently generates with
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelParams {
+    pub temperature: f32,
+    pub top_p: f32,
+    /// 0 when top-k sampling is off
+    pub top_k: usize,
+    pub max_tokens: usize,
+    pub frequency_penalty: f32,
+    pub presence_penalty: f32,
+    pub context_size: usize,
+    /// None when llama.cpp picks the thread count
     pub n_threads: Option<usize>,
-    /// Batch size for processing
+    pub n_gpu_layers: i32,
     pub batch_size: usize,
+    /// Template chat prompts are formatted with
+    pub template: PromptTemplate,
+    /// Order the sampling steps apply in
+    pub sampler_chain: Vec<SamplerStep>,
 }
 
 impl Default for ChatContext {
@@ -88,6 +575,8 @@ impl Default for ChatContext {
             messages: Vec::new(),
             max_messages: 20,
             context_size: 4096,
+            template: None,
+            response_prefix: None,
         }
     }
 }
@@ -96,9 +585,18 @@ impl Default for ModelConfig {
     fn default() -> Self {
         Self {
             context_size: 4096,
+            auto_context_size: true,
             n_gpu_layers: 0, // CPU only by default
-            n_threads: None, // Let the system decide
-            batch_size: 1,  // Single request at a time
+            n_threads: None, // Chosen from the model size on load
+            batch_size: 512,
+            anti_repeat_window: None,
+            temperature: 0.7,
+            debug_sampling: false,
+            stop_tokens: Vec::new(),
+            force_load: false,
+            llama_verbose: false,
+            mmproj_path: None,
+            parallel: 1,
         }
     }
 }
@@ -109,6 +607,7 @@ impl ChatMessage {
         Self {
             role: ChatRole::User,
             content: content.into(),
+            name: None,
         }
     }
 
@@ -117,6 +616,23 @@ impl ChatMessage {
         Self {
             role: ChatRole::Assistant,
             content: content.into(),
+            name: None,
+        }
+    }
+
+    /// Create a new user message from a named participant
+    pub fn user_named(name: impl Into<String>, content: impl Into<String>) -> Self {
+        Self {
+            name: Some(name.into()),
+            ..Self::user(content)
+        }
+    }
+
+    /// Create a new assistant message from a named participant
+    pub fn assistant_named(name: impl Into<String>, content: impl Into<String>) -> Self {
+        Self {
+            name: Some(name.into()),
+            ..Self::assistant(content)
         }
     }
 }
@@ -129,19 +645,35 @@ impl ChatContext {
             messages: Vec::new(),
             max_messages: 20,
             context_size: 4096,
+            template: None,
+            response_prefix: None,
         }
     }
 
     /// Add a message to the context
     pub fn add_message(&mut self, message: ChatMessage) {
         self.messages.push(message);
-        
-        // Trim older messages if we exceed max_messages
+        self.trim_messages();
+    }
+    
+    /// Change the message limit, dropping the oldest messages if needed
+    pub fn set_max_messages(&mut self, max_messages: usize) {
+        self.max_messages = max_messages;
+        self.trim_messages();
+    }
+    
+    /// Trim older messages if we exceed max_messages
+    fn trim_messages(&mut self) {
         if self.messages.len() > self.max_messages {
             let excess = self.messages.len() - self.max_messages;
             self.messages.drain(0..excess);
         }
     }
+    
+    /// Rough token count of the formatted prompt (~4 characters per token)
+    pub fn estimated_tokens(&self) -> usize {
+        self.format_prompt().len() / 4
+    }
 
     /// Format the entire context as a string for the model
     pub fn format_prompt(&self) -> String {
@@ -150,25 +682,38 @@ impl ChatContext {
     
     /// Format prompt with specific template
     pub fn format_prompt_with_template(&self, template: &PromptTemplate) -> String {
-        match template {
+        let mut prompt = match template {
             PromptTemplate::ChatML => self.format_chatml(),
             PromptTemplate::Alpaca => self.format_alpaca(),
             PromptTemplate::Llama2 => self.format_llama2(),
+            PromptTemplate::MistralInstruct => self.format_mistral_instruct(),
+            PromptTemplate::Phi3 => self.format_phi3(),
+        };
+        if let Some(prefix) = &self.response_prefix {
+            prompt.push_str(prefix);
         }
+        prompt
     }
     
     fn format_chatml(&self) -> String {
         let mut prompt = format!("<|im_start|>system\n{}<|im_end|>\n", self.system_prompt);
         
         for message in &self.messages {
-            match message.role {
-                ChatRole::User => {
-                    prompt.push_str(&format!("<|im_start|>user\n{}<|im_end|>\n", message.content));
-                }
-                ChatRole::Assistant => {
-                    prompt.push_str(&format!("<|im_start|>assistant\n{}<|im_end|>\n", message.content));
-                }
+            let role = match message.role {
+                ChatRole::User => "user",
+                ChatRole::Assistant => "assistant",
+                ChatRole::System => "system",
+            };
+            // ChatML attributes a message to a participant in the role line, so
+            // the name mustn't break out of it
+            let name = message.name.as_deref().map(|name| {
+                name.split_whitespace().collect::<Vec<_>>().join("_")
+            });
+            match name.filter(|name| !name.is_empty()) {
+                Some(name) => prompt.push_str(&format!("<|im_start|>{} name={}\n", role, name)),
+                None => prompt.push_str(&format!("<|im_start|>{}\n", role)),
             }
+            prompt.push_str(&format!("{}<|im_end|>\n", message.content));
         }
         
         prompt.push_str("<|im_start|>assistant\n");
@@ -178,9 +723,17 @@ impl ChatContext {
     fn format_alpaca(&self) -> String {
         let mut prompt = format!("Below is an instruction that describes a task. Write a response that appropriately completes the request.\n\n### Instruction:\n{}\n\n", self.system_prompt);
         
-        if let Some(last_message) = self.messages.last() {
-            if last_message.role == ChatRole::User {
-                prompt.push_str(&format!("### Input:\n{}\n\n", last_message.content));
+        for message in &self.messages {
+            match message.role {
+                ChatRole::User => {
+                    prompt.push_str(&format!("### Input:\n{}\n\n", message.content));
+                }
+                ChatRole::Assistant => {
+                    prompt.push_str(&format!("### Response:\n{}\n\n", message.content));
+                }
+                ChatRole::System => {
+                    prompt.push_str(&format!("### Instruction:\n{}\n\n", message.content));
+                }
             }
         }
         
@@ -189,44 +742,121 @@ impl ChatContext {
     }
     
     fn format_llama2(&self) -> String {
-        let mut prompt = format!("[INST] <<SYS>>\n{}\n<</SYS>>\n\n", self.system_prompt);
+        // The system block goes inside the first [INST] only
+        let mut system_block = Some(format!("<<SYS>>\n{}\n<</SYS>>\n\n", self.system_prompt));
+        let mut prompt = String::new();
+        
+        for message in &self.messages {
+            match message.role {
+                ChatRole::User => {
+                    let system = system_block.take().unwrap_or_default();
+                    if !prompt.is_empty() {
+                        prompt.push_str("<s>");
+                    }
+                    prompt.push_str(&format!("[INST] {}{} [/INST]", system, message.content));
+                }
+                ChatRole::Assistant => {
+                    // An assistant turn with no preceding user turn still needs the system block
+                    if let Some(system) = system_block.take() {
+                        prompt.push_str(&format!("[INST] {} [/INST]", system.trim_end()));
+                    }
+                    prompt.push_str(&format!(" {} </s>", message.content));
+                }
+                ChatRole::System => {
+                    // Llama 2 has no system turns, so the instruction goes into the next [INST]
+                    let block = format!("<<SYS>>\n{}\n<</SYS>>\n\n", message.content);
+                    system_block.get_or_insert_with(String::new).push_str(&block);
+                }
+            }
+        }
+        
+        // Open a new instruction for the reply unless the last turn is an unanswered user turn
+        let awaiting_reply = matches!(self.messages.last(), Some(m) if m.role == ChatRole::User);
+        if !awaiting_reply {
+            let system = system_block.take().unwrap_or_default();
+            if !prompt.is_empty() {
+                prompt.push_str("<s>");
+            }
+            prompt.push_str(&format!("[INST] {} [/INST]", system.trim_end()));
+        }
+        
+        prompt
+    }
+    
+    fn format_mistral_instruct(&self) -> String {
+        // Mistral has no system role, so the system prompt leads the first instruction
+        let mut system = Some(format!("{}\n\n", self.system_prompt));
+        let mut prompt = String::from("<s>");
         
         for message in &self.messages {
             match message.role {
                 ChatRole::User => {
-                    prompt.push_str(&format!("{} [/INST]", message.content));
+                    let system = system.take().unwrap_or_default();
+                    prompt.push_str(&format!("[INST] {}{} [/INST]", system, message.content));
                 }
                 ChatRole::Assistant => {
-                    prompt.push_str(&format!(" {} [INST] ", message.content));
+                    prompt.push_str(&format!(" {}</s>", message.content));
+                }
+                ChatRole::System => {
+                    system.get_or_insert_with(String::new).push_str(&format!("{}\n\n", message.content));
                 }
             }
         }
         
-        if !prompt.ends_with("[/INST]") {
-            prompt.push_str(" [/INST]");
+        let awaiting_reply = matches!(self.messages.last(), Some(m) if m.role == ChatRole::User);
+        if !awaiting_reply {
+            let system = system.take().unwrap_or_default();
+            prompt.push_str(&format!("[INST] {} [/INST]", system.trim_end()));
+        }
+        
+        prompt
+    }
+    
+    fn format_phi3(&self) -> String {
+        let mut prompt = format!("<|system|>\n{}<|end|>\n", self.system_prompt);
+        
+        for message in &self.messages {
+            let role = match message.role {
+                ChatRole::User => "user",
+                ChatRole::Assistant => "assistant",
+                ChatRole::System => "system",
+            };
+            prompt.push_str(&format!("<|{}|>\n{}<|end|>\n", role, message.content));
         }
         
+        prompt.push_str("<|assistant|>\n");
         prompt
     }
 }
 
 impl Model {
-    /// Load a model from the given path
-    pub fn load(model_path: &Path) -> Result<Self> {
-        Self::load_with_config(model_path, ModelConfig::default())
-    }
-    
     /// Load a model with custom configuration
-    pub fn load_with_config(model_path: &Path, config: ModelConfig) -> Result<Self> {
+    ///
+    /// `config.context_size` is clamped to the model's trained context length.
+    /// With `auto_context_size` set, or `n_threads` unset, they are first
+    /// chosen from the model's parameter count and the available memory.
+    #[cfg(feature = "inference")]
+    pub fn load_with_config(model_path: &Path, mut config: ModelConfig) -> Result<Self, ModelError> {
         info!("Loading model from {:?} with config: {:?}", model_path, config);
         
+        // --parallel is checked by clap, but a registry entry's setting is not
+        if config.parallel > MAX_PARALLEL {
+            warn!("{} parallel sequences exceed llama.cpp's maximum of {}, clamping", config.parallel, MAX_PARALLEL);
+        }
+        config.parallel = config.parallel.clamp(1, MAX_PARALLEL);
+        
+        if !config.llama_verbose {
+            route_backend_logs();
+        }
+        
         // Initialize backend
-        let backend = LlamaBackend::init()?;
+        let backend = LlamaBackend::init()
+            .map_err(|e| ModelError::LoadFailed(format!("Failed to initialize backend: {}", e)))?;
         let backend = Arc::new(backend);
         
         // Validate that the file exists and is a GGUF file
         if !model_path.exists() {
-            anyhow::bail!("Model file does not exist: {:?}", model_path);
+            return Err(ModelError::LoadFailed(format!("Model file does not exist: {:?}", model_path)));
         }
         
         if !model_path.extension()
@@ -245,99 +875,1825 @@ impl Model {
             warn!("Model file seems very small ({:.2} MB), this might not be a valid model", file_size_mb);
         }
         
+        // llama.cpp's errors for these are opaque, so catch them first
+        check_gguf_file(model_path, metadata.len())?;
+        
+        // Running out of memory gets the process killed without a message
+        check_available_memory(model_path, metadata.len(), &config)?;
+        
         // Load the model using llama-cpp-2 - simplified approach
-        let llama_model = LlamaModel::load_from_file(&backend, model_path, &Default::default())
-            .map_err(|e| anyhow::anyhow!("Failed to load GGUF model: {}", e))?;
+        let model_params = LlamaModelParams::default().with_n_gpu_layers(config.n_gpu_layers.max(0) as u32);
+        let llama_model = LlamaModel::load_from_file(&backend, model_path, &model_params)
+            .map_err(|e| ModelError::LoadFailed(format!("Failed to load GGUF model: {}", e)))?;
         
         info!("Model loaded successfully");
         
-        // Create context for inference - simplified approach
-        let llama_context = llama_model.new_context(&backend, Default::default())
-            .map_err(|e| anyhow::anyhow!("Failed to create context: {}", e))?;
+        let chat_capable = detect_chat_capable(&llama_model, model_path);
+        if !chat_capable {
+            warn!("Model {:?} does not look chat-capable, only raw completions will work", model_path);
+        }
+        
+        // Don't run past the context length the model was trained with
+        let n_ctx_train = llama_model.n_ctx_train() as usize;
+        let n_params = llama_model.n_params();
+        let available_memory = crate::utils::available_memory_bytes();
+        if config.auto_context_size {
+            // The memory picked for one window is shared by all the sequences
+            config.context_size = (auto_context_size(n_params, metadata.len(), available_memory) / config.parallel.max(1))
+                .max(AUTO_CONTEXT_MIN);
+            if n_ctx_train > 0 {
+                config.context_size = config.context_size.min(n_ctx_train);
+            }
+            info!(
+                "Chose context size {} for {:.1}B parameters and {} available memory",
+                config.context_size,
+                n_params as f64 / 1e9,
+                available_memory.map_or_else(|| "unknown".to_string(), crate::utils::format_file_size),
+            );
+        }
+        if config.n_threads.is_none() {
+            let threads = auto_thread_count(n_params);
+            info!("Chose {} threads for {:.1}B parameters", threads, n_params as f64 / 1e9);
+            config.n_threads = Some(threads);
+        }
+        if n_ctx_train > 0 && config.context_size > n_ctx_train {
+            warn!(
+                "Requested context size {} exceeds the model's trained maximum of {}, clamping",
+                config.context_size, n_ctx_train
+            );
+            config.context_size = n_ctx_train;
+        }
+        
+        // llama.cpp splits the context evenly between sequences
+        let n_ctx = config.context_size.checked_mul(config.parallel)
+            .and_then(|n_ctx| u32::try_from(n_ctx).ok())
+            .ok_or_else(|| ModelError::LoadFailed(format!(
+                "A context of {} tokens for each of {} sequences is too large",
+                config.context_size, config.parallel
+            )))?;
+        let mut context_params = LlamaContextParams::default()
+            .with_n_ctx(NonZeroU32::new(n_ctx))
+            .with_n_batch(config.batch_size as u32)
+            .with_n_seq_max(config.parallel as u32);
+        if let Some(threads) = config.n_threads {
+            context_params = context_params
+                .with_n_threads(threads as i32)
+                .with_n_threads_batch(threads as i32);
+        }
+        
+        // The context borrows the model, so the model is boxed to keep its address
+        // stable for as long as the context lives. `llama_context` is declared before
+        // `llama_model` so it is dropped first, and `unload` clears it first too.
+        let llama_model = Box::new(llama_model);
+        let model_ref: &'static LlamaModel = unsafe { &*(llama_model.as_ref() as *const LlamaModel) };
+        let llama_context = model_ref.new_context(&backend, context_params)
+            .map_err(|e| ModelError::LoadFailed(format!("Failed to create context: {}", e)))?;
+        
+        info!(
+            "Context created successfully (n_ctx = {}, n_batch = {}, sequences = {})",
+            config.context_size * config.parallel, config.batch_size, config.parallel
+        );
         
-        info!("Context created successfully");
+        #[cfg(feature = "vision")]
+        let mtmd_context = match &config.mmproj_path {
+            Some(mmproj_path) => Some(load_projector(mmproj_path, model_ref, &config)?),
+            None => None,
+        };
         
-        Ok(Self {
+        let mut model = Self {
             model_path: model_path.to_path_buf(),
-            llama_model: Some(llama_model),
+            #[cfg(feature = "vision")]
+            mtmd_context,
             llama_context: Some(llama_context),
-            backend,
-            temperature: 0.7,
-            max_tokens: 1024,
-            top_p: 0.95,
+            llama_model: Some(llama_model),
+            backend: Some(backend),
+            evaluated_tokens: Vec::new(),
+            sequences: Vec::new(),
+            temperature: config.temperature,
+            max_tokens: DEFAULT_MAX_TOKENS,
+            top_p: DEFAULT_TOP_P,
+            top_k: 0,
+            frequency_penalty: 0.0,
+            presence_penalty: 0.0,
+            repeat_window: config.anti_repeat_window,
+            sampler_chain: SamplerStep::DEFAULT_CHAIN.to_vec(),
+            grammar: None,
+            raw_output: false,
+            ignore_eos: false,
+            stop_tokens: Vec::new(),
+            template_stop_tokens: Vec::new(),
             loaded: true,
             config,
-        })
+            chat_capable,
+            template: PromptTemplate::ChatML,
+            cancellation: None,
+            #[cfg(feature = "mock")]
+            mock: false,
+        };
+        model.template_stop_tokens = model.lookup_stop_tokens(&PromptTemplate::ChatML);
+        Ok(model)
     }
     
-    /// Generate a response for the given context (simplified version)
-    pub fn generate(&mut self, context: &ChatContext) -> Result<String> {
+    /// Loading always fails when llama.cpp isn't compiled in
+    #[cfg(not(feature = "inference"))]
+    pub fn load_with_config(_model_path: &Path, _config: ModelConfig) -> Result<Self, ModelError> {
+        Err(ModelError::InferenceDisabled)
+    }
+    
+    /// Create a mock model that never touches llama.cpp
+    ///
+    /// `generate` returns deterministic text describing the prompt, so tests can
+    /// exercise routing, trimming and error handling without a GGUF file.
+    #[cfg(feature = "mock")]
+    pub fn load_mock(model_path: &Path, config: ModelConfig) -> Self {
+        info!("Loading mock model for {:?}", model_path);
+        Self {
+            model_path: model_path.to_path_buf(),
+            #[cfg(feature = "vision")]
+            mtmd_context: None,
+            #[cfg(feature = "inference")]
+            llama_context: None,
+            #[cfg(feature = "inference")]
+            llama_model: None,
+            #[cfg(feature = "inference")]
+            backend: None,
+            #[cfg(feature = "inference")]
+            evaluated_tokens: Vec::new(),
+            #[cfg(feature = "inference")]
+            sequences: Vec::new(),
+            temperature: config.temperature,
+            max_tokens: DEFAULT_MAX_TOKENS,
+            top_p: DEFAULT_TOP_P,
+            top_k: 0,
+            frequency_penalty: 0.0,
+            presence_penalty: 0.0,
+            repeat_window: config.anti_repeat_window,
+            sampler_chain: SamplerStep::DEFAULT_CHAIN.to_vec(),
+            grammar: None,
+            raw_output: false,
+            ignore_eos: false,
+            stop_tokens: Vec::new(),
+            template_stop_tokens: Vec::new(),
+            loaded: true,
+            config,
+            chat_capable: true,
+            template: PromptTemplate::ChatML,
+            cancellation: None,
+            mock: true,
+        }
+    }
+    
+    /// Canned response for mock models, using the same rough estimate as `ChatContext`
+    #[cfg(feature = "mock")]
+    fn complete_mock(&self, prompt: &str, seed: u32, on_token: &mut dyn FnMut(&str)) -> Result<Completion, ModelError> {
         if !self.loaded {
-            anyhow::bail!("Model is not loaded");
+            return Err(ModelError::LoadFailed("Model is not loaded".to_string()));
         }
         
-        let prompt = context.format_prompt();
-        debug!("Using prompt: {}", prompt);
-        debug!("Model parameters: temp={}, max_tokens={}, top_p={}", 
-               self.temperature, self.max_tokens, self.top_p);
-        
-        // For now, return a simple response indicating the model is loaded
-        let response = format!("Model response to: {}", prompt);
-        info!("Generated response: {}", response);
+        let prompt_tokens = prompt.len() / 4;
+        if prompt_tokens >= self.config.context_size {
+            return Err(ModelError::ContextOverflow {
+                prompt_tokens,
+                context_size: self.config.context_size,
+            });
+        }
         
-        Ok(response)
+        let response = format!("Mock response to a prompt of {} tokens (seed {})", prompt_tokens, seed);
+        let mut text: String = response.chars().take(self.max_tokens * 4).collect();
+        let mut finish_reason = if text.len() < response.len() {
+            FinishReason::Length
+        } else {
+            FinishReason::Stop
+        };
+        let mut streamed = 0;
+        for word in text.split_inclusive(' ') {
+            if self.is_cancelled() {
+                finish_reason = FinishReason::Cancelled;
+                break;
+            }
+            on_token(word);
+            streamed += word.len();
+        }
+        text.truncate(streamed);
+        Ok(Completion {
+            completion_tokens: text.len() / 4,
+            text,
+            finish_reason,
+            prompt_tokens,
+            prompt_eval_time: Duration::ZERO,
+            generation_time: Duration::ZERO,
+        })
     }
     
-    /// Update temperature (0.0 - 1.0)
-    pub fn set_temperature(&mut self, temperature: f32) {
-        self.temperature = temperature;
+    /// Generate a response, passing each piece of text to `on_token` as soon as
+    /// it is produced
+    pub fn generate_stream(
+        &mut self,
+        context: &ChatContext,
+        mut on_token: impl FnMut(&str),
+    ) -> Result<Completion, ModelError> {
+        let prompt = self.format_prompt(context);
+        self.generate_reply(context, &prompt, &[], rand::random(), &mut on_token)
     }
     
-    /// Update top_p (0.0 - 1.0)
-    pub fn set_top_p(&mut self, top_p: f32) {
-        self.top_p = top_p;
+    /// Generate a response to a conversation with images in it, streaming it
+    /// like `generate_stream`
+    ///
+    /// The messages hold an `IMAGE_MARKER` where each of `images` (PNG or JPEG
+    /// bytes) goes, in order. Each image is run through the model's multimodal
+    /// projector and its embeddings are decoded in place of the marker. Without
+    /// images this is `generate_stream` sampling with `seed`.
+    pub fn generate_with_images(
+        &mut self,
+        context: &ChatContext,
+        images: &[Vec<u8>],
+        seed: u32,
+        mut on_token: impl FnMut(&str),
+    ) -> Result<Completion, ModelError> {
+        let prompt = self.format_prompt(context);
+        self.generate_reply(context, &prompt, images, seed, &mut on_token)
     }
     
-    /// Update max_new_tokens
-    pub fn set_max_tokens(&mut self, max_tokens: usize) {
-        self.max_tokens = max_tokens;
+    /// Generate one reply to a conversation from its formatted prompt
+    ///
+    /// The reply includes the conversation's response prefix and, unless raw
+    /// output is on, is cleaned of template artifacts as it streams.
+    fn generate_reply(
+        &mut self,
+        context: &ChatContext,
+        prompt: &str,
+        images: &[Vec<u8>],
+        seed: u32,
+        on_token: &mut dyn FnMut(&str),
+    ) -> Result<Completion, ModelError> {
+        let mut shaper = ReplyShaper::new(self, context);
+        let stop_sequences = shaper.stop_sequences();
+        shaper.start(on_token);
+        let completion = {
+            let mut emit = |piece: &str| shaper.push(piece, on_token);
+            if images.is_empty() {
+                self.complete_seeded(prompt, seed, stop_sequences, &mut emit)?
+            } else {
+                self.complete_with_images(prompt, images, seed, stop_sequences, &mut emit)?
+            }
+        };
+        Ok(shaper.finish(completion, on_token))
     }
     
-    /// Get current temperature
-    pub fn get_temperature(&self) -> f32 {
-        self.temperature
+    /// Return chat replies as generated instead of stripping the template's
+    /// artifacts and surrounding whitespace from them
+    pub fn set_raw_output(&mut self, raw_output: bool) {
+        self.raw_output = raw_output;
     }
     
-    /// Get current max_tokens
-    pub fn get_max_tokens(&self) -> usize {
-        self.max_tokens
+    /// Continue a raw prompt without applying any chat template
+    pub fn complete(&mut self, prompt: &str) -> Result<Completion, ModelError> {
+        self.complete_seeded(prompt, rand::random(), &[], &mut |_| {})
     }
     
-    /// Get current top_p
-    pub fn get_top_p(&self) -> f32 {
-        self.top_p
+    /// Forget the evaluated prompt so the next generation decodes it from scratch
+    pub fn clear_cache(&mut self) {
+        #[cfg(feature = "inference")]
+        self.evaluated_tokens.clear();
     }
     
-    /// Check if model is loaded
-    pub fn is_loaded(&self) -> bool {
-        self.loaded
+    /// Generate `n` independent responses for the same context
+    ///
+    /// Each run drops the previous run's output from the KV cache and samples
+    /// with `seed + i`, so the same seed reproduces the same set of responses.
+    pub fn generate_n(&mut self, context: &ChatContext, n: usize, seed: u32) -> Result<Vec<Completion>, ModelError> {
+        let prompt = self.format_prompt(context);
+        (0..n)
+            .map(|i| self.generate_reply(context, &prompt, &[], seed.wrapping_add(i as u32), &mut |_| {}))
+            .collect()
     }
     
-    /// Get model configuration
-    pub fn get_config(&self) -> &ModelConfig {
-        &self.config
+    /// Sequences the context was created with (see `ModelConfig::parallel`)
+    pub fn parallel(&self) -> usize {
+        self.config.parallel.max(1)
     }
     
-    /// Generate response without streaming (for API interface)
-    pub fn generate_sync(&mut self, context: &ChatContext) -> Result<String> {
-        self.generate(context)
+    /// A job generating a reply to `context` sampled with `seed` and the
+    /// model's current settings, for `start_sequence`
+    ///
+    /// The reply streams into the returned receiver like `generate_stream`
+    /// passes it to its callback, and ends with a `SequenceEvent::Done`.
+    pub fn chat_sequence(&self, context: &ChatContext, seed: u32) -> (SequenceJob, Receiver<SequenceEvent>) {
+        let shaper = ReplyShaper::new(self, context);
+        self.sequence_job(self.format_prompt(context), seed, Some(shaper))
     }
     
-    /// Unload the model to free memory
-    pub fn unload(&mut self) {
-        info!("Unloading model: {:?}", self.model_path);
-        self.llama_context = None;
-        self.llama_model = None;
-        self.loaded = false;
+    /// A job continuing a raw prompt like `complete`, for `start_sequence`
+    pub fn completion_sequence(&self, prompt: &str, seed: u32) -> (SequenceJob, Receiver<SequenceEvent>) {
+        self.sequence_job(prompt.to_string(), seed, None)
+    }
+    
+    fn sequence_job(&self, prompt: String, seed: u32, shaper: Option<ReplyShaper>) -> (SequenceJob, Receiver<SequenceEvent>) {
+        let (events, receiver) = std::sync::mpsc::channel();
+        let job = SequenceJob {
+            prompt,
+            seed,
+            settings: self.sampling_settings(),
+            cancellation: self.cancellation.clone(),
+            output: SequenceOutput { shaper, events },
+        };
+        (job, receiver)
+    }
+    
+    /// Replies being generated in sequences of the context
+    pub fn active_sequences(&self) -> usize {
+        #[cfg(feature = "inference")]
+        return self.sequences.len();
+        #[cfg(not(feature = "inference"))]
+        0
+    }
+    
+    /// Decode a job's prompt into a free sequence of the context and sample
+    /// its first token; each `step_sequences` then adds a token to it, along
+    /// with every other running sequence, until the reply ends
+    ///
+    /// The result goes to the job's receiver, failures included. A job started
+    /// while all `parallel()` sequences are taken fails.
+    pub fn start_sequence(&mut self, job: SequenceJob) {
+        let SequenceJob { prompt, seed, settings, cancellation, mut output } = job;
+        output.start();
+        
+        #[cfg(feature = "mock")]
+        if self.mock {
+            let result = self.complete_mock(&prompt, seed, &mut |piece| output.token(piece));
+            output.done(result);
+            return;
+        }
+        
+        #[cfg(feature = "inference")]
+        match self.decode_sequence_prompt(&prompt, seed, &settings, cancellation, &mut output) {
+            Ok((seq_id, sequence, prompt_eval_time, Some(next_token))) => {
+                self.sequences.push(RunningSequence { seq_id, sequence, next_token, output, prompt_eval_time });
+            }
+            Ok((seq_id, sequence, prompt_eval_time, None)) => self.end_sequence(seq_id, sequence, output, prompt_eval_time),
+            Err(e) => output.done(Err(e)),
+        }
+        #[cfg(not(feature = "inference"))]
+        {
+            let _ = (prompt, seed, settings, cancellation);
+            output.done(Err(ModelError::InferenceDisabled));
+        }
+    }
+    
+    /// Tokenize and decode a sequence's prompt and sample the first token,
+    /// while the prompt's logits are the last ones decoded
+    #[cfg(feature = "inference")]
+    fn decode_sequence_prompt(
+        &mut self,
+        prompt: &str,
+        seed: u32,
+        settings: &SamplingSettings,
+        cancellation: Option<CancellationToken>,
+        output: &mut SequenceOutput,
+    ) -> Result<(i32, Sequence, Duration, Option<LlamaToken>), ModelError> {
+        let Some(seq_id) = (0..self.parallel() as i32).find(|id| self.sequences.iter().all(|running| running.seq_id != *id)) else {
+            return Err(ModelError::GenerationFailed(format!("All {} sequences are in use", self.parallel())));
+        };
+        let stop_sequences = output.shaper.as_ref().map_or(&[][..], ReplyShaper::stop_sequences);
+        let Some(llama_model) = self.llama_model.as_ref() else {
+            return Err(ModelError::LoadFailed("Model is not loaded".to_string()));
+        };
+        let tokens = llama_model.vocab().tokenize(prompt.as_bytes(), true, true);
+        if tokens.is_empty() {
+            return Err(ModelError::GenerationFailed("Prompt is empty".to_string()));
+        }
+        if tokens.len() >= self.config.context_size {
+            return Err(ModelError::ContextOverflow {
+                prompt_tokens: tokens.len(),
+                context_size: self.config.context_size,
+            });
+        }
+        let mut sequence = self.new_sequence(tokens.len(), settings, seed, cancellation, stop_sequences)?;
+        // Sequence 0 is also the one `complete_llama` caches prompts in
+        if seq_id == 0 {
+            self.evaluated_tokens.clear();
+        }
+        let (Some(ctx), Some(llama_model)) = (self.llama_context.as_mut(), self.llama_model.as_ref()) else {
+            return Err(ModelError::LoadFailed("Model is not loaded".to_string()));
+        };
+        debug!("Starting sequence {} with prompt: {}", seq_id, prompt);
+        
+        let prompt_start = Instant::now();
+        ctx.clear_kv_cache_seq(Some(seq_id as u32), None, None)
+            .map_err(|e| ModelError::GenerationFailed(e.to_string()))?;
+        let batch_size = self.config.batch_size.max(1);
+        let mut batch = LlamaBatch::new(batch_size.min(tokens.len()), 1);
+        for (chunk_index, chunk) in tokens.chunks(batch_size).enumerate() {
+            batch.clear();
+            for (i, token) in chunk.iter().enumerate() {
+                let position = chunk_index * batch_size + i;
+                batch.add(*token, position as i32, &[seq_id], position == tokens.len() - 1)
+                    .map_err(|e| ModelError::GenerationFailed(e.to_string()))?;
+            }
+            ctx.decode(&mut batch)
+                .map_err(|e| ModelError::GenerationFailed(e.to_string()))?;
+        }
+        let prompt_eval_time = prompt_start.elapsed();
+        
+        let next_token = sequence.sample(ctx, llama_model, batch.n_tokens() - 1, &mut |piece| output.token(piece));
+        Ok((seq_id, sequence, prompt_eval_time, next_token))
+    }
+    
+    /// Decode the last token sampled for every running sequence in one batch
+    /// and sample each one's next token, ending the replies that are done
+    pub fn step_sequences(&mut self) {
+        #[cfg(feature = "inference")]
+        {
+            if self.sequences.is_empty() {
+                return;
+            }
+            let (Some(ctx), Some(llama_model)) = (self.llama_context.as_mut(), self.llama_model.as_ref()) else {
+                return;
+            };
+            let mut batch = LlamaBatch::new(self.sequences.len(), 1);
+            let decoded = self.sequences.iter()
+                .try_for_each(|running| {
+                    let position = running.sequence.next_position() as i32;
+                    batch.add(running.next_token, position, &[running.seq_id], true)
+                })
+                .map_err(|e| e.to_string())
+                .and_then(|()| ctx.decode(&mut batch).map_err(|e| e.to_string()));
+            if let Err(e) = decoded {
+                warn!("Decoding {} sequences failed: {}", self.sequences.len(), e);
+                self.fail_sequences(&e);
+                return;
+            }
+            
+            for (index, running) in self.sequences.iter_mut().enumerate() {
+                running.sequence.decoded(running.next_token);
+                let output = &mut running.output;
+                if let Some(token) = running.sequence.sample(ctx, llama_model, index as i32, &mut |piece| output.token(piece)) {
+                    running.next_token = token;
+                }
+            }
+            let (finished, running) = std::mem::take(&mut self.sequences)
+                .into_iter()
+                .partition(|running| running.sequence.is_finished());
+            self.sequences = running;
+            for running in finished {
+                self.end_sequence(running.seq_id, running.sequence, running.output, running.prompt_eval_time);
+            }
+        }
+    }
+    
+    /// Send a finished reply and free its part of the KV cache
+    #[cfg(feature = "inference")]
+    fn end_sequence(&mut self, seq_id: i32, sequence: Sequence, mut output: SequenceOutput, prompt_eval_time: Duration) {
+        if let Some(ctx) = self.llama_context.as_mut() {
+            let _ = ctx.clear_kv_cache_seq(Some(seq_id as u32), None, None);
+        }
+        let (completion, _) = sequence.finish(&mut |piece| output.token(piece));
+        output.done(Ok(Completion { prompt_eval_time, ..completion }));
+    }
+    
+    /// End every running sequence with an error
+    #[cfg(feature = "inference")]
+    fn fail_sequences(&mut self, message: &str) {
+        for running in std::mem::take(&mut self.sequences) {
+            if let Some(ctx) = self.llama_context.as_mut() {
+                let _ = ctx.clear_kv_cache_seq(Some(running.seq_id as u32), None, None);
+            }
+            running.output.done(Err(ModelError::GenerationFailed(message.to_string())));
+        }
+    }
+    
+    /// Format a conversation with its own template if it has one, else the model's
+    ///
+    /// This is exactly the prompt `generate` sends to the model.
+    pub fn format_prompt(&self, context: &ChatContext) -> String {
+        context.format_prompt_with_template(self.template_for(context))
     }
-}
\ No newline at end of file
+    
+    /// The template a conversation is formatted with
+    fn template_for<'a>(&'a self, context: &'a ChatContext) -> &'a PromptTemplate {
+        context.template.as_ref().unwrap_or(&self.template)
+    }
+    
+    /// Run the decode loop on a raw prompt and return the generated text,
+    /// which ends before the first of `stop_sequences` the model writes
+    fn complete_seeded(
+        &mut self,
+        prompt: &str,
+        seed: u32,
+        stop_sequences: &'static [&'static str],
+        on_token: &mut dyn FnMut(&str),
+    ) -> Result<Completion, ModelError> {
+        #[cfg(feature = "mock")]
+        if self.mock {
+            return self.complete_mock(prompt, seed, on_token);
+        }
+        
+        self.complete_llama(prompt, seed, stop_sequences, on_token)
+    }
+    
+    /// Tokenize, decode and sample with llama.cpp
+    #[cfg(feature = "inference")]
+    fn complete_llama(
+        &mut self,
+        prompt: &str,
+        seed: u32,
+        stop_sequences: &'static [&'static str],
+        on_token: &mut dyn FnMut(&str),
+    ) -> Result<Completion, ModelError> {
+        // Their sequences share the KV cache this would clear
+        if !self.sequences.is_empty() {
+            return Err(ModelError::GenerationFailed("The model is busy generating parallel sequences".to_string()));
+        }
+        let (Some(ctx), Some(llama_model)) = (self.llama_context.as_mut(), self.llama_model.as_ref()) else {
+            return Err(ModelError::LoadFailed("Model is not loaded".to_string()));
+        };
+        debug!("Using prompt: {}", prompt);
+        
+        let vocab = llama_model.vocab();
+        let tokens = vocab.tokenize(prompt.as_bytes(), true, true);
+        if tokens.is_empty() {
+            return Err(ModelError::GenerationFailed("Prompt is empty".to_string()));
+        }
+        if tokens.len() >= self.config.context_size {
+            return Err(ModelError::ContextOverflow {
+                prompt_tokens: tokens.len(),
+                context_size: self.config.context_size,
+            });
+        }
+        
+        // Keep the part of the KV cache shared with the previous prompt, so a new
+        // chat turn only decodes the messages appended since. The cache is left
+        // empty if anything below fails, which forces a full reset next time.
+        let cached = std::mem::take(&mut self.evaluated_tokens);
+        // The last prompt token is always decoded again to get fresh logits
+        let mut reused = cached.iter().zip(&tokens).take_while(|(a, b)| a == b).count()
+            .min(tokens.len() - 1);
+        let trimmed = reused > 0
+            && ctx.clear_kv_cache_seq(Some(0), Some(reused as u32), None).unwrap_or(false);
+        if !trimmed {
+            // Nothing in common, or a cache that can't be partially cleared
+            ctx.clear_kv_cache();
+            reused = 0;
+        }
+        debug!("Reusing {} of {} prompt tokens from the KV cache", reused, tokens.len());
+        
+        let prompt_start = Instant::now();
+        // Submit the prompt in chunks of at most n_batch tokens
+        let suffix = &tokens[reused..];
+        let batch_size = self.config.batch_size.max(1);
+        let mut batch = LlamaBatch::new(batch_size.min(suffix.len()), 1);
+        for (chunk_index, chunk) in suffix.chunks(batch_size).enumerate() {
+            batch.clear();
+            let offset = reused + chunk_index * batch_size;
+            for (i, token) in chunk.iter().enumerate() {
+                let position = offset + i;
+                batch.add(*token, position as i32, &[0], position == tokens.len() - 1)
+                    .map_err(|e| ModelError::GenerationFailed(e.to_string()))?;
+            }
+            ctx.decode(&mut batch)
+                .map_err(|e| ModelError::GenerationFailed(e.to_string()))?;
+        }
+        let prompt_eval_time = prompt_start.elapsed();
+        
+        let (completion, generated) = self.sample_completion(tokens.len(), batch.n_tokens() - 1, seed, stop_sequences, on_token)?;
+        let mut evaluated = tokens;
+        evaluated.extend(generated);
+        self.evaluated_tokens = evaluated;
+        Ok(Completion { prompt_eval_time, ..completion })
+    }
+    
+    /// Sample a completion after a prompt taking `prompt_len` positions, whose
+    /// last logits are at `logits_index` in the context (-1 for the last ones
+    /// decoded), and return it with the tokens generated
+    ///
+    /// `prompt_eval_time` is left at zero for the caller to fill in.
+    #[cfg(feature = "inference")]
+    fn sample_completion(
+        &mut self,
+        prompt_len: usize,
+        mut logits_index: i32,
+        seed: u32,
+        stop_sequences: &'static [&'static str],
+        on_token: &mut dyn FnMut(&str),
+    ) -> Result<(Completion, Vec<LlamaToken>), ModelError> {
+        let settings = self.sampling_settings();
+        let mut sequence = self.new_sequence(prompt_len, &settings, seed, self.cancellation.clone(), stop_sequences)?;
+        let (Some(ctx), Some(llama_model)) = (self.llama_context.as_mut(), self.llama_model.as_ref()) else {
+            return Err(ModelError::LoadFailed("Model is not loaded".to_string()));
+        };
+        
+        let mut batch = LlamaBatch::new(1, 1);
+        while let Some(token) = sequence.sample(ctx, llama_model, logits_index, on_token) {
+            batch.clear();
+            batch.add(token, sequence.next_position() as i32, &[0], true)
+                .map_err(|e| ModelError::GenerationFailed(e.to_string()))?;
+            ctx.decode(&mut batch)
+                .map_err(|e| ModelError::GenerationFailed(e.to_string()))?;
+            logits_index = batch.n_tokens() - 1;
+            sequence.decoded(token);
+        }
+        Ok(sequence.finish(on_token))
+    }
+    
+    /// The settings a generation started now samples with
+    fn sampling_settings(&self) -> SamplingSettings {
+        SamplingSettings {
+            temperature: self.temperature,
+            top_p: self.top_p,
+            top_k: self.top_k,
+            frequency_penalty: self.frequency_penalty,
+            presence_penalty: self.presence_penalty,
+            sampler_chain: self.sampler_chain.clone(),
+            grammar: self.grammar.clone(),
+            max_tokens: self.max_tokens,
+            repeat_window: self.repeat_window,
+            ignore_eos: self.ignore_eos,
+        }
+    }
+    
+    /// Start sampling a reply after a prompt taking `prompt_len` positions
+    #[cfg(feature = "inference")]
+    fn new_sequence(
+        &self,
+        prompt_len: usize,
+        settings: &SamplingSettings,
+        seed: u32,
+        cancellation: Option<CancellationToken>,
+        stop_sequences: &'static [&'static str],
+    ) -> Result<Sequence, ModelError> {
+        let Some(llama_model) = self.llama_model.as_ref() else {
+            return Err(ModelError::LoadFailed("Model is not loaded".to_string()));
+        };
+        debug!("Model parameters: temp={}, max_tokens={}, top_p={}, top_k={}, seed={}", 
+               settings.temperature, settings.max_tokens, settings.top_p, settings.top_k, seed);
+        
+        // Greedy decoding ignores top-p and temperature, but penalties still apply
+        let greedy = settings.temperature <= 0.0;
+        let mut samplers = Vec::new();
+        // The grammar goes first so later steps only see tokens it allows
+        if let Some(grammar) = &settings.grammar {
+            samplers.push(LlamaSampler::grammar(llama_model, grammar, "root")
+                .map_err(|e| ModelError::InvalidGrammar(e.to_string()))?);
+        }
+        for step in &settings.sampler_chain {
+            match step {
+                SamplerStep::Penalties if settings.frequency_penalty != 0.0 || settings.presence_penalty != 0.0 => {
+                    // Penalize based on everything this completion can generate
+                    let penalty_window = settings.max_tokens.min(self.config.context_size) as i32;
+                    samplers.push(LlamaSampler::penalties(
+                        llama_model.n_vocab(),
+                        penalty_window,
+                        1.0,
+                        settings.frequency_penalty,
+                        settings.presence_penalty,
+                    ));
+                }
+                SamplerStep::TopK if !greedy && settings.top_k > 0 => {
+                    samplers.push(LlamaSampler::top_k(settings.top_k.min(i32::MAX as usize) as i32));
+                }
+                SamplerStep::TopP if !greedy => samplers.push(LlamaSampler::top_p(settings.top_p, 1)),
+                SamplerStep::Temperature if !greedy => samplers.push(LlamaSampler::temp(settings.temperature)),
+                _ => {}
+            }
+        }
+        samplers.push(if greedy { LlamaSampler::greedy() } else { LlamaSampler::dist(seed) });
+        
+        // Stop at the end of the context window even if max_tokens allows more
+        let max_new = settings.max_tokens.min(self.config.context_size - prompt_len);
+        Ok(Sequence {
+            sampler: LlamaSampler::chain_simple(samplers),
+            cancellation,
+            stop_tokens: self.stop_tokens.iter().chain(&self.template_stop_tokens).copied().collect(),
+            ignore_eos: settings.ignore_eos,
+            repeat_window: settings.repeat_window,
+            debug_sampling: self.config.debug_sampling,
+            stop_sequences,
+            prompt_len,
+            max_new,
+            output: String::new(),
+            emitted: 0,
+            decoder: Utf8Decoder::default(),
+            generated: Vec::new(),
+            hit_stop_sequence: false,
+            finish_reason: (max_new == 0).then_some(FinishReason::Length),
+            started: Instant::now(),
+        })
+    }
+    
+    #[cfg(not(feature = "inference"))]
+    fn complete_llama(
+        &mut self,
+        _prompt: &str,
+        _seed: u32,
+        _stop_sequences: &'static [&'static str],
+        _on_token: &mut dyn FnMut(&str),
+    ) -> Result<Completion, ModelError> {
+        Err(ModelError::InferenceDisabled)
+    }
+    
+    /// Run a prompt with images through the multimodal projector and llama.cpp,
+    /// then sample as `complete_llama` does
+    ///
+    /// Image embeddings can't be matched against the KV cache, so the prompt is
+    /// always decoded from scratch and the cache is left empty for the next one.
+    #[cfg(feature = "vision")]
+    fn complete_with_images(
+        &mut self,
+        prompt: &str,
+        images: &[Vec<u8>],
+        seed: u32,
+        stop_sequences: &'static [&'static str],
+        on_token: &mut dyn FnMut(&str),
+    ) -> Result<Completion, ModelError> {
+        if !self.loaded {
+            return Err(ModelError::LoadFailed("Model is not loaded".to_string()));
+        }
+        if self.parallel() > 1 {
+            return Err(ModelError::VisionUnsupported("the model was loaded with parallel sequences".to_string()));
+        }
+        let (Some(mtmd_context), Some(ctx)) = (self.mtmd_context.as_mut(), self.llama_context.as_mut()) else {
+            return Err(ModelError::VisionUnsupported(format!(
+                "no multimodal projector (mmproj) was found next to {:?}",
+                self.model_path
+            )));
+        };
+        debug!("Using prompt with {} images: {}", images.len(), prompt);
+        
+        let bitmaps = images.iter()
+            .enumerate()
+            .map(|(i, image)| MtmdBitmap::from_buffer(mtmd_context, image, false)
+                .map_err(|e| ModelError::InvalidImage(format!("image {} could not be decoded: {}", i + 1, e))))
+            .collect::<Result<Vec<_>, _>>()?;
+        let text = MtmdInputText { text: prompt.to_string(), add_special: true, parse_special: true };
+        let chunks = mtmd_context.tokenize(text, &bitmaps.iter().collect::<Vec<_>>())
+            .map_err(|e| ModelError::InvalidImage(e.to_string()))?;
+        let prompt_len = chunks.total_positions() as usize;
+        if prompt_len >= self.config.context_size {
+            return Err(ModelError::ContextOverflow {
+                prompt_tokens: prompt_len,
+                context_size: self.config.context_size,
+            });
+        }
+        
+        self.evaluated_tokens.clear();
+        ctx.clear_kv_cache();
+        let prompt_start = Instant::now();
+        chunks.eval_chunks(mtmd_context, ctx, 0, 0, self.config.batch_size.max(1) as i32, true)
+            .map_err(|e| ModelError::GenerationFailed(e.to_string()))?;
+        let prompt_eval_time = prompt_start.elapsed();
+        
+        let (completion, _) = self.sample_completion(prompt_len, -1, seed, stop_sequences, on_token)?;
+        Ok(Completion { prompt_eval_time, ..completion })
+    }
+    
+    #[cfg(not(feature = "vision"))]
+    fn complete_with_images(
+        &mut self,
+        _prompt: &str,
+        _images: &[Vec<u8>],
+        _seed: u32,
+        _stop_sequences: &'static [&'static str],
+        _on_token: &mut dyn FnMut(&str),
+    ) -> Result<Completion, ModelError> {
+        Err(ModelError::VisionUnsupported(
+            "rustllm was built without the `vision` feature".to_string(),
+        ))
+    }
+    
+    /// Check that the model can read images, failing with the reason it can't
+    pub fn check_image_support(&self) -> Result<(), ModelError> {
+        #[cfg(feature = "vision")]
+        {
+            if self.parallel() > 1 {
+                return Err(ModelError::VisionUnsupported("the model was loaded with parallel sequences".to_string()));
+            }
+            if self.mtmd_context.is_none() {
+                return Err(ModelError::VisionUnsupported(format!(
+                    "no multimodal projector (mmproj) was found next to {:?}",
+                    self.model_path
+                )));
+            }
+            Ok(())
+        }
+        #[cfg(not(feature = "vision"))]
+        Err(ModelError::VisionUnsupported(
+            "rustllm was built without the `vision` feature".to_string(),
+        ))
+    }
+    
+    /// Number of tokens `prompt` takes, tokenized the way generation does
+    pub fn count_tokens(&self, prompt: &str) -> Result<usize, ModelError> {
+        #[cfg(feature = "mock")]
+        if self.mock {
+            return Ok(prompt.len() / 4);
+        }
+        
+        self.count_tokens_llama(prompt)
+    }
+    
+    #[cfg(feature = "inference")]
+    fn count_tokens_llama(&self, prompt: &str) -> Result<usize, ModelError> {
+        let llama_model = self.llama_model.as_ref()
+            .ok_or_else(|| ModelError::LoadFailed("Model is not loaded".to_string()))?;
+        Ok(llama_model.vocab().tokenize(prompt.as_bytes(), true, true).len())
+    }
+    
+    #[cfg(not(feature = "inference"))]
+    fn count_tokens_llama(&self, _prompt: &str) -> Result<usize, ModelError> {
+        Err(ModelError::InferenceDisabled)
+    }
+    
+    /// Update temperature (0.0 - 1.0)
+    pub fn set_temperature(&mut self, temperature: f32) {
+        self.temperature = temperature;
+    }
+    
+    /// Update top_p (0.0 - 1.0)
+    pub fn set_top_p(&mut self, top_p: f32) {
+        self.top_p = top_p;
+    }
+    
+    /// Update top_k (0 = off)
+    pub fn set_top_k(&mut self, top_k: usize) {
+        self.top_k = top_k;
+    }
+    
+    /// Update the OpenAI-style frequency and presence penalties (-2.0 - 2.0,
+    /// 0.0 = off)
+    pub fn set_penalties(&mut self, frequency_penalty: f32, presence_penalty: f32) {
+        self.frequency_penalty = frequency_penalty;
+        self.presence_penalty = presence_penalty;
+    }
+    
+    /// Change the order sampling steps apply in (see `SamplerStep::DEFAULT_CHAIN`)
+    pub fn set_sampler_chain(&mut self, chain: Vec<SamplerStep>) {
+        self.sampler_chain = chain;
+    }
+    
+    /// Constrain the output to a GBNF grammar with a `root` rule, or lift the
+    /// constraint with `None` (see `JSON_GRAMMAR`)
+    pub fn set_grammar(&mut self, grammar: Option<String>) {
+        self.grammar = grammar;
+    }
+    
+    /// Update max_new_tokens
+    pub fn set_max_tokens(&mut self, max_tokens: usize) {
+        self.max_tokens = max_tokens;
+    }
+    
+    /// Go back to the sampling settings the model was loaded with, so a
+    /// server request's temperature, top_p, top_k, max_tokens, penalties,
+    /// sampler order and grammar don't carry over to the next request
+    pub fn reset_sampling(&mut self) {
+        self.temperature = self.config.temperature;
+        self.top_p = DEFAULT_TOP_P;
+        self.top_k = 0;
+        self.max_tokens = DEFAULT_MAX_TOKENS;
+        self.frequency_penalty = 0.0;
+        self.presence_penalty = 0.0;
+        self.sampler_chain = SamplerStep::DEFAULT_CHAIN.to_vec();
+        self.grammar = None;
+    }
+    
+    /// Stop when the last `window` generated tokens repeat (None = off)
+    pub fn set_repeat_window(&mut self, window: Option<usize>) {
+        self.repeat_window = window;
+    }
+    
+    /// Keep generating past end-of-generation tokens until `max_tokens`
+    pub fn set_ignore_eos(&mut self, ignore_eos: bool) {
+        self.ignore_eos = ignore_eos;
+    }
+    
+    /// Get current temperature
+    pub fn get_temperature(&self) -> f32 {
+        self.temperature
+    }
+    
+    /// Get current max_tokens
+    pub fn get_max_tokens(&self) -> usize {
+        self.max_tokens
+    }
+    
+    /// Get current top_p
+    pub fn get_top_p(&self) -> f32 {
+        self.top_p
+    }
+    
+    /// Get current top_k (0 = off)
+    pub fn get_top_k(&self) -> usize {
+        self.top_k
+    }
+    
+    /// Whether the model can hold a conversation
+    ///
+    /// Base and embedding models have no chat template, and forcing one on
+    /// them produces nonsense; use `complete` for those instead.
+    pub fn is_chat_capable(&self) -> bool {
+        self.chat_capable
+    }
+    
+    /// Stop generations at the next token once `token` is cancelled
+    ///
+    /// The token stays in place for later generations until it is replaced or
+    /// cleared with `None`.
+    pub fn set_cancellation(&mut self, token: Option<CancellationToken>) {
+        self.cancellation = token;
+    }
+    
+    #[cfg_attr(not(feature = "mock"), allow(dead_code))]
+    fn is_cancelled(&self) -> bool {
+        self.cancellation.as_ref().is_some_and(CancellationToken::is_cancelled)
+    }
+    
+    /// The sampling and load parameters currently in effect
+    pub fn parameter_snapshot(&self) -> ModelParams {
+        ModelParams {
+            temperature: self.get_temperature(),
+            top_p: self.get_top_p(),
+            top_k: self.get_top_k(),
+            max_tokens: self.get_max_tokens(),
+            frequency_penalty: self.frequency_penalty,
+            presence_penalty: self.presence_penalty,
+            context_size: self.config.context_size,
+            n_threads: self.config.n_threads,
+            n_gpu_layers: self.config.n_gpu_layers,
+            batch_size: self.config.batch_size,
+            template: self.template.clone(),
+            sampler_chain: self.sampler_chain.clone(),
+        }
+    }
+    
+    /// Template used to format chat prompts
+    pub fn template(&self) -> &PromptTemplate {
+        &self.template
+    }
+    
+    /// Set the template used to format chat prompts, stopping generation at
+    /// its end-of-turn tokens from now on
+    pub fn set_template(&mut self, template: PromptTemplate) {
+        self.template_stop_tokens = self.lookup_stop_tokens(&template);
+        self.template = template;
+    }
+    
+    /// Also end generation when the token `id` is sampled
+    pub fn add_stop_token(&mut self, id: i32) {
+        if !self.stop_tokens.contains(&id) {
+            self.stop_tokens.push(id);
+        }
+    }
+    
+    /// Ids of the template's end-of-turn markers that are single tokens in
+    /// this model's vocabulary
+    #[cfg(feature = "inference")]
+    fn lookup_stop_tokens(&self, template: &PromptTemplate) -> Vec<i32> {
+        let Some(llama_model) = self.llama_model.as_ref() else {
+            return Vec::new();
+        };
+        let vocab = llama_model.vocab();
+        template
+            .end_of_turn_markers()
+            .iter()
+            .filter_map(|marker| match vocab.tokenize(marker.as_bytes(), false, true).as_slice() {
+                [token] => Some(token.0),
+                _ => None,
+            })
+            .inspect(|id| debug!("Stopping generation at template token {}", id))
+            .collect()
+    }
+    
+    #[cfg(not(feature = "inference"))]
+    fn lookup_stop_tokens(&self, _template: &PromptTemplate) -> Vec<i32> {
+        Vec::new()
+    }
+    
+    /// Get the path the model was loaded from
+    pub fn model_path(&self) -> &Path {
+        &self.model_path
+    }
+    
+    /// Get model configuration
+    pub fn get_config(&self) -> &ModelConfig {
+        &self.config
+    }
+    
+    /// Rough memory the loaded model takes: its weights plus a KV cache for
+    /// the whole context window of every sequence, with the share of offloaded layers in VRAM
+    pub fn memory_footprint(&self) -> MemoryFootprint {
+        #[cfg(feature = "inference")]
+        if let Some(llama_model) = &self.llama_model {
+            let kv_cache_tokens = (self.config.context_size * self.parallel()) as u64;
+            let kv_cache = kv_cache_tokens * kv_cache_bytes_per_token(llama_model.n_params());
+            let total = llama_model.size() + kv_cache;
+            let layers = u64::from(llama_model.n_layer().max(1));
+            let offloaded = (self.config.n_gpu_layers.max(0) as u64).min(layers);
+            let vram_bytes = total / layers * offloaded;
+            return MemoryFootprint { ram_bytes: total - vram_bytes, vram_bytes };
+        }
+        
+        // Without llama.cpp only the file size is known
+        let weights = std::fs::metadata(&self.model_path).map_or(0, |metadata| metadata.len());
+        MemoryFootprint { ram_bytes: if self.loaded { weights } else { 0 }, vram_bytes: 0 }
+    }
+    
+    /// Unload the model to free memory
+    pub fn unload(&mut self) {
+        info!("Unloading model: {:?}", self.model_path);
+        #[cfg(feature = "inference")]
+        {
+            #[cfg(feature = "vision")]
+            {
+                self.mtmd_context = None;
+            }
+            self.fail_sequences("Model was unloaded");
+            self.llama_context = None;
+            self.llama_model = None;
+            self.evaluated_tokens.clear();
+        }
+        self.loaded = false;
+    }
+}
+
+/// Whether the last `window` items are one short pattern repeated at least
+/// twice, e.g. the same token or phrase over and over
+#[cfg(feature = "inference")]
+fn ends_in_cycle<T: PartialEq>(items: &[T], window: usize) -> bool {
+    if window < 2 || items.len() < window {
+        return false;
+    }
+    let tail = &items[items.len() - window..];
+    (1..=window / 2).any(|period| tail[period..].iter().zip(tail).all(|(a, b)| a == b))
+}
+
+/// Most sequences a llama.cpp context can hold (`LLAMA_MAX_SEQ`)
+pub const MAX_PARALLEL: usize = 256;
+
+/// Tokens a reply may have unless `set_max_tokens` says otherwise
+const DEFAULT_MAX_TOKENS: usize = 1024;
+
+/// Nucleus sampling cutoff unless `set_top_p` says otherwise
+const DEFAULT_TOP_P: f32 = 0.95;
+
+/// Context size picked for models under 2B parameters, halved for each size
+/// tier above that
+#[cfg(feature = "inference")]
+const AUTO_CONTEXT_SMALL_MODEL: usize = 8192;
+
+/// Smallest context size the heuristic will pick, however little memory is free
+#[cfg(feature = "inference")]
+const AUTO_CONTEXT_MIN: usize = 512;
+
+/// Context size for a model with `n_params` parameters whose weights take
+/// `model_bytes`. Small models get a larger window; the window then shrinks
+/// until its KV cache fits in half of the memory left after the weights.
+#[cfg(feature = "inference")]
+fn auto_context_size(n_params: u64, model_bytes: u64, available_memory: Option<u64>) -> usize {
+    let preferred = match n_params {
+        0..=1_999_999_999 => AUTO_CONTEXT_SMALL_MODEL,
+        2_000_000_000..=9_999_999_999 => AUTO_CONTEXT_SMALL_MODEL / 2,
+        _ => AUTO_CONTEXT_SMALL_MODEL / 4,
+    };
+    let Some(available) = available_memory else {
+        return preferred;
+    };
+    let kv_bytes_per_token = kv_cache_bytes_per_token(n_params);
+    let budget = available.saturating_sub(model_bytes) / 2;
+    let mut context_size = preferred;
+    while context_size > AUTO_CONTEXT_MIN && context_size as u64 * kv_bytes_per_token > budget {
+        context_size /= 2;
+    }
+    context_size
+}
+
+/// GBNF grammar for a single JSON object, from llama.cpp's `grammars/json.gbnf`
+pub const JSON_GRAMMAR: &str = r#"root   ::= object
+value  ::= object | array | string | number | ("true" | "false" | "null") ws
+
+object ::=
+  "{" ws (
+            string ":" ws value
+    ("," ws string ":" ws value)*
+  )? "}" ws
+
+array  ::=
+  "[" ws (
+            value
+    ("," ws value)*
+  )? "]" ws
+
+string ::=
+  "\"" (
+    [^"\\\x7F\x00-\x1F] |
+    "\\" (["\\bfnrt] | "u" [0-9a-fA-F]{4})
+  )* "\"" ws
+
+number ::= ("-"? ([0-9] | [1-9] [0-9]{0,15})) ("." [0-9]+)? ([eE] [-+]? [0-9] [1-9]{0,15})? ws
+
+ws ::= | " " | "\n" [ \t]{0,20}
+"#;
+
+/// Where an image goes in a message's content, llama.cpp's default media marker
+/// (see `Model::generate_with_images`)
+pub const IMAGE_MARKER: &str = "<__media__>";
+
+/// Target of the events llama.cpp's log messages are routed into, for filtering
+pub const BACKEND_LOG_TARGET: &str = "llama-cpp-2";
+
+/// Send llama.cpp's log messages, which it otherwise prints straight to
+/// stderr, through `tracing` at their own levels (once per process)
+#[cfg(feature = "inference")]
+fn route_backend_logs() {
+    static ROUTED: std::sync::Once = std::sync::Once::new();
+    ROUTED.call_once(|| llama_cpp_2::send_logs_to_tracing(llama_cpp_2::LogOptions::default()));
+}
+
+/// A rough f16 KV cache cost, about 0.8 MiB per token for a 7B model
+#[cfg(feature = "inference")]
+fn kv_cache_bytes_per_token(n_params: u64) -> u64 {
+    (n_params / 8192).max(1)
+}
+
+/// Refuse to load a model whose weights plus KV cache won't fit in the
+/// available memory, unless `config.force_load` is set
+///
+/// Weights offloaded to the GPU don't count against system memory, so the
+/// check is skipped when any layers are offloaded, as it is when the available
+/// memory isn't known. An automatically sized context already fits whatever
+/// memory the weights leave, so only the weights are counted then.
+#[cfg(feature = "inference")]
+fn check_available_memory(model_path: &Path, model_bytes: u64, config: &ModelConfig) -> Result<(), ModelError> {
+    if config.n_gpu_layers > 0 {
+        return Ok(());
+    }
+    let Some(available) = crate::utils::available_memory_bytes() else {
+        return Ok(());
+    };
+    
+    let mut required = model_bytes;
+    if !config.auto_context_size {
+        // The header gives the parameter count and trained context without loading
+        if let Ok(header) = super::metadata::read_gguf_metadata(model_path) {
+            let context_size = match header.context_length {
+                Some(trained) if trained > 0 => (config.context_size as u64).min(trained),
+                _ => config.context_size as u64,
+            };
+            let n_params = header.parameter_count.unwrap_or_default();
+            let kv_cache_tokens = context_size.saturating_mul(config.parallel.max(1) as u64);
+            required = required.saturating_add(kv_cache_tokens.saturating_mul(kv_cache_bytes_per_token(n_params)));
+        }
+    }
+    if required <= available {
+        return Ok(());
+    }
+    
+    if config.force_load {
+        warn!(
+            "Loading {:?} needs about {} but only {} is available; the process may be killed for running out of memory",
+            model_path,
+            crate::utils::format_file_size(required),
+            crate::utils::format_file_size(available),
+        );
+        return Ok(());
+    }
+    Err(ModelError::InsufficientMemory { path: model_path.to_path_buf(), required, available })
+}
+
+/// CPU threads for a model with `n_params` parameters: every physical core,
+/// except that models under 1B parameters stop scaling past four threads
+#[cfg(feature = "inference")]
+fn auto_thread_count(n_params: u64) -> usize {
+    let cores = num_cpus::get_physical();
+    if n_params < 1_000_000_000 {
+        cores.min(4)
+    } else {
+        cores
+    }
+}
+
+/// A reply being sampled: its sampler, the text so far, and what ends it
+#[cfg(feature = "inference")]
+struct Sequence {
+    sampler: LlamaSampler,
+    cancellation: Option<CancellationToken>,
+    /// Token ids that end the reply besides the model's end-of-generation tokens
+    stop_tokens: Vec<i32>,
+    ignore_eos: bool,
+    repeat_window: Option<usize>,
+    debug_sampling: bool,
+    stop_sequences: &'static [&'static str],
+    prompt_len: usize,
+    max_new: usize,
+    output: String,
+    /// Text before this offset has been passed to on_token
+    emitted: usize,
+    decoder: Utf8Decoder,
+    /// Tokens sampled and decoded after the prompt
+    generated: Vec<LlamaToken>,
+    hit_stop_sequence: bool,
+    /// Set once the reply has ended
+    finish_reason: Option<FinishReason>,
+    started: Instant,
+}
+
+#[cfg(feature = "inference")]
+impl Sequence {
+    /// Position of the next token in the sequence
+    fn next_position(&self) -> usize {
+        self.prompt_len + self.generated.len()
+    }
+    
+    fn is_finished(&self) -> bool {
+        self.finish_reason.is_some()
+    }
+    
+    /// Sample the next token from the logits at `logits_index` and pass on the
+    /// text that is now final, returning the token if it has to be decoded for
+    /// the reply to go on
+    fn sample(
+        &mut self,
+        ctx: &mut LlamaContext,
+        llama_model: &LlamaModel,
+        logits_index: i32,
+        on_token: &mut dyn FnMut(&str),
+    ) -> Option<LlamaToken> {
+        if self.is_finished() {
+            return None;
+        }
+        if self.cancellation.as_ref().is_some_and(CancellationToken::is_cancelled) {
+            self.finish_reason = Some(FinishReason::Cancelled);
+            return None;
+        }
+        let vocab = llama_model.vocab();
+        let token = self.sampler.sample(ctx, logits_index);
+        if self.debug_sampling {
+            let piece = |token| String::from_utf8_lossy(&vocab.token_to_piece(token, false, None)).into_owned();
+            log_sampling_step(self.generated.len(), ctx.get_logits_ith(logits_index), token, piece);
+        }
+        if (vocab.is_eog(token) || self.stop_tokens.contains(&token.0)) && !self.ignore_eos {
+            self.finish_reason = Some(FinishReason::Stop);
+            return None;
+        }
+        // Stop sequences may be written with special tokens, so render those too
+        let render_special = !self.stop_sequences.is_empty();
+        let piece = self.decoder.push(&vocab.token_to_piece(token, render_special, None));
+        if !piece.is_empty() {
+            self.output.push_str(&piece);
+            // Text is only emitted once it can't be part of a stop sequence
+            let unsettled = &self.output[self.emitted..];
+            let stop = self.stop_sequences.iter().filter_map(|stop| unsettled.find(stop)).min();
+            let settled = self.emitted + stop.unwrap_or(unsettled.len() - partial_match_len(unsettled, self.stop_sequences));
+            if settled > self.emitted {
+                on_token(&self.output[self.emitted..settled]);
+                self.emitted = settled;
+            }
+            if stop.is_some() {
+                self.output.truncate(settled);
+                self.hit_stop_sequence = true;
+                self.finish_reason = Some(FinishReason::Stop);
+                return None;
+            }
+        }
+        Some(token)
+    }
+    
+    /// Record a sampled token once it has been decoded, ending the reply at
+    /// its length limit or when it starts repeating itself
+    fn decoded(&mut self, token: LlamaToken) {
+        self.generated.push(token);
+        if let Some(window) = self.repeat_window {
+            if ends_in_cycle(&self.generated, window) {
+                warn!("Stopping generation, the last {} tokens repeat", window);
+                self.finish_reason = Some(FinishReason::Repetition);
+                return;
+            }
+        }
+        if self.generated.len() >= self.max_new {
+            self.finish_reason = Some(FinishReason::Length);
+        }
+    }
+    
+    /// Pass on the rest of the text and return the completion with the tokens generated
+    ///
+    /// `prompt_eval_time` is left at zero for the caller to fill in.
+    fn finish(mut self, on_token: &mut dyn FnMut(&str)) -> (Completion, Vec<LlamaToken>) {
+        // Anything after a stop sequence was cut off, including a partial character
+        if !self.hit_stop_sequence {
+            self.output.push_str(&self.decoder.finish());
+        }
+        if self.output.len() > self.emitted {
+            on_token(&self.output[self.emitted..]);
+        }
+        let finish_reason = self.finish_reason.unwrap_or(FinishReason::Length);
+        let generation_time = self.started.elapsed();
+        info!("Generated {} tokens in {:?} ({:?})", self.generated.len(), generation_time, finish_reason);
+        
+        let completion = Completion {
+            text: self.output,
+            finish_reason,
+            prompt_tokens: self.prompt_len,
+            completion_tokens: self.generated.len(),
+            prompt_eval_time: Duration::ZERO,
+            generation_time,
+        };
+        (completion, self.generated)
+    }
+}
+
+/// A reply generated in its own sequence of a context shared with others
+#[cfg(feature = "inference")]
+struct RunningSequence {
+    /// Sequence id in the context and the batches
+    seq_id: i32,
+    sequence: Sequence,
+    /// Token sampled last, decoded by the next `step_sequences`
+    next_token: LlamaToken,
+    output: SequenceOutput,
+    prompt_eval_time: Duration,
+}
+
+/// Turns the bytes of successive token pieces into text. A multi-byte
+/// character can span tokens, so an incomplete sequence at the end of a piece
+/// is carried over to the next one instead of being converted early.
+#[cfg(feature = "inference")]
+#[derive(Default)]
+struct Utf8Decoder {
+    pending: Vec<u8>,
+}
+
+#[cfg(feature = "inference")]
+impl Utf8Decoder {
+    /// Append a token's bytes and return the text that is now complete.
+    /// Invalid sequences become U+FFFD rather than stalling the output.
+    fn push(&mut self, bytes: &[u8]) -> String {
+        self.pending.extend_from_slice(bytes);
+        let mut text = String::new();
+        let mut start = 0;
+        while start < self.pending.len() {
+            match std::str::from_utf8(&self.pending[start..]) {
+                Ok(valid) => {
+                    text.push_str(valid);
+                    start = self.pending.len();
+                }
+                Err(e) => {
+                    let valid_end = start + e.valid_up_to();
+                    text.push_str(std::str::from_utf8(&self.pending[start..valid_end]).expect("checked above"));
+                    match e.error_len() {
+                        Some(invalid) => {
+                            text.push(char::REPLACEMENT_CHARACTER);
+                            start = valid_end + invalid;
+                        }
+                        // Incomplete sequence at the end, wait for the next token
+                        None => {
+                            start = valid_end;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        self.pending.drain(..start);
+        text
+    }
+
+    /// Text for whatever is still carried over once generation stops
+    fn finish(self) -> String {
+        String::from_utf8_lossy(&self.pending).into_owned()
+    }
+}
+
+/// Candidates listed per step by `ModelConfig::debug_sampling`
+#[cfg(feature = "inference")]
+const DEBUG_SAMPLING_CANDIDATES: usize = 5;
+
+/// Log the most likely tokens of one generation step and the token sampled
+///
+/// Probabilities are the softmax of the raw logits, i.e. the model's own
+/// distribution before temperature, top-p and penalties reshape it.
+#[cfg(feature = "inference")]
+fn log_sampling_step(step: usize, logits: &[f32], sampled: LlamaToken, piece: impl Fn(LlamaToken) -> String) {
+    let max_logit = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let total: f32 = logits.iter().map(|logit| (logit - max_logit).exp()).sum();
+    let probability = |logit: f32| (logit - max_logit).exp() / total;
+    
+    // Partition out the best candidates rather than sorting the whole vocabulary
+    let mut top: Vec<(usize, f32)> = logits.iter().copied().enumerate().collect();
+    let count = DEBUG_SAMPLING_CANDIDATES.min(top.len());
+    if count < top.len() {
+        top.select_nth_unstable_by(count, |a, b| b.1.total_cmp(&a.1));
+        top.truncate(count);
+    }
+    top.sort_by(|a, b| b.1.total_cmp(&a.1));
+    
+    let candidates: Vec<String> = top
+        .iter()
+        .map(|&(id, logit)| format!("{:?} {:.3}", piece(LlamaToken::new(id as i32)), probability(logit)))
+        .collect();
+    let sampled_p = logits.get(sampled.0 as usize).map_or(0.0, |&logit| probability(logit));
+    debug!(
+        "step {}: sampled {:?} (p={:.3}), top: {}",
+        step,
+        piece(sampled),
+        sampled_p,
+        candidates.join(", ")
+    );
+}
+
+/// Architectures that only produce embeddings and can't generate text
+#[cfg(feature = "inference")]
+const EMBEDDING_ARCHITECTURES: &[&str] = &["bert", "nomic-bert", "jina-bert-v2", "t5encoder"];
+
+/// Oldest GGUF format version llama.cpp still loads (v1 support was dropped)
+pub const MIN_GGUF_VERSION: u32 = 2;
+
+/// Check that a file looks like a complete GGUF model
+///
+/// The file must start with the `GGUF` magic number followed by a format
+/// version llama.cpp still reads, and a file that is a built-in model's
+/// download must be close to the registry's size for it, unless it was pinned
+/// to another revision.
+pub fn check_gguf_file(model_path: &Path, size: u64) -> Result<(), ModelError> {
+    use std::io::Read;
+    
+    // The header starts with the magic number and a little-endian u32 version
+    let mut header = [0u8; 8];
+    let read = std::fs::File::open(model_path)?.read_exact(&mut header);
+    if read.is_err() || &header[..4] != b"GGUF" {
+        return Err(ModelError::NotAGgufFile { path: model_path.to_path_buf() });
+    }
+    let version = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    if version < MIN_GGUF_VERSION {
+        return Err(ModelError::UnsupportedGgufVersion {
+            path: model_path.to_path_buf(),
+            found: version,
+            minimum: MIN_GGUF_VERSION,
+        });
+    }
+    
+    // Registry sizes are approximate, so only flag files well short of them
+    let filename = model_path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+    let builtin = super::download::builtin_model_for_file(filename)
+        .filter(|_| super::download::stored_revision(model_path).is_none());
+    if let Some(info) = builtin {
+        if size < info.size_bytes / 10 * 9 {
+            return Err(ModelError::IncompleteDownload {
+                path: model_path.to_path_buf(),
+                name: info.name,
+                size,
+                expected: info.size_bytes,
+            });
+        }
+    }
+    
+    Ok(())
+}
+
+/// A model is chat-capable if it ships a chat template. Older conversions often
+/// lack one, so a chat or instruct tune named as such in `general.name` or the
+/// file name also counts.
+#[cfg(feature = "inference")]
+fn detect_chat_capable(llama_model: &LlamaModel, model_path: &Path) -> bool {
+    let architecture = llama_model.meta_val_str("general.architecture").unwrap_or_default();
+    if EMBEDDING_ARCHITECTURES.contains(&architecture.as_str()) {
+        return false;
+    }
+    
+    if llama_model.meta_val_str("tokenizer.chat_template").is_ok() {
+        return true;
+    }
+    
+    let general_name = llama_model.meta_val_str("general.name").unwrap_or_default();
+    let file_name = model_path.file_stem().and_then(|s| s.to_str()).unwrap_or_default();
+    [general_name.as_str(), file_name].iter().any(|name| {
+        name.to_lowercase()
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .any(|word| matches!(word, "chat" | "instruct" | "it"))
+    })
+}
+
+/// Load a multimodal projector for `llama_model`, on the GPU if any of the
+/// model's layers are
+#[cfg(feature = "vision")]
+fn load_projector(path: &Path, llama_model: &LlamaModel, config: &ModelConfig) -> Result<MtmdContext, ModelError> {
+    let path_str = path.to_str()
+        .ok_or_else(|| ModelError::LoadFailed(format!("Projector path {:?} is not valid UTF-8", path)))?;
+    let mut params = MtmdContextParams {
+        use_gpu: config.n_gpu_layers > 0,
+        print_timings: false,
+        ..MtmdContextParams::default()
+    };
+    if let Some(threads) = config.n_threads {
+        params.n_threads = threads as i32;
+    }
+    let mtmd_context = MtmdContext::init_from_file(path_str, llama_model, &params)
+        .map_err(|e| ModelError::LoadFailed(format!("Failed to load projector {:?}: {}", path, e)))?;
+    if !mtmd_context.support_vision() {
+        return Err(ModelError::LoadFailed(format!("Projector {:?} doesn't support image input", path)));
+    }
+    info!("Loaded multimodal projector {:?}", path);
+    Ok(mtmd_context)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zero_turn_context() -> ChatContext {
+        ChatContext::new("You are a helpful assistant.")
+    }
+
+    fn one_turn_context() -> ChatContext {
+        let mut context = zero_turn_context();
+        context.add_message(ChatMessage::user("What is the capital of France?"));
+        context
+    }
+
+    fn three_turn_context() -> ChatContext {
+        let mut context = ChatContext::new("You are a helpful assistant.");
+        context.add_message(ChatMessage::user("What is the capital of France?"));
+        context.add_message(ChatMessage::assistant("Paris."));
+        context.add_message(ChatMessage::user("And of Italy?"));
+        context
+    }
+
+    #[test]
+    fn chatml_without_turns_opens_the_reply() {
+        let prompt = zero_turn_context().format_prompt_with_template(&PromptTemplate::ChatML);
+
+        assert_eq!(
+            prompt,
+            "<|im_start|>system\nYou are a helpful assistant.<|im_end|>\n\
+             <|im_start|>assistant\n"
+        );
+    }
+
+    #[test]
+    fn chatml_renders_one_turn() {
+        let prompt = one_turn_context().format_prompt_with_template(&PromptTemplate::ChatML);
+
+        assert_eq!(
+            prompt,
+            "<|im_start|>system\nYou are a helpful assistant.<|im_end|>\n\
+             <|im_start|>user\nWhat is the capital of France?<|im_end|>\n\
+             <|im_start|>assistant\n"
+        );
+    }
+
+    #[test]
+    fn chatml_renders_all_turns() {
+        let prompt = three_turn_context().format_prompt_with_template(&PromptTemplate::ChatML);
+
+        assert_eq!(
+            prompt,
+            "<|im_start|>system\nYou are a helpful assistant.<|im_end|>\n\
+             <|im_start|>user\nWhat is the capital of France?<|im_end|>\n\
+             <|im_start|>assistant\nParis.<|im_end|>\n\
+             <|im_start|>user\nAnd of Italy?<|im_end|>\n\
+             <|im_start|>assistant\n"
+        );
+    }
+
+    #[test]
+    fn chatml_uses_the_default_system_prompt() {
+        let prompt = ChatContext::default().format_prompt_with_template(&PromptTemplate::ChatML);
+
+        assert_eq!(
+            prompt,
+            "<|im_start|>system\nYou are a helpful, respectful and honest assistant. \
+             Always answer as helpfully as possible.<|im_end|>\n\
+             <|im_start|>assistant\n"
+        );
+    }
+
+    #[test]
+    fn alpaca_without_turns_opens_the_response() {
+        let prompt = zero_turn_context().format_prompt_with_template(&PromptTemplate::Alpaca);
+
+        assert_eq!(
+            prompt,
+            "Below is an instruction that describes a task. Write a response that appropriately completes the request.\n\n\
+             ### Instruction:\nYou are a helpful assistant.\n\n\
+             ### Response:\n"
+        );
+    }
+
+    #[test]
+    fn alpaca_renders_one_turn() {
+        let prompt = one_turn_context().format_prompt_with_template(&PromptTemplate::Alpaca);
+
+        assert_eq!(
+            prompt,
+            "Below is an instruction that describes a task. Write a response that appropriately completes the request.\n\n\
+             ### Instruction:\nYou are a helpful assistant.\n\n\
+             ### Input:\nWhat is the capital of France?\n\n\
+             ### Response:\n"
+        );
+    }
+
+    #[test]
+    fn alpaca_renders_all_turns() {
+        let prompt = three_turn_context().format_prompt_with_template(&PromptTemplate::Alpaca);
+
+        assert_eq!(
+            prompt,
+            "Below is an instruction that describes a task. Write a response that appropriately completes the request.\n\n\
+             ### Instruction:\nYou are a helpful assistant.\n\n\
+             ### Input:\nWhat is the capital of France?\n\n\
+             ### Response:\nParis.\n\n\
+             ### Input:\nAnd of Italy?\n\n\
+             ### Response:\n"
+        );
+    }
+
+    #[test]
+    fn llama2_without_turns_keeps_the_system_block() {
+        let prompt = zero_turn_context().format_prompt_with_template(&PromptTemplate::Llama2);
+
+        assert_eq!(prompt, "[INST] <<SYS>>\nYou are a helpful assistant.\n<</SYS>> [/INST]");
+    }
+
+    #[test]
+    fn llama2_puts_the_system_block_in_the_first_turn() {
+        let prompt = one_turn_context().format_prompt_with_template(&PromptTemplate::Llama2);
+
+        assert_eq!(
+            prompt,
+            "[INST] <<SYS>>\nYou are a helpful assistant.\n<</SYS>>\n\n\
+             What is the capital of France? [/INST]"
+        );
+    }
+
+    #[test]
+    fn llama2_wraps_each_user_turn() {
+        let prompt = three_turn_context().format_prompt_with_template(&PromptTemplate::Llama2);
+
+        assert_eq!(
+            prompt,
+            "[INST] <<SYS>>\nYou are a helpful assistant.\n<</SYS>>\n\n\
+             What is the capital of France? [/INST] Paris. </s>\
+             <s>[INST] And of Italy? [/INST]"
+        );
+    }
+
+    #[test]
+    fn chatml_artifacts_are_stripped() {
+        let reply = PromptTemplate::ChatML.strip_artifacts("Paris.<|im_end|>\n<|im_start|>");
+
+        assert_eq!(reply, "Paris.");
+    }
+
+    #[test]
+    fn alpaca_artifacts_are_stripped() {
+        let reply = PromptTemplate::Alpaca.strip_artifacts("<s> Paris. </s>");
+
+        assert_eq!(reply, "Paris.");
+    }
+
+    #[test]
+    fn llama2_artifacts_are_stripped() {
+        let reply = PromptTemplate::Llama2.strip_artifacts(" Paris. </s><s>[INST]");
+
+        assert_eq!(reply, "Paris.");
+    }
+
+    #[test]
+    fn mistral_instruct_artifacts_are_stripped() {
+        let reply = PromptTemplate::MistralInstruct.strip_artifacts("Paris.[/INST] </s>");
+
+        assert_eq!(reply, "Paris.");
+    }
+
+    #[test]
+    fn phi3_artifacts_are_stripped() {
+        let reply = PromptTemplate::Phi3.strip_artifacts("\nParis.<|end|>\n<|assistant|>");
+
+        assert_eq!(reply, "Paris.");
+    }
+
+    #[test]
+    fn other_templates_tokens_are_kept() {
+        let reply = PromptTemplate::ChatML.strip_artifacts("Use </s> to end a sequence.");
+
+        assert_eq!(reply, "Use </s> to end a sequence.");
+    }
+
+    #[test]
+    fn filter_strips_an_artifact_split_across_pieces() {
+        let mut filter = ArtifactFilter::new(&PromptTemplate::ChatML);
+
+        let pieces = [" Paris", ". <|im", "_end", "|>"].map(|piece| filter.push(piece));
+
+        assert_eq!(pieces, ["Paris", ".", "", ""]);
+        assert_eq!(filter.finish(), "");
+    }
+
+    #[test]
+    fn filter_releases_text_that_only_looked_like_an_artifact() {
+        let mut filter = ArtifactFilter::new(&PromptTemplate::ChatML);
+
+        assert_eq!(filter.push("a <"), "a");
+        assert_eq!(filter.push(" b"), " < b");
+        assert_eq!(filter.push(" <|"), "");
+        assert_eq!(filter.finish(), " <|");
+    }
+
+    #[test]
+    fn sequence_output_sends_the_shaped_reply() {
+        let (events, receiver) = std::sync::mpsc::channel();
+        let shaper = ReplyShaper {
+            template: PromptTemplate::ChatML,
+            prefix: "Sure,".to_string(),
+            filter: Some(ArtifactFilter::new(&PromptTemplate::ChatML)),
+        };
+        let mut output = SequenceOutput { shaper: Some(shaper), events };
+
+        output.start();
+        output.token(" Paris");
+        output.token("<|im_end|>");
+        output.done(Ok(Completion {
+            text: " Paris<|im_end|>".to_string(),
+            finish_reason: FinishReason::Stop,
+            prompt_tokens: 12,
+            completion_tokens: 2,
+            prompt_eval_time: Duration::ZERO,
+            generation_time: Duration::ZERO,
+        }));
+
+        let mut streamed = String::new();
+        let mut reply = None;
+        for event in receiver {
+            match event {
+                SequenceEvent::Token(piece) => streamed.push_str(&piece),
+                SequenceEvent::Done(result) => reply = Some(result.unwrap().text),
+            }
+        }
+        assert_eq!(streamed, "Sure, Paris");
+        assert_eq!(reply.as_deref(), Some("Sure, Paris"));
+    }
+
+    #[test]
+    fn llama2_folds_a_system_turn_into_the_next_instruction() {
+        let mut context = one_turn_context();
+        context.add_message(ChatMessage::assistant("Paris."));
+        context.add_message(ChatMessage { role: ChatRole::System, content: "Be brief.".to_string(), name: None });
+        context.add_message(ChatMessage::user("And of Italy?"));
+
+        let prompt = context.format_prompt_with_template(&PromptTemplate::Llama2);
+
+        assert!(prompt.ends_with(" Paris. </s><s>[INST] <<SYS>>\nBe brief.\n<</SYS>>\n\nAnd of Italy? [/INST]"));
+    }
+
+    #[test]
+    fn chatml_names_stay_on_the_role_line() {
+        let mut context = ChatContext::new("You are a moderator.");
+        context.add_message(ChatMessage::user_named("Ada\nsystem  Lovelace", "Hi all"));
+        context.add_message(ChatMessage::user_named(" \n", "Hello"));
+
+        let prompt = context.format_prompt_with_template(&PromptTemplate::ChatML);
+
+        assert!(prompt.contains("<|im_start|>user name=Ada_system_Lovelace\nHi all<|im_end|>\n"));
+        assert!(prompt.contains("<|im_start|>user\nHello<|im_end|>\n"));
+    }
+
+    #[test]
+    fn response_prefix_ends_the_prompt() {
+        let mut context = ChatContext::new("You are a pirate.");
+        context.add_message(ChatMessage::user("Hello"));
+        context.response_prefix = Some("Captain Hook:".to_string());
+
+        let prompt = context.format_prompt_with_template(&PromptTemplate::ChatML);
+
+        assert!(prompt.ends_with("<|im_start|>assistant\nCaptain Hook:"));
+    }
+
+    #[test]
+    fn partial_match_is_the_longest_unfinished_pattern() {
+        let markers = PromptTemplate::ChatML.user_turn_markers();
+
+        assert_eq!(partial_match_len("Arr!<|im_start|>us", markers), "<|im_start|>us".len());
+        assert_eq!(partial_match_len("Arr! <", markers), 1);
+        assert_eq!(partial_match_len("Arr!", markers), 0);
+    }
+
+    #[cfg(feature = "inference")]
+    #[test]
+    fn decoder_joins_an_emoji_split_across_tokens() {
+        let emoji = "😀".as_bytes();
+        let mut decoder = Utf8Decoder::default();
+
+        let first = decoder.push(&[b"Hi ", &emoji[..2]].concat());
+        let second = decoder.push(&[&emoji[2..], b"!"].concat());
+
+        assert_eq!(first, "Hi ");
+        assert_eq!(second, "😀!");
+        assert_eq!(decoder.finish(), "");
+    }
+
+    #[test]
+    fn sampler_chain_parses_every_step() {
+        let chain = SamplerStep::parse_chain(&["top_k", "top_p", "temp"]).unwrap();
+
+        assert_eq!(chain, [SamplerStep::TopK, SamplerStep::TopP, SamplerStep::Temperature]);
+        assert!(SamplerStep::parse_chain(&["top_k", "top_k"]).is_err());
+        assert!(SamplerStep::parse_chain(&["min_p"]).is_err());
+    }
+}
@@ -0,0 +1,490 @@
+//! Model metadata read from GGUF headers, and an on-disk cache of it
+//!
+//! Reading a header only touches the key-value section at the start of the
+//! file, but that still means seeking past every tokenizer entry, so the
+//! results are kept in `~/.rustllm/metadata-cache.json` keyed by path and
+//! invalidated when a file's modification time or size changes.
+
+use super::ModelError;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Bumped when [`GgufMetadata`] gains fields, so older cache files are re-read
+const CACHE_VERSION: u32 = 2;
+
+/// GGUF value type ids, from the GGUF specification
+const TYPE_UINT8: u32 = 0;
+const TYPE_INT8: u32 = 1;
+const TYPE_UINT16: u32 = 2;
+const TYPE_INT16: u32 = 3;
+const TYPE_UINT32: u32 = 4;
+const TYPE_INT32: u32 = 5;
+const TYPE_FLOAT32: u32 = 6;
+const TYPE_BOOL: u32 = 7;
+const TYPE_STRING: u32 = 8;
+const TYPE_ARRAY: u32 = 9;
+const TYPE_UINT64: u32 = 10;
+const TYPE_INT64: u32 = 11;
+const TYPE_FLOAT64: u32 = 12;
+
+/// What `model list --details` shows about a model file
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GgufMetadata {
+    /// `general.architecture`, e.g. `llama`
+    pub architecture: Option<String>,
+    /// `general.name`
+    pub name: Option<String>,
+    /// `general.file_type`, the quantization most tensors use
+    pub file_type: Option<u32>,
+    /// `<architecture>.context_length`, the context the model was trained with
+    pub context_length: Option<u64>,
+    /// Total elements across all tensors
+    pub parameter_count: Option<u64>,
+}
+
+impl GgufMetadata {
+    /// The quantization as llama.cpp names it, e.g. `Q4_K_M`
+    pub fn quantization(&self) -> Option<String> {
+        self.file_type.map(quantization_name)
+    }
+}
+
+/// llama.cpp's name for a `general.file_type` value
+pub fn quantization_name(file_type: u32) -> String {
+    let name = match file_type {
+        0 => "F32",
+        1 => "F16",
+        2 => "Q4_0",
+        3 => "Q4_1",
+        7 => "Q8_0",
+        8 => "Q5_0",
+        9 => "Q5_1",
+        10 => "Q2_K",
+        11 => "Q3_K_S",
+        12 => "Q3_K_M",
+        13 => "Q3_K_L",
+        14 => "Q4_K_S",
+        15 => "Q4_K_M",
+        16 => "Q5_K_S",
+        17 => "Q5_K_M",
+        18 => "Q6_K",
+        19 => "IQ2_XXS",
+        20 => "IQ2_XS",
+        21 => "Q2_K_S",
+        22 => "IQ3_XS",
+        23 => "IQ3_XXS",
+        24 => "IQ1_S",
+        25 => "IQ4_NL",
+        26 => "IQ3_S",
+        27 => "IQ3_M",
+        28 => "IQ2_S",
+        29 => "IQ2_M",
+        30 => "IQ4_XS",
+        31 => "IQ1_M",
+        32 => "BF16",
+        other => return format!("type {}", other),
+    };
+    name.to_string()
+}
+
+/// Read the metadata of a GGUF file from its header
+///
+/// Only the key-value section and the tensor descriptions after it are read;
+/// values that aren't needed, such as the tokenizer vocabulary, are skipped
+/// over.
+pub fn read_gguf_metadata(path: &Path) -> Result<GgufMetadata, ModelError> {
+    let invalid = |reason: String| ModelError::InvalidGgufHeader { path: path.to_path_buf(), reason };
+
+    let mut reader = HeaderReader { inner: BufReader::new(File::open(path)?) };
+    let mut magic = [0u8; 4];
+    if reader.inner.read_exact(&mut magic).is_err() || &magic != b"GGUF" {
+        return Err(ModelError::NotAGgufFile { path: path.to_path_buf() });
+    }
+    let version = reader.u32().map_err(|e| invalid(e.to_string()))?;
+    if version < super::inference::MIN_GGUF_VERSION {
+        return Err(ModelError::UnsupportedGgufVersion {
+            path: path.to_path_buf(),
+            found: version,
+            minimum: super::inference::MIN_GGUF_VERSION,
+        });
+    }
+
+    reader.read_metadata().map_err(|e| invalid(e.to_string()))
+}
+
+/// Little-endian reads over a GGUF file
+struct HeaderReader<R> {
+    inner: R,
+}
+
+impl<R: Read + Seek> HeaderReader<R> {
+    fn bytes<const N: usize>(&mut self) -> std::io::Result<[u8; N]> {
+        let mut buf = [0u8; N];
+        self.inner.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn u32(&mut self) -> std::io::Result<u32> {
+        self.bytes().map(u32::from_le_bytes)
+    }
+
+    fn u64(&mut self) -> std::io::Result<u64> {
+        self.bytes().map(u64::from_le_bytes)
+    }
+
+    fn skip(&mut self, len: u64) -> std::io::Result<()> {
+        let len = i64::try_from(len).map_err(|_| invalid_data("length out of range"))?;
+        self.inner.seek(std::io::SeekFrom::Current(len))?;
+        Ok(())
+    }
+
+    fn string(&mut self) -> std::io::Result<String> {
+        let len = self.u64()?;
+        // Keys and the values kept are short; anything longer is corruption
+        if len > 64 * 1024 {
+            return Err(invalid_data("string too long"));
+        }
+        let mut buf = vec![0u8; len as usize];
+        self.inner.read_exact(&mut buf)?;
+        String::from_utf8(buf).map_err(|_| invalid_data("string is not UTF-8"))
+    }
+
+    fn skip_string(&mut self) -> std::io::Result<()> {
+        let len = self.u64()?;
+        self.skip(len)
+    }
+
+    /// Read a value if it's an integer, otherwise skip it
+    fn integer(&mut self, value_type: u32) -> std::io::Result<Option<u64>> {
+        let value = match value_type {
+            TYPE_UINT8 => self.bytes::<1>()?[0] as u64,
+            TYPE_UINT16 => u16::from_le_bytes(self.bytes()?) as u64,
+            TYPE_UINT32 => self.u32()? as u64,
+            TYPE_UINT64 => self.u64()?,
+            TYPE_INT32 => u64::try_from(i32::from_le_bytes(self.bytes()?)).unwrap_or_default(),
+            TYPE_INT64 => u64::try_from(i64::from_le_bytes(self.bytes()?)).unwrap_or_default(),
+            other => {
+                self.skip_value(other)?;
+                return Ok(None);
+            },
+        };
+        Ok(Some(value))
+    }
+
+    fn skip_value(&mut self, value_type: u32) -> std::io::Result<()> {
+        match value_type {
+            TYPE_STRING => self.skip_string(),
+            TYPE_ARRAY => {
+                let element_type = self.u32()?;
+                let len = self.u64()?;
+                match fixed_size(element_type) {
+                    Some(size) => self.skip(len.checked_mul(size).ok_or_else(|| invalid_data("array too long"))?),
+                    None => (0..len).try_for_each(|_| self.skip_value(element_type)),
+                }
+            },
+            other => match fixed_size(other) {
+                Some(size) => self.skip(size),
+                None => Err(invalid_data(format!("unknown value type {}", other))),
+            },
+        }
+    }
+
+    /// Read the key-value section and the tensor descriptions, which follow
+    /// the magic number and version
+    fn read_metadata(&mut self) -> std::io::Result<GgufMetadata> {
+        let tensor_count = self.u64()?;
+        let kv_count = self.u64()?;
+
+        let mut metadata = GgufMetadata::default();
+        // The architecture names the context length key, but may come after it
+        let mut context_lengths = Vec::new();
+        for _ in 0..kv_count {
+            let key = self.string()?;
+            let value_type = self.u32()?;
+            match key.as_str() {
+                "general.architecture" | "general.name" if value_type == TYPE_STRING => {
+                    let value = Some(self.string()?);
+                    if key == "general.architecture" {
+                        metadata.architecture = value;
+                    } else {
+                        metadata.name = value;
+                    }
+                },
+                "general.file_type" => {
+                    metadata.file_type = self.integer(value_type)?.and_then(|value| u32::try_from(value).ok());
+                },
+                _ => match key.strip_suffix(".context_length") {
+                    Some(architecture) => {
+                        if let Some(value) = self.integer(value_type)? {
+                            context_lengths.push((architecture.to_string(), value));
+                        }
+                    },
+                    None => self.skip_value(value_type)?,
+                },
+            }
+        }
+        metadata.context_length = context_lengths.into_iter()
+            .find(|(architecture, _)| Some(architecture) == metadata.architecture.as_ref())
+            .map(|(_, value)| value);
+
+        // Each tensor is described by its name, dimensions, type and offset
+        let mut parameter_count: u64 = 0;
+        for _ in 0..tensor_count {
+            self.skip_string()?;
+            let dimensions = self.u32()?;
+            if dimensions > 8 {
+                return Err(invalid_data(format!("tensor has {} dimensions", dimensions)));
+            }
+            let mut elements: u64 = 1;
+            for _ in 0..dimensions {
+                elements = elements.saturating_mul(self.u64()?);
+            }
+            parameter_count = parameter_count.saturating_add(elements);
+            self.skip(4 + 8)?;
+        }
+        metadata.parameter_count = Some(parameter_count).filter(|count| *count > 0);
+
+        Ok(metadata)
+    }
+}
+
+/// Size in bytes of a fixed-size value type
+fn fixed_size(value_type: u32) -> Option<u64> {
+    match value_type {
+        TYPE_UINT8 | TYPE_INT8 | TYPE_BOOL => Some(1),
+        TYPE_UINT16 | TYPE_INT16 => Some(2),
+        TYPE_UINT32 | TYPE_INT32 | TYPE_FLOAT32 => Some(4),
+        TYPE_UINT64 | TYPE_INT64 | TYPE_FLOAT64 => Some(8),
+        _ => None,
+    }
+}
+
+fn invalid_data(message: impl Into<String>) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.into())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheEntry {
+    modified: SystemTime,
+    size: u64,
+    metadata: GgufMetadata,
+}
+
+#[derive(Debug, Deserialize)]
+struct CacheFile {
+    version: u32,
+    entries: HashMap<PathBuf, CacheEntry>,
+}
+
+/// Parsed GGUF metadata, cached in `~/.rustllm/metadata-cache.json`
+///
+/// A missing, unreadable or outdated cache file is treated as empty; it is
+/// only a cache, so it gets rebuilt on the next [`MetadataCache::save`].
+#[derive(Debug, Default)]
+pub struct MetadataCache {
+    path: Option<PathBuf>,
+    entries: HashMap<PathBuf, CacheEntry>,
+    changed: bool,
+}
+
+impl MetadataCache {
+    /// Location of the cache file
+    pub fn path() -> Option<PathBuf> {
+        home::home_dir().map(|home| home.join(".rustllm").join("metadata-cache.json"))
+    }
+
+    /// Load the cache file, or start an empty cache
+    pub fn load() -> Self {
+        let path = Self::path();
+        let entries = path.as_ref()
+            .and_then(|path| std::fs::read(path).ok())
+            .and_then(|contents| serde_json::from_slice::<CacheFile>(&contents).ok())
+            .filter(|file| file.version == CACHE_VERSION)
+            .map(|file| file.entries)
+            .unwrap_or_default();
+        Self { path, entries, changed: false }
+    }
+
+    /// The metadata of a model file, from the cache if the file hasn't changed
+    /// since it was cached, otherwise read from its header
+    pub fn get(&mut self, path: &Path) -> Result<GgufMetadata, ModelError> {
+        let file_metadata = std::fs::metadata(path)?;
+        let modified = file_metadata.modified()?;
+        let size = file_metadata.len();
+
+        if let Some(entry) = self.entries.get(path) {
+            if entry.modified == modified && entry.size == size {
+                return Ok(entry.metadata.clone());
+            }
+        }
+
+        let metadata = read_gguf_metadata(path)?;
+        self.entries.insert(path.to_path_buf(), CacheEntry { modified, size, metadata: metadata.clone() });
+        self.changed = true;
+        Ok(metadata)
+    }
+
+    /// Write the cache file if anything was added, dropping entries for files
+    /// that no longer exist
+    pub fn save(&mut self) -> Result<()> {
+        let Some(path) = self.path.as_ref() else {
+            return Ok(());
+        };
+        let before = self.entries.len();
+        self.entries.retain(|model_path, _| model_path.is_file());
+        if !self.changed && self.entries.len() == before {
+            return Ok(());
+        }
+
+        let dir = path.parent().unwrap_or(Path::new("."));
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create directory {:?}", dir))?;
+        let contents = serde_json::to_vec(&serde_json::json!({
+            "version": CACHE_VERSION,
+            "entries": self.entries,
+        }))?;
+
+        let mut temp_file = tempfile::NamedTempFile::new_in(dir)
+            .with_context(|| format!("Failed to create temporary file in {:?}", dir))?;
+        temp_file.write_all(&contents)?;
+        temp_file.persist(path)
+            .with_context(|| format!("Failed to write {:?}", path))?;
+        self.changed = false;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn string(value: &str) -> Vec<u8> {
+        let mut bytes = (value.len() as u64).to_le_bytes().to_vec();
+        bytes.extend_from_slice(value.as_bytes());
+        bytes
+    }
+
+    /// A GGUF v3 header with the given key-value pairs, each value already
+    /// encoded, and one tensor per list of dimensions
+    fn gguf(entries: &[(&str, u32, Vec<u8>)], tensors: &[&[u64]]) -> Vec<u8> {
+        let mut bytes = b"GGUF".to_vec();
+        bytes.extend_from_slice(&3u32.to_le_bytes());
+        bytes.extend_from_slice(&(tensors.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&(entries.len() as u64).to_le_bytes());
+        for (key, value_type, value) in entries {
+            bytes.extend(string(key));
+            bytes.extend_from_slice(&value_type.to_le_bytes());
+            bytes.extend_from_slice(value);
+        }
+        for (i, dimensions) in tensors.iter().enumerate() {
+            bytes.extend(string(&format!("blk.{}.weight", i)));
+            bytes.extend_from_slice(&(dimensions.len() as u32).to_le_bytes());
+            for dimension in *dimensions {
+                bytes.extend_from_slice(&dimension.to_le_bytes());
+            }
+            // Tensor type and data offset
+            bytes.extend_from_slice(&[0; 4 + 8]);
+        }
+        bytes
+    }
+
+    fn string_array(values: &[&str]) -> Vec<u8> {
+        let mut bytes = TYPE_STRING.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&(values.len() as u64).to_le_bytes());
+        for value in values {
+            bytes.extend(string(value));
+        }
+        bytes
+    }
+
+    fn model_header(architecture: &str) -> Vec<u8> {
+        let mut scores = TYPE_FLOAT32.to_le_bytes().to_vec();
+        scores.extend_from_slice(&3u64.to_le_bytes());
+        scores.extend_from_slice(&[0; 3 * 4]);
+        gguf(
+            &[
+                // Before the architecture that says which one applies
+                (&format!("{}.context_length", architecture), TYPE_UINT32, 4096u32.to_le_bytes().to_vec()),
+                ("bert.context_length", TYPE_UINT32, 512u32.to_le_bytes().to_vec()),
+                ("general.name", TYPE_STRING, string("Tiny Test")),
+                ("tokenizer.ggml.tokens", TYPE_ARRAY, string_array(&["<s>", "</s>", "hello"])),
+                ("tokenizer.ggml.scores", TYPE_ARRAY, scores),
+                ("tokenizer.ggml.add_bos_token", TYPE_BOOL, vec![1]),
+                ("general.file_type", TYPE_UINT32, 15u32.to_le_bytes().to_vec()),
+                ("general.architecture", TYPE_STRING, string(architecture)),
+            ],
+            &[&[4096, 32000], &[4096]],
+        )
+    }
+
+    fn write_file(dir: &Path, name: &str, contents: &[u8]) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn reads_header_fields_and_skips_the_rest() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_file(dir.path(), "tiny.gguf", &model_header("llama"));
+
+        let metadata = read_gguf_metadata(&path).unwrap();
+
+        assert_eq!(metadata, GgufMetadata {
+            architecture: Some("llama".to_string()),
+            name: Some("Tiny Test".to_string()),
+            file_type: Some(15),
+            context_length: Some(4096),
+            parameter_count: Some(4096 * 32000 + 4096),
+        });
+        assert_eq!(metadata.quantization().as_deref(), Some("Q4_K_M"));
+    }
+
+    #[test]
+    fn truncated_header_is_invalid() {
+        let dir = tempfile::tempdir().unwrap();
+        let header = model_header("llama");
+        let path = write_file(dir.path(), "truncated.gguf", &header[..header.len() / 2]);
+
+        assert!(matches!(read_gguf_metadata(&path), Err(ModelError::InvalidGgufHeader { .. })));
+    }
+
+    #[test]
+    fn other_files_are_not_gguf() {
+        let dir = tempfile::tempdir().unwrap();
+
+        for (name, contents) in [("model.bin", &b"PK\x03\x04not a model"[..]), ("empty.gguf", &[][..])] {
+            let path = write_file(dir.path(), name, contents);
+            assert!(matches!(read_gguf_metadata(&path), Err(ModelError::NotAGgufFile { .. })), "{}", name);
+        }
+    }
+
+    #[test]
+    fn cache_rereads_files_whose_size_or_mtime_changed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_file(dir.path(), "model.gguf", &model_header("llama"));
+        let mut cache = MetadataCache::default();
+        assert_eq!(cache.get(&path).unwrap().architecture.as_deref(), Some("llama"));
+
+        // A different size is noticed straight away
+        std::fs::write(&path, model_header("phi3")).unwrap();
+        assert_eq!(cache.get(&path).unwrap().architecture.as_deref(), Some("phi3"));
+        let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+
+        // Same size and mtime: the cached entry is trusted
+        std::fs::write(&path, model_header("phi2")).unwrap();
+        let file = File::options().write(true).open(&path).unwrap();
+        file.set_modified(modified).unwrap();
+        assert_eq!(cache.get(&path).unwrap().architecture.as_deref(), Some("phi3"));
+
+        file.set_modified(modified + Duration::from_secs(60)).unwrap();
+        assert_eq!(cache.get(&path).unwrap().architecture.as_deref(), Some("phi2"));
+    }
+}
@@ -1,141 +1,162 @@
 pub mod download;
+pub mod error;
 pub mod inference;
+pub mod metadata;
+pub mod store;
 
-use anyhow::{Context, Result};
+pub use error::ModelError;
+pub use store::{FsModelStore, ModelStore};
+
+use anyhow::Result;
 use sha2::{Digest, Sha256};
 use std::fs::{self, File};
+use serde::Deserialize;
+use std::cmp::Ordering;
 use std::path::{Path, PathBuf};
-use tracing::info;
+use std::time::SystemTime;
+use walkdir::WalkDir;
 
-// Main functions exposed from this module
-pub async fn download_model(model_name: &str, models_dir: &Path) -> Result<()> {
-    download_model_with_options(model_name, models_dir, false).await
-}
+/// How many levels below a models directory are searched for models by
+/// default, counting the file itself: 2 finds `models/llama/llama2-7b.gguf`
+pub const DEFAULT_SCAN_DEPTH: usize = 2;
 
-pub async fn download_model_with_options(model_name: &str, models_dir: &Path, skip_hash: bool) -> Result<()> {
-    let model_info = download::get_model_info(model_name).await?;
-    let model_path = models_dir.join(&model_info.filename);
-    
-    // Check if model already exists
-    if model_path.exists() {
-        info!("Model {} already exists at {:?}", model_name, model_path);
-        
-        if !skip_hash && !model_info.sha256.is_empty() {
-            // Verify hash
-            let file_hash = calculate_file_hash(&model_path)?;
-            if file_hash == model_info.sha256 {
-                info!("Model hash verified successfully");
-                return Ok(());
-            } else {
-                info!("Model hash verification failed, redownloading");
-                fs::remove_file(&model_path)?;
+/// List the `.gguf` files in every model directory, in directory order
+///
+/// Subdirectories are searched up to `max_depth` levels deep, counting the
+/// file itself, so 1 only lists the top level. Directories that don't exist
+/// are skipped, and so are multimodal projectors (see [`is_projector_file`]).
+pub fn list_model_files(models_dirs: &[PathBuf], max_depth: usize) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for models_dir in models_dirs.iter().filter(|dir| dir.is_dir()) {
+        let entries = WalkDir::new(models_dir)
+            .min_depth(1)
+            .max_depth(max_depth)
+            .follow_links(true)
+            .sort_by_file_name();
+        for entry in entries {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_type().is_file()
+                && path.extension().and_then(|ext| ext.to_str()) == Some("gguf")
+                && !is_projector_file(path)
+            {
+                files.push(entry.into_path());
             }
-        } else {
-            info!("Skipping hash verification for existing model");
-            return Ok(());
         }
     }
-    
-    let expected_hash = if skip_hash { String::new() } else { model_info.sha256 };
-    download::download_model_file(&model_info.download_url, &model_path, &expected_hash).await?;
-    info!("Model {} downloaded successfully to {:?}", model_name, model_path);
-    Ok(())
+    Ok(files)
 }
 
-pub async fn list_models(models_dir: &Path) -> Result<()> {
-    info!("Listing models in {:?}", models_dir);
-    
-    // Ensure the directory exists
-    if !models_dir.exists() {
-        println!("Models directory does not exist. No models available.");
-        return Ok(());
-    }
-    
-    // List local models
-    let mut models_found = false;
-    println!("Available local models:");
-    
-    for entry in fs::read_dir(models_dir)? {
-        let entry = entry?;
-        let path = entry.path();
-        
-        if path.is_file() && path.extension().and_then(|ext| ext.to_str()) == Some("gguf") {
-            if let Some(model_name) = path.file_name().and_then(|n| n.to_str()) {
-                let size_bytes = entry.metadata()?.len();
-                let size_mb = size_bytes as f64 / 1_048_576.0;
-                
-                println!("- {} ({:.2} MB)", model_name, size_mb);
-                models_found = true;
-            }
-        }
-    }
-    
-    if !models_found {
-        println!("No models found. Use 'rustllm model pull <model>' to download a model.");
+/// Whether a `.gguf` file is a multimodal projector rather than a model, going
+/// by the `mmproj` llama.cpp's conversion scripts put in their names
+pub fn is_projector_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.to_lowercase().contains("mmproj"))
+}
+
+/// The multimodal projector next to a model file, if there is exactly one
+///
+/// With several in the directory it's unclear which belongs to the model, so
+/// none is picked.
+#[cfg_attr(not(feature = "vision"), allow(dead_code))]
+pub fn find_projector(model_path: &Path) -> Option<PathBuf> {
+    let dir = model_path.parent()?;
+    let mut projectors: Vec<PathBuf> = fs::read_dir(dir).ok()?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "gguf") && is_projector_file(path))
+        .collect();
+    if projectors.len() > 1 {
+        tracing::warn!(
+            "Found {} projectors next to {:?}, not loading any; keep each vision model in its own directory",
+            projectors.len(),
+            model_path
+        );
+        return None;
     }
-    
-    // List available models to download (from a hypothetical registry)
-    println!("\nModels available for download:");
-    println!("- llama2-7b.Q4_K_M.gguf");
-    println!("- mistral-7b.Q4_K_M.gguf");
-    println!("- phi-2.Q4_K_M.gguf");
-    println!("- neural-chat-7b.Q4_K_M.gguf");
-    
-    Ok(())
+    projectors.pop()
 }
 
-pub async fn delete_model(model_name: &str, models_dir: &Path) -> Result<()> {
-    let model_path = find_model_path(model_name, models_dir)?;
-    
-    // Delete the file
-    fs::remove_file(&model_path)
-        .with_context(|| format!("Failed to delete model file at {:?}", model_path))?;
-    
-    info!("Model {} deleted successfully", model_name);
-    println!("Model {} deleted successfully", model_name);
-    
-    Ok(())
+/// The name a model file is listed under: its path relative to the models
+/// directory containing it, with `/` separators so `llama/llama2-7b.gguf`
+/// reads the same on every platform
+pub fn model_display_name(path: &Path, models_dirs: &[PathBuf]) -> String {
+    let relative = models_dirs
+        .iter()
+        .find_map(|dir| path.strip_prefix(dir).ok())
+        .unwrap_or(path);
+    relative
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
 }
 
-// Helper functions
-fn find_model_path(model_name: &str, models_dir: &Path) -> Result<PathBuf> {
-    // Check if the exact filename exists
-    let exact_path = models_dir.join(model_name);
-    if exact_path.exists() {
-        return Ok(exact_path);
-    }
-    
-    // Check if model_name with .gguf extension exists
-    let with_extension = if model_name.ends_with(".gguf") {
-        models_dir.join(model_name)
-    } else {
-        models_dir.join(format!("{}.gguf", model_name))
-    };
-    
-    if with_extension.exists() {
-        return Ok(with_extension);
-    }
-    
-    // Try to find a partial match
-    for entry in fs::read_dir(models_dir)? {
-        let entry = entry?;
-        let path = entry.path();
-        
-        if path.is_file() {
-            if let Some(file_name) = path.file_name().and_then(|n| n.to_str()) {
-                if file_name.contains(model_name) {
-                    return Ok(path);
-                }
-            }
+/// What model listings are sorted by
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ModelSort {
+    #[default]
+    Name,
+    Size,
+    /// Last modification time of the file
+    Modified,
+}
+
+/// Direction of a model listing sort
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// Sort a model listing; `key` gives each entry's name, size and modification
+/// time. The sort is stable and ties stay in name order in both directions.
+/// Entries with an unknown modification time sort as the oldest.
+pub fn sort_models<T>(
+    models: &mut [T],
+    sort: ModelSort,
+    order: SortOrder,
+    key: impl Fn(&T) -> (&str, u64, Option<SystemTime>),
+) {
+    models.sort_by(|a, b| key(a).0.cmp(key(b).0));
+    let compare = |a: &T, b: &T| -> Ordering {
+        let (a, b) = (key(a), key(b));
+        match sort {
+            ModelSort::Name => a.0.cmp(b.0),
+            ModelSort::Size => a.1.cmp(&b.1),
+            ModelSort::Modified => a.2.cmp(&b.2),
         }
+    };
+    match order {
+        SortOrder::Asc => models.sort_by(compare),
+        SortOrder::Desc => models.sort_by(|a, b| compare(b, a)),
     }
-    
-    anyhow::bail!("Model {} not found in {:?}", model_name, models_dir)
 }
 
-pub fn calculate_file_hash(file_path: &Path) -> Result<String> {
+/// The directory new downloads go to: the first one that is writable
+///
+/// Falls back to the first directory so the error surfaces when writing.
+pub fn download_dir(models_dirs: &[PathBuf]) -> &Path {
+    models_dirs
+        .iter()
+        .find(|dir| fs::metadata(dir).map(|m| !m.permissions().readonly()).unwrap_or(false))
+        .or_else(|| models_dirs.first())
+        .map(PathBuf::as_path)
+        .expect("at least one models directory")
+}
+
+/// Calculate the SHA256 of a file, calling `on_progress` with the total number
+/// of bytes hashed so far after each chunk
+pub fn calculate_file_hash_with_progress(
+    file_path: &Path,
+    mut on_progress: impl FnMut(u64),
+) -> Result<String> {
     let mut file = File::open(file_path)?;
     let mut hasher = Sha256::new();
+    let mut hashed_bytes = 0u64;
     
     let mut buffer = [0; 1024 * 1024]; // 1MB buffer
     loop {
@@ -144,6 +165,8 @@ pub fn calculate_file_hash(file_path: &Path) -> Result<String> {
             break;
         }
         hasher.update(&buffer[..bytes_read]);
+        hashed_bytes += bytes_read as u64;
+        on_progress(hashed_bytes);
     }
     
     let hash = hasher.finalize();
@@ -151,7 +174,32 @@ pub fn calculate_file_hash(file_path: &Path) -> Result<String> {
 }
 
 // Load a model for inference
-pub fn load_model(model_name: &str, models_dir: &Path) -> Result<inference::Model> {
-    let model_path = find_model_path(model_name, models_dir)?;
-    inference::Model::load(&model_path)
+pub fn load_model(
+    model_name: &str,
+    store: &dyn ModelStore,
+    config: inference::ModelConfig,
+) -> Result<inference::Model, ModelError> {
+    let model_path = store.resolve(model_name)?;
+    #[cfg(feature = "vision")]
+    let config = inference::ModelConfig {
+        mmproj_path: config.mmproj_path.or_else(|| find_projector(&model_path)),
+        ..config
+    };
+    let stop_tokens = config.stop_tokens.clone();
+
+    #[cfg(feature = "mock")]
+    let mut model = if std::env::var("RUSTLLM_MOCK_MODEL").is_ok_and(|value| value == "1") {
+        inference::Model::load_mock(&model_path, config)
+    } else {
+        inference::Model::load_with_config(&model_path, config)?
+    };
+    #[cfg(not(feature = "mock"))]
+    let mut model = inference::Model::load_with_config(&model_path, config)?;
+
+    let file_name = model_path.file_name().and_then(|n| n.to_str()).unwrap_or(model_name);
+    model.set_template(inference::PromptTemplate::guess_from_filename(file_name));
+    for id in stop_tokens {
+        model.add_stop_token(id);
+    }
+    Ok(model)
 }
\ No newline at end of file
@@ -0,0 +1,243 @@
+//! Where model files are kept
+//!
+//! Model management goes through [`ModelStore`] instead of touching the models
+//! directories directly, so name resolution and listing can be tested against
+//! an in-memory store.
+
+use super::{list_model_files, model_display_name, ModelError};
+use std::path::{Component, Path, PathBuf};
+
+/// A set of models directories and the `.gguf` files in them
+pub trait ModelStore: Send + Sync {
+    /// Directories models are read from, in priority order
+    fn dirs(&self) -> &[PathBuf];
+
+    /// Every model file, in directory order
+    fn list(&self) -> std::io::Result<Vec<PathBuf>>;
+
+    /// Whether there is a model file at `path`
+    fn exists(&self, path: &Path) -> bool;
+
+    /// Delete the model file at `path`, and the revision recorded for it
+    fn delete(&self, path: &Path) -> std::io::Result<()>;
+
+    /// Find a model path from a model name
+    ///
+    /// Tries the exact filename (or relative path), then the name with a
+    /// `.gguf` extension, in each directory in order, then a model whose name
+    /// contains `model_name`. A partial match must be unique. Names with `..`,
+    /// a root or a prefix are never found, so they can't reach files outside
+    /// the models directories.
+    fn resolve(&self, model_name: &str) -> Result<PathBuf, ModelError> {
+        if !Path::new(model_name).components().all(|component| matches!(component, Component::Normal(_))) {
+            return Err(ModelError::NotFound {
+                name: model_name.to_string(),
+                dirs: self.dirs().to_vec(),
+            });
+        }
+
+        let with_extension = if model_name.ends_with(".gguf") {
+            model_name.to_string()
+        } else {
+            format!("{}.gguf", model_name)
+        };
+
+        for models_dir in self.dirs() {
+            // Check if the exact filename exists
+            let exact_path = models_dir.join(model_name);
+            if self.exists(&exact_path) {
+                return Ok(exact_path);
+            }
+
+            // Check if model_name with .gguf extension exists
+            let with_extension = models_dir.join(&with_extension);
+            if self.exists(&with_extension) {
+                return Ok(with_extension);
+            }
+        }
+
+        // Try to find a partial match
+        let mut matches: Vec<PathBuf> = self.list()?
+            .into_iter()
+            .filter(|path| model_display_name(path, self.dirs()).contains(model_name))
+            .collect();
+
+        match matches.len() {
+            0 => Err(ModelError::NotFound {
+                name: model_name.to_string(),
+                dirs: self.dirs().to_vec(),
+            }),
+            1 => Ok(matches.remove(0)),
+            _ => {
+                let mut candidates: Vec<String> = matches
+                    .iter()
+                    .map(|p| p.display().to_string())
+                    .collect();
+                candidates.sort();
+                Err(ModelError::MultipleMatches {
+                    name: model_name.to_string(),
+                    candidates,
+                })
+            }
+        }
+    }
+}
+
+/// Models directories on disk, searched `scan_depth` levels deep
+#[derive(Debug, Clone)]
+pub struct FsModelStore {
+    dirs: Vec<PathBuf>,
+    scan_depth: usize,
+}
+
+impl FsModelStore {
+    /// A store over `dirs`, listing models up to `scan_depth` levels below
+    /// each of them (see [`list_model_files`])
+    pub fn new(dirs: Vec<PathBuf>, scan_depth: usize) -> Self {
+        Self { dirs, scan_depth }
+    }
+}
+
+impl ModelStore for FsModelStore {
+    fn dirs(&self) -> &[PathBuf] {
+        &self.dirs
+    }
+
+    fn list(&self) -> std::io::Result<Vec<PathBuf>> {
+        list_model_files(&self.dirs, self.scan_depth)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn delete(&self, path: &Path) -> std::io::Result<()> {
+        std::fs::remove_file(path)?;
+        super::download::store_revision(path, None)
+    }
+}
+
+/// A store whose files only exist in memory, for tests
+#[cfg(test)]
+pub struct MemoryModelStore {
+    dirs: Vec<PathBuf>,
+    files: std::sync::Mutex<std::collections::BTreeSet<PathBuf>>,
+}
+
+#[cfg(test)]
+impl MemoryModelStore {
+    /// A store over `dirs` holding `files`, given relative to the first directory
+    pub fn new(dirs: &[&str], files: &[&str]) -> Self {
+        let dirs: Vec<PathBuf> = dirs.iter().map(PathBuf::from).collect();
+        let files = files.iter().map(|file| dirs[0].join(file)).collect();
+        Self { dirs, files: std::sync::Mutex::new(files) }
+    }
+
+    /// Add a file, given by its full path
+    pub fn add(&self, path: impl Into<PathBuf>) {
+        self.files.lock().unwrap().insert(path.into());
+    }
+}
+
+#[cfg(test)]
+impl ModelStore for MemoryModelStore {
+    fn dirs(&self) -> &[PathBuf] {
+        &self.dirs
+    }
+
+    fn list(&self) -> std::io::Result<Vec<PathBuf>> {
+        let files = self.files.lock().unwrap();
+        Ok(self.dirs.iter()
+            .flat_map(|dir| files.iter().filter(move |path| path.starts_with(dir)))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "gguf") && !super::is_projector_file(path))
+            .cloned()
+            .collect())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.files.lock().unwrap().contains(path)
+    }
+
+    fn delete(&self, path: &Path) -> std::io::Result<()> {
+        if self.files.lock().unwrap().remove(path) {
+            Ok(())
+        } else {
+            Err(std::io::ErrorKind::NotFound.into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_exact_name() {
+        let store = MemoryModelStore::new(&["/models"], &["mistral-7b.gguf", "mistral-7b-instruct.gguf"]);
+
+        assert_eq!(store.resolve("mistral-7b.gguf").unwrap(), Path::new("/models/mistral-7b.gguf"));
+    }
+
+    #[test]
+    fn resolves_name_without_extension() {
+        let store = MemoryModelStore::new(&["/models"], &["mistral-7b.gguf", "mistral-7b-instruct.gguf"]);
+
+        assert_eq!(store.resolve("mistral-7b").unwrap(), Path::new("/models/mistral-7b.gguf"));
+    }
+
+    #[test]
+    fn resolves_unique_partial_name() {
+        let store = MemoryModelStore::new(&["/models"], &["llama/llama2-7b.gguf", "phi3.gguf"]);
+
+        assert_eq!(store.resolve("llama2").unwrap(), Path::new("/models/llama/llama2-7b.gguf"));
+    }
+
+    #[test]
+    fn ambiguous_partial_name_lists_candidates() {
+        let store = MemoryModelStore::new(&["/models"], &["llama2-7b.gguf", "llama2-13b.gguf"]);
+
+        match store.resolve("llama2") {
+            Err(ModelError::MultipleMatches { candidates, .. }) => {
+                assert_eq!(candidates, ["/models/llama2-13b.gguf", "/models/llama2-7b.gguf"]);
+            }
+            other => panic!("expected MultipleMatches, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn projector_is_not_a_model() {
+        let store = MemoryModelStore::new(&["/models"], &["llava/llava-7b.gguf", "llava/mmproj-llava-7b-f16.gguf"]);
+
+        assert_eq!(store.resolve("llava").unwrap(), Path::new("/models/llava/llava-7b.gguf"));
+    }
+
+    #[test]
+    fn earlier_directory_wins() {
+        let store = MemoryModelStore::new(&["/fast", "/slow"], &[]);
+        store.add("/slow/phi3.gguf");
+        store.add("/fast/phi3.gguf");
+
+        assert_eq!(store.resolve("phi3").unwrap(), Path::new("/fast/phi3.gguf"));
+    }
+
+    #[test]
+    fn names_outside_the_models_dir_are_not_found() {
+        let store = MemoryModelStore::new(&["/models"], &["phi3.gguf"]);
+        store.add("/x.gguf");
+        store.add("/abs.gguf");
+
+        for name in ["../x", "../x.gguf", "/abs", "/abs.gguf", "sub/../../x"] {
+            assert!(matches!(store.resolve(name), Err(ModelError::NotFound { .. })), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn deleted_model_is_not_found() {
+        let store = MemoryModelStore::new(&["/models"], &["phi3.gguf"]);
+
+        store.delete(Path::new("/models/phi3.gguf")).unwrap();
+
+        assert!(matches!(store.resolve("phi3"), Err(ModelError::NotFound { .. })));
+        assert!(store.list().unwrap().is_empty());
+    }
+}
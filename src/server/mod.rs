@@ -1,79 +1,838 @@
 //! Server module for the Rust-based LLM chat tool
 
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
-    response::{IntoResponse, Response},
+    extract::{ConnectInfo, Extension, Path, Query, Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Html, IntoResponse, Response,
+    },
     routing::{delete, get, post},
     Json, Router,
 };
 use serde::{Deserialize, Serialize};
+use notify::{
+    event::{ModifyKind, RenameMode},
+    EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+};
+use futures::{
+    future::{BoxFuture, Shared},
+    FutureExt, Stream, StreamExt,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_util::sync::CancellationToken;
 use std::{
     collections::HashMap,
-    net::SocketAddr,
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
     path::{Path as FilePath, PathBuf},
-    sync::{Arc, Mutex},
+    sync::{mpsc, Arc, Mutex, RwLock, Weak},
+    time::{Duration, Instant},
 };
-use tracing::info;
+use tracing::{info, info_span, warn, Instrument};
 
 use crate::model::{
     self,
-    inference::{ChatContext, ChatMessage, ChatRole, Model},
+    download::{DownloadConfig, DownloadProgress},
+    inference::{
+        ChatContext, ChatMessage, ChatRole, Completion, FinishReason, MemoryFootprint, Model, ModelConfig,
+        PromptTemplate, SamplerStep, SequenceEvent, SequenceJob, IMAGE_MARKER, JSON_GRAMMAR,
+    },
+    ModelError, ModelStore,
 };
 
 /// Server state shared across all connections
 #[derive(Clone)]
 struct AppState {
-    /// Path to the models directory
-    models_dir: PathBuf,
+    /// Model files, read from the models directories in priority order
+    store: Arc<dyn ModelStore>,
     /// Cache of loaded models to avoid reloading between requests
-    models: Arc<Mutex<HashMap<String, Arc<Mutex<Model>>>>>,
+    models: Arc<Mutex<HashMap<String, CachedModel>>>,
+    /// Loads in progress, shared by every request for a model that isn't cached yet
+    loading: Arc<Mutex<HashMap<String, ModelLoad>>>,
+    /// Options used for model downloads
+    download_config: DownloadConfig,
+    /// Configuration applied when loading models
+    model_config: ModelConfig,
+    /// Upper bound on `n` in chat requests
+    max_completions: usize,
+    /// Last scan of the models directories, reused by `GET /api/models`
+    model_list: Arc<Mutex<Option<ModelListCache>>>,
+    /// How long a model list scan is reused for
+    model_list_ttl: Duration,
+    /// Model used by requests that leave out `model`, swapped on SIGHUP
+    default_model: Arc<RwLock<Option<String>>>,
+    /// Whether a reload re-reads `default_model` (not when it came from the command line)
+    reload_default_model: bool,
+    /// Cancellation tokens of in-flight generations, keyed by request id
+    generations: Arc<Mutex<HashMap<String, CancellationToken>>>,
+    /// Slots every generation holds while it runs, bounding concurrent
+    /// inference across all models
+    generation_slots: Arc<Semaphore>,
+    max_concurrent_generations: usize,
+    generation_queue_timeout: Option<Duration>,
+    /// Model downloads started through the API, keyed by job id
+    downloads: Arc<Mutex<HashMap<String, DownloadJob>>>,
+    /// Nice value applied to threads that generate (see `ServerOptions::nice`)
+    nice: Option<i32>,
+    /// Fetch http(s) image URLs in chat requests (see `ServerOptions::allow_remote_images`)
+    allow_remote_images: bool,
+    /// How long models stay loaded after their last use, unless the last
+    /// request set `keep_alive` (None = forever)
+    model_idle_timeout: Option<Duration>,
+    /// Cancelled to stop the server, by a signal or `POST /api/shutdown`
+    shutdown: CancellationToken,
+}
+
+/// A generation that `/api/chat/cancel` can stop, unregistered when dropped
+struct ActiveGeneration {
+    generations: Arc<Mutex<HashMap<String, CancellationToken>>>,
+    request_id: String,
+    token: CancellationToken,
+}
+
+impl Drop for ActiveGeneration {
+    fn drop(&mut self) {
+        // Leave the entry alone if it belongs to another generation by now
+        let mut generations = self.generations.lock().unwrap();
+        if generations.get(&self.request_id) == Some(&self.token) {
+            generations.remove(&self.request_id);
+        }
+    }
+}
+
+/// A scan of the models directories
+struct ModelListCache {
+    models: Vec<ModelInfo>,
+    scanned_at: Instant,
+}
+
+impl AppState {
+    /// Forget the cached model list after the models on disk have changed
+    fn invalidate_model_list(&self) {
+        *self.model_list.lock().unwrap() = None;
+    }
+
+    /// Make the generation for `request_id` cancellable until the returned
+    /// guard is dropped
+    ///
+    /// Fails with 409 if a generation with the same request id is still
+    /// running, since `/api/chat/cancel` couldn't tell them apart.
+    fn register_generation(&self, request_id: &str) -> Result<ActiveGeneration, ApiError> {
+        let token = CancellationToken::new();
+        let mut generations = self.generations.lock().unwrap();
+        if generations.contains_key(request_id) {
+            return Err(ApiError::new(
+                StatusCode::CONFLICT,
+                format!("A generation for request {} is already in progress", request_id),
+            ));
+        }
+        generations.insert(request_id.to_string(), token.clone());
+        Ok(ActiveGeneration {
+            generations: Arc::clone(&self.generations),
+            request_id: request_id.to_string(),
+            token,
+        })
+    }
+
+    /// Wait for a free generation slot, failing with 503 if none frees up
+    /// within the queue timeout
+    ///
+    /// The slot is released when the returned permit is dropped, so move it
+    /// into the blocking task doing the generation.
+    async fn acquire_generation_slot(&self) -> Result<OwnedSemaphorePermit, ApiError> {
+        let acquire = Arc::clone(&self.generation_slots).acquire_owned();
+        let permit = match self.generation_queue_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, acquire).await.map_err(|_| ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("Timed out after {:?} waiting for a free generation slot", timeout),
+            ))?,
+            None => acquire.await,
+        };
+        Ok(permit.expect("the generation semaphore is never closed"))
+    }
+
+    /// Generations currently holding a slot
+    fn generations_in_flight(&self) -> usize {
+        self.max_concurrent_generations - self.generation_slots.available_permits()
+    }
+
+    /// Queue of a cached model's sequence worker, if it runs parallel sequences
+    fn sequence_queue(&self, model_name: &str) -> Option<mpsc::Sender<SequenceJob>> {
+        self.models.lock().unwrap().get(model_name).and_then(|cached| cached.sequences.clone())
+    }
+
+    /// Fail with 400 unless the cached model `model_name` can read images
+    fn check_image_support(&self, model_name: &str) -> Result<(), ApiError> {
+        self.models.lock().unwrap().get(model_name).map_or(Ok(()), |cached| cached.image_support.clone())
+    }
+
+    /// The model a request asked for, or the default model if it left it out
+    fn resolve_model(&self, requested: &str) -> Result<String, ApiError> {
+        if !requested.is_empty() {
+            return Ok(requested.to_string());
+        }
+        self.default_model.read().unwrap().clone().ok_or_else(|| ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "No model given and the server has no default model",
+        ))
+    }
+}
+
+/// Options for the API server
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    /// Evict cached models whose file is removed from the models directory
+    pub watch_models: bool,
+    /// Maximum number of completions a single chat request may ask for
+    pub max_completions: usize,
+    /// Unload cached models that haven't been used for this long (None = never)
+    pub model_idle_timeout: Option<Duration>,
+    /// Serve the browser chat UI at `/`
+    pub ui: bool,
+    /// How long `GET /api/models` reuses a scan of the models directories
+    pub model_list_ttl: Duration,
+    /// Model used by requests that leave out `model`
+    pub default_model: Option<String>,
+    /// Generations allowed to run at once across all models
+    pub max_concurrent_generations: usize,
+    /// How long a generation waits for a free slot before failing (None = forever)
+    pub generation_queue_timeout: Option<Duration>,
+    /// Listen on this Unix domain socket instead of `host:port`
+    pub unix_socket: Option<PathBuf>,
+    /// Re-read `default_model` from the config file on SIGHUP; off when a flag
+    /// or environment variable chose it
+    pub reload_default_model: bool,
+    /// Requests per minute allowed from each client IP (None = unlimited)
+    pub rate_limit: Option<u32>,
+    /// Take the client IP from `X-Forwarded-For` set by a reverse proxy
+    pub trust_proxy: bool,
+    /// Nice value generation threads run at, so inference yields to other work
+    pub nice: Option<i32>,
+    /// Accept `POST /api/shutdown`, which stops the server
+    pub allow_remote_shutdown: bool,
+    /// Fetch http(s) image URLs in chat requests; without it only `data:`
+    /// URIs are accepted, so clients can't make the server fetch arbitrary
+    /// addresses
+    pub allow_remote_images: bool,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        Self {
+            watch_models: false,
+            max_completions: 4,
+            model_idle_timeout: None,
+            ui: false,
+            model_list_ttl: Duration::from_secs(5),
+            default_model: None,
+            max_concurrent_generations: num_cpus::get(),
+            generation_queue_timeout: None,
+            unix_socket: None,
+            reload_default_model: false,
+            rate_limit: None,
+            trust_proxy: false,
+            nice: None,
+            allow_remote_shutdown: false,
+            allow_remote_images: false,
+        }
+    }
+}
+
+/// A model load that any number of requests can wait on
+type ModelLoad = Shared<BoxFuture<'static, Result<Arc<Mutex<Model>>, ApiError>>>;
+
+/// A loaded model in the server cache
+struct CachedModel {
+    /// The loaded model, shared with in-flight requests
+    model: Arc<Mutex<Model>>,
+    /// File the model was loaded from
+    path: PathBuf,
+    /// Estimated when loaded, since the model is locked while it generates
+    memory: MemoryFootprint,
+    context_size: usize,
+    loaded_at: chrono::DateTime<chrono::Utc>,
+    /// When the model was last loaded, looked up or used for generation
+    last_used: Instant,
+    /// How long to keep the model after `last_used`, as the last request that
+    /// used it asked (None = the server's `--model-idle-timeout-secs`)
+    keep_alive: Option<ModelKeepAlive>,
+    /// Queue of the model's sequence worker, for models loaded with more than
+    /// one sequence (see `spawn_sequence_worker`)
+    sequences: Option<mpsc::Sender<SequenceJob>>,
+    /// Whether the model can read images, checked on load so requests with
+    /// images are turned away before anything is fetched
+    image_support: Result<(), ApiError>,
+}
+
+impl CachedModel {
+    /// How long the model stays loaded after its last use (None = forever)
+    fn idle_timeout(&self, default: Option<Duration>) -> Option<Duration> {
+        match self.keep_alive {
+            Some(ModelKeepAlive::Forever) => None,
+            Some(ModelKeepAlive::For(duration)) => Some(duration),
+            None => default,
+        }
+    }
+}
+
+/// How long a request asks for its model to stay loaded, as in Ollama's API
+///
+/// Given as seconds or a duration string like `"5m"` or `"1h30m"`; `0`
+/// unloads the model as soon as the request is done and any negative value
+/// keeps it loaded until the server stops.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "KeepAliveValue")]
+enum ModelKeepAlive {
+    Forever,
+    For(Duration),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum KeepAliveValue {
+    Seconds(f64),
+    Text(String),
+}
+
+impl TryFrom<KeepAliveValue> for ModelKeepAlive {
+    type Error = String;
+
+    fn try_from(value: KeepAliveValue) -> Result<Self, Self::Error> {
+        let seconds = match value {
+            KeepAliveValue::Seconds(seconds) => seconds,
+            KeepAliveValue::Text(text) => match text.trim().parse::<f64>() {
+                Ok(seconds) => seconds,
+                Err(_) => {
+                    let text = text.trim();
+                    return match text.strip_prefix('-') {
+                        Some(rest) if !parse_duration(rest)?.is_zero() => Ok(ModelKeepAlive::Forever),
+                        Some(_) => Ok(ModelKeepAlive::For(Duration::ZERO)),
+                        None => parse_duration(text).map(ModelKeepAlive::For),
+                    };
+                },
+            },
+        };
+        if seconds < 0.0 {
+            Ok(ModelKeepAlive::Forever)
+        } else {
+            Duration::try_from_secs_f64(seconds)
+                .map(ModelKeepAlive::For)
+                .map_err(|e| format!("invalid keep_alive {}: {}", seconds, e))
+        }
+    }
+}
+
+/// Parse a duration like `"90s"`, `"5m"` or `"1h30m"` (units `ms`, `s`, `m` and `h`)
+fn parse_duration(text: &str) -> Result<Duration, String> {
+    let invalid = || format!("invalid keep_alive {:?}, expected seconds or a duration like \"5m\"", text);
+    if text.is_empty() {
+        return Err(invalid());
+    }
+    let mut total = Duration::ZERO;
+    let mut rest = text;
+    while !rest.is_empty() {
+        let number_end = rest.find(|c: char| !(c.is_ascii_digit() || c == '.')).ok_or_else(invalid)?;
+        let (number, tail) = rest.split_at(number_end);
+        let number: f64 = number.parse().map_err(|_| invalid())?;
+        let unit_end = tail.find(|c: char| c.is_ascii_digit() || c == '.').unwrap_or(tail.len());
+        let (unit, tail) = tail.split_at(unit_end);
+        let unit_seconds = match unit {
+            "ms" => 0.001,
+            "s" => 1.0,
+            "m" => 60.0,
+            "h" => 3600.0,
+            _ => return Err(invalid()),
+        };
+        total += Duration::try_from_secs_f64(number * unit_seconds).map_err(|_| invalid())?;
+        rest = tail;
+    }
+    Ok(total)
 }
 
 /// Start the API server on the specified host and port
-pub async fn start_server(host: String, port: u16, models_dir: PathBuf) -> anyhow::Result<()> {
+pub async fn start_server(
+    host: String,
+    port: u16,
+    store: Arc<dyn ModelStore>,
+    download_config: DownloadConfig,
+    model_config: ModelConfig,
+    options: ServerOptions,
+) -> anyhow::Result<()> {
     // Create shared state
     let state = AppState {
-        models_dir,
+        store,
         models: Arc::new(Mutex::new(HashMap::new())),
+        loading: Arc::new(Mutex::new(HashMap::new())),
+        download_config,
+        model_config,
+        max_completions: options.max_completions,
+        model_list: Arc::new(Mutex::new(None)),
+        model_list_ttl: options.model_list_ttl,
+        default_model: Arc::new(RwLock::new(options.default_model)),
+        reload_default_model: options.reload_default_model,
+        generations: Arc::new(Mutex::new(HashMap::new())),
+        generation_slots: Arc::new(Semaphore::new(options.max_concurrent_generations)),
+        max_concurrent_generations: options.max_concurrent_generations,
+        generation_queue_timeout: options.generation_queue_timeout,
+        downloads: Arc::new(Mutex::new(HashMap::new())),
+        nice: options.nice,
+        allow_remote_images: options.allow_remote_images,
+        model_idle_timeout: options.model_idle_timeout,
+        shutdown: CancellationToken::new(),
+    };
+
+    if !cfg!(feature = "inference") {
+        warn!("Built without inference support, chat endpoints will return 501");
+    }
+
+    spawn_idle_unloader(state.clone());
+
+    #[cfg(unix)]
+    spawn_sighup_reloader(state.clone())?;
+
+    // Keep the watcher alive for as long as the server runs
+    let _watcher = if options.watch_models {
+        Some(watch_models_dir(state.clone())?)
+    } else {
+        None
     };
 
     // Build router with routes
-    let app = Router::new()
+    let mut router = Router::new()
         // Model endpoints
         .route("/api/models", get(list_models))
         .route("/api/models/:model_name", get(get_model_info))
         .route("/api/models/:model_name", post(download_model))
         .route("/api/models/:model_name", delete(delete_model))
+        .route("/api/downloads/:id", get(get_download))
+        .route("/api/registry/reload", post(reload_registry))
         // Chat endpoints
         .route("/api/chat", post(chat))
         .route("/api/chat/stream", post(chat_stream))
+        .route("/api/chat/cancel", post(cancel_chat))
+        .route("/api/completions", post(completions))
+        .route("/api/preview", post(preview))
+        .route("/api/ps", get(list_running_models))
         // Health check
-        .route("/api/health", get(health_check))
+        .route("/api/health", get(health_check));
+    if options.ui {
+        router = router.route("/", get(web_ui));
+    }
+    if options.allow_remote_shutdown {
+        router = router.route("/api/shutdown", post(shutdown_server));
+    }
+    if let Some(per_minute) = options.rate_limit {
+        let limiter = Arc::new(RateLimiter::new(per_minute, options.trust_proxy));
+        router = router.layer(middleware::from_fn_with_state(limiter, rate_limit));
+    }
+    let shutdown = shutdown_signal(state.clone());
+    let app = router
+        .layer(middleware::from_fn(access_log))
+        .layer(middleware::from_fn(request_id))
         .with_state(state);
 
+    if let Some(socket_path) = &options.unix_socket {
+        return serve_unix(socket_path, app, options.ui, shutdown).await;
+    }
+
     // Parse the address and start the server
     let addr = format!("{}:{}", host, port).parse::<SocketAddr>()?;
     info!("Server listening on http://{}", addr);
+    if options.ui {
+        info!("Chat UI available at http://{}/", addr);
+    }
     
     let listener = tokio::net::TcpListener::bind(&addr).await?;
-    axum::serve(listener, app).await?;
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown)
+        .await?;
+
+    Ok(())
+}
+
+/// Serve the router on a Unix domain socket until `shutdown` resolves, then
+/// remove the socket file
+#[cfg(unix)]
+async fn serve_unix(
+    socket_path: &FilePath,
+    app: Router,
+    ui: bool,
+    shutdown: impl std::future::Future<Output = ()>,
+) -> anyhow::Result<()> {
+    use hyper_util::{
+        rt::{TokioExecutor, TokioIo},
+        server::conn::auto::Builder,
+        service::TowerToHyperService,
+    };
+    use std::os::unix::fs::FileTypeExt;
+
+    // A socket left behind by a crashed server blocks bind, but one that still
+    // accepts connections belongs to a running server
+    if let Ok(metadata) = std::fs::symlink_metadata(socket_path) {
+        if !metadata.file_type().is_socket() {
+            anyhow::bail!("{} exists and is not a socket", socket_path.display());
+        }
+        if tokio::net::UnixStream::connect(socket_path).await.is_ok() {
+            anyhow::bail!("{} is in use by another server", socket_path.display());
+        }
+        std::fs::remove_file(socket_path)?;
+        info!("Removed stale socket {}", socket_path.display());
+    }
+
+    let listener = tokio::net::UnixListener::bind(socket_path)?;
+    info!("Server listening on unix:{}", socket_path.display());
+    if ui {
+        info!("Chat UI available at / on unix:{}", socket_path.display());
+    }
+
+    tokio::pin!(shutdown);
+    let result = loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => break Err(e.into()),
+            },
+            _ = &mut shutdown => break Ok(()),
+        };
+        let service = TowerToHyperService::new(app.clone());
+        tokio::spawn(async move {
+            if let Err(e) = Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                .await
+            {
+                warn!("Error serving unix socket connection: {}", e);
+            }
+        });
+    };
+
+    if let Err(e) = std::fs::remove_file(socket_path) {
+        warn!("Failed to remove socket {}: {}", socket_path.display(), e);
+    }
+    result
+}
+
+#[cfg(not(unix))]
+async fn serve_unix(
+    _socket_path: &FilePath,
+    _app: Router,
+    _ui: bool,
+    _shutdown: impl std::future::Future<Output = ()>,
+) -> anyhow::Result<()> {
+    anyhow::bail!("--unix-socket is only supported on Unix platforms")
+}
+
+/// Resolve once the server should stop: on ctrl-c, SIGTERM on Unix, or
+/// `POST /api/shutdown`
+///
+/// Generations in flight are cancelled then, since their streams would
+/// otherwise hold the connections open until they finish.
+async fn shutdown_signal(state: AppState) {
+    tokio::select! {
+        _ = termination_signal() => state.shutdown.cancel(),
+        _ = state.shutdown.cancelled() => {}
+    }
+    info!("Shutting down");
+    for token in state.generations.lock().unwrap().values() {
+        token.cancel();
+    }
+}
 
+/// Resolve on ctrl-c or SIGTERM
+#[cfg(unix)]
+async fn termination_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+        }
+        Err(e) => {
+            warn!("Failed to listen for SIGTERM: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+        }
+    }
+}
+
+/// Resolve on ctrl-c
+#[cfg(not(unix))]
+async fn termination_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Reload settings read at startup each time the process gets SIGHUP, leaving
+/// cached models and open connections alone
+#[cfg(unix)]
+fn spawn_sighup_reloader(state: AppState) -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            info!("Received SIGHUP, reloading configuration");
+            if let Err(e) = reload_config(&state) {
+                warn!("Keeping current settings: {:#}", e);
+            }
+        }
+    });
     Ok(())
 }
 
+/// What a configuration reload changed
+#[derive(Serialize)]
+struct RegistryReload {
+    /// Models that can be pulled by name without asking a remote registry
+    builtin_models: usize,
+    /// Remote registry entries dropped, to be fetched again on next use
+    cleared_registry_entries: usize,
+    /// Model used by requests that leave out `model`
+    default_model: Option<String>,
+}
+
+/// Re-read the config file, then forget cached registry entries and, unless it
+/// was set on the command line, swap in the default model. A config file that
+/// fails to load leaves the current settings in place.
+fn reload_config(state: &AppState) -> anyhow::Result<RegistryReload> {
+    let config = crate::config::Config::load()?;
+
+    let cleared_registry_entries = model::download::clear_remote_registry_cache();
+    info!("Cleared {} cached registry entries", cleared_registry_entries);
+
+    // A default model given on the command line wins over the config file
+    let mut default_model = state.default_model.write().unwrap();
+    if state.reload_default_model && *default_model != config.default_model {
+        info!(
+            "Default model changed from {} to {}",
+            default_model.as_deref().unwrap_or("(none)"),
+            config.default_model.as_deref().unwrap_or("(none)"),
+        );
+        *default_model = config.default_model;
+    }
+
+    Ok(RegistryReload {
+        builtin_models: model::download::builtin_models().len(),
+        cleared_registry_entries,
+        default_model: default_model.clone(),
+    })
+}
+
+/// Reload the configuration like SIGHUP does, for where signals are awkward
+///
+/// A config file that fails to load is reported with 422 and changes nothing.
+async fn reload_registry(
+    State(state): State<AppState>,
+) -> Result<Json<ApiResponse<RegistryReload>>, ApiError> {
+    let reload = reload_config(&state).map_err(|e| ApiError::new(
+        StatusCode::UNPROCESSABLE_ENTITY,
+        format!("Failed to reload configuration: {:#}", e),
+    ))?;
+    Ok(Json(ApiResponse::success(reload)))
+}
+
+/// Watch the models directories so cached models are evicted when their file
+/// is removed out-of-band
+fn watch_models_dir(state: AppState) -> notify::Result<RecommendedWatcher> {
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event| {
+        let _ = tx.send(event);
+    })?;
+    for models_dir in state.store.dirs().iter().filter(|dir| dir.is_dir()) {
+        watcher.watch(models_dir, RecursiveMode::Recursive)?;
+        info!("Watching {:?} for model changes", models_dir);
+    }
+
+    tokio::spawn(async move {
+        while let Some(event) = rx.recv().await {
+            match event {
+                Ok(event) => handle_models_dir_event(&state, event),
+                Err(e) => warn!("Error watching models directory: {}", e),
+            }
+        }
+    });
+
+    Ok(watcher)
+}
+
+/// React to a filesystem event in the models directory
+fn handle_models_dir_event(state: &AppState, event: notify::Event) {
+    let removed = matches!(
+        event.kind,
+        EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(RenameMode::From))
+    );
+    let added = matches!(
+        event.kind,
+        EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(RenameMode::To))
+    );
+
+    let gguf_paths = event
+        .paths
+        .iter()
+        .filter(|path| path.extension().and_then(|ext| ext.to_str()) == Some("gguf"));
+
+    for path in gguf_paths {
+        if added || removed {
+            state.invalidate_model_list();
+        }
+        if added {
+            info!("Model file added: {:?}", path);
+        } else if removed {
+            info!("Model file removed: {:?}", path);
+            evict_models_at_path(state, path);
+        }
+    }
+}
+
+/// Remove and unload every cached model that was loaded from `path`
+fn evict_models_at_path(state: &AppState, path: &FilePath) {
+    let mut models = state.models.lock().unwrap();
+    models.retain(|name, cached| {
+        if cached.path != path {
+            return true;
+        }
+
+        info!("Evicting cached model {}", name);
+        // A model busy generating is freed once its last request drops it
+        if let Ok(mut model) = cached.model.try_lock() {
+            model.unload();
+        }
+        false
+    });
+}
+
+/// Periodically unload cached models that have been idle for `timeout`
+fn spawn_idle_unloader(state: AppState) {
+    if let Some(timeout) = state.model_idle_timeout {
+        info!("Unloading models idle for more than {:?}", timeout);
+    }
+    // Requests can ask for short keep-alives, so check every second
+    let period = Duration::from_secs(1);
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            unload_idle_models(&state);
+        }
+    });
+}
+
+/// Remove and unload every cached model that outlived its keep-alive, or the
+/// server's idle timeout if the last request didn't set one
+fn unload_idle_models(state: &AppState) {
+    let mut models = state.models.lock().unwrap();
+    models.retain(|name, cached| {
+        let timeout = cached.idle_timeout(state.model_idle_timeout);
+        if timeout.is_none_or(|timeout| cached.last_used.elapsed() < timeout) {
+            return true;
+        }
+
+        // A model that is generating right now isn't idle, and neither is one
+        // a request still holds while it waits for a generation slot
+        if Arc::strong_count(&cached.model) > 1 {
+            return true;
+        }
+        match cached.model.try_lock() {
+            Ok(mut model) if model.active_sequences() == 0 => {
+                info!("Unloading idle model {}", name);
+                model.unload();
+                false
+            }
+            _ => true,
+        }
+    });
+}
+
+/// Record that a cached model was just used, and how long the request wants
+/// it kept loaded
+///
+/// A keep-alive of zero unloads the model right away, unless another request
+/// is using it, in which case the idle unloader gets it once that one is done.
+fn touch_model(state: &AppState, model_name: &str, keep_alive: Option<ModelKeepAlive>) {
+    {
+        let mut models = state.models.lock().unwrap();
+        if let Some(cached) = models.get_mut(model_name) {
+            cached.last_used = Instant::now();
+            cached.keep_alive = keep_alive;
+        }
+    }
+    if keep_alive == Some(ModelKeepAlive::For(Duration::ZERO)) {
+        unload_idle_models(state);
+    }
+}
+
 /// Custom HTTP error with message
+#[derive(Clone)]
 struct ApiError {
     status: StatusCode,
     message: String,
+    /// Fields added to the body next to `error`, for clients to act on
+    details: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+impl ApiError {
+    /// An error with just a message in its body
+    fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+            details: None,
+        }
+    }
 }
 
 impl IntoResponse for ApiError {
     fn into_response(self) -> Response {
-        let body = Json(serde_json::json!({
-            "error": self.message
-        }));
-        (self.status, body).into_response()
+        let mut body = serde_json::Map::new();
+        body.insert("error".to_string(), self.message.into());
+        body.extend(self.details.unwrap_or_default());
+        (self.status, Json(body)).into_response()
+    }
+}
+
+impl From<ModelError> for ApiError {
+    fn from(error: ModelError) -> Self {
+        let status = match &error {
+            ModelError::NotFound { .. } => StatusCode::NOT_FOUND,
+            ModelError::MultipleMatches { .. } => StatusCode::CONFLICT,
+            ModelError::ContextOverflow { .. }
+            | ModelError::PromptTooLong { .. }
+            | ModelError::InvalidGrammar(_)
+            | ModelError::VisionUnsupported(_)
+            | ModelError::InvalidImage(_) => StatusCode::BAD_REQUEST,
+            ModelError::DownloadFailed(_)
+            | ModelError::DownloadStalled { .. }
+            | ModelError::TruncatedDownload { .. }
+            | ModelError::HashMismatch { .. } => StatusCode::BAD_GATEWAY,
+            ModelError::Io(_)
+            | ModelError::LoadFailed(_)
+            | ModelError::GenerationFailed(_)
+            | ModelError::NotAGgufFile { .. }
+            | ModelError::InvalidGgufHeader { .. }
+            | ModelError::IncompleteDownload { .. }
+            | ModelError::UnsupportedGgufVersion { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            ModelError::InsufficientMemory { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            ModelError::InferenceDisabled => StatusCode::NOT_IMPLEMENTED,
+        };
+        let details = match &error {
+            ModelError::PromptTooLong { prompt_tokens, max_tokens, context_size } => {
+                let details = serde_json::json!({
+                    "prompt_tokens": prompt_tokens,
+                    "max_tokens": max_tokens,
+                    "context_size": context_size,
+                });
+                details.as_object().cloned()
+            }
+            _ => None,
+        };
+        Self {
+            status,
+            message: error.to_string(),
+            details,
+        }
     }
 }
 
@@ -95,19 +854,85 @@ impl<T> ApiResponse<T> {
             error: None,
         }
     }
+}
 
-    fn error(message: impl Into<String>) -> Self {
-        Self {
-            success: false,
-            data: None,
-            error: Some(message.into()),
-        }
-    }
+/// Health check response
+#[derive(Serialize)]
+struct HealthResponse {
+    status: &'static str,
+    /// Generations running right now, across all models
+    generations_in_flight: usize,
+    /// Limit on `generations_in_flight` (`--max-concurrent-gen`)
+    max_concurrent_generations: usize,
 }
 
 /// Health check endpoint
-async fn health_check() -> impl IntoResponse {
-    Json(ApiResponse::success("OK"))
+async fn health_check(State(state): State<AppState>) -> impl IntoResponse {
+    Json(ApiResponse::success(HealthResponse {
+        status: "OK",
+        generations_in_flight: state.generations_in_flight(),
+        max_concurrent_generations: state.max_concurrent_generations,
+    }))
+}
+
+/// Shutdown endpoint: stops the server the way ctrl-c does, once the
+/// response is sent (only routed with `--allow-remote-shutdown`)
+async fn shutdown_server(State(state): State<AppState>) -> Json<ApiResponse<String>> {
+    info!("Shutdown requested over the API");
+    state.shutdown.cancel();
+    Json(ApiResponse::success("Server is shutting down".to_string()))
+}
+
+/// Loaded models response
+#[derive(Serialize)]
+struct RunningModelsResponse {
+    models: Vec<RunningModel>,
+}
+
+/// A model loaded in memory
+#[derive(Serialize)]
+struct RunningModel {
+    name: String,
+    path: PathBuf,
+    /// Size of the model file
+    size_bytes: u64,
+    /// Approximate memory in use, weights plus KV cache
+    #[serde(flatten)]
+    memory: MemoryFootprint,
+    context_size: usize,
+    loaded_at: String,
+    /// When the model is unloaded if no request uses it before then (null = never)
+    expires_at: Option<String>,
+    /// Whether a request is generating with the model right now
+    busy: bool,
+}
+
+/// List the models currently loaded in memory, as opposed to `/api/models`,
+/// which lists the model files on disk
+async fn list_running_models(State(state): State<AppState>) -> Json<ApiResponse<RunningModelsResponse>> {
+    let models = state.models.lock().unwrap();
+    let mut running: Vec<RunningModel> = models
+        .iter()
+        .map(|(name, cached)| {
+            let expires_at = cached.idle_timeout(state.model_idle_timeout).and_then(|timeout| {
+                let remaining = chrono::Duration::from_std(timeout.saturating_sub(cached.last_used.elapsed())).ok()?;
+                chrono::Utc::now().checked_add_signed(remaining).map(|time| time.to_rfc3339())
+            });
+            RunningModel {
+                name: name.clone(),
+                path: cached.path.clone(),
+                size_bytes: std::fs::metadata(&cached.path).map_or(0, |metadata| metadata.len()),
+                memory: cached.memory,
+                context_size: cached.context_size,
+                loaded_at: cached.loaded_at.to_rfc3339(),
+                expires_at,
+                busy: cached.model.try_lock().is_err(),
+            }
+        })
+        .collect();
+    running.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Json(ApiResponse::success(RunningModelsResponse { models: running }))
 }
 
 /// Model information response
@@ -116,59 +941,109 @@ struct ModelListResponse {
     models: Vec<ModelInfo>,
 }
 
+/// Query parameters for listing models
+#[derive(Deserialize)]
+struct ListModelsQuery {
+    /// Rescan the models directories instead of using the cached list
+    #[serde(default)]
+    refresh: bool,
+    #[serde(default)]
+    sort: model::ModelSort,
+    #[serde(default)]
+    order: model::SortOrder,
+}
+
 /// Model information
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 struct ModelInfo {
     name: String,
     size_bytes: u64,
     last_modified: String,
+    /// Models directory the file lives in
+    directory: PathBuf,
+    /// Hugging Face revision the file was pinned to when downloaded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    revision: Option<String>,
 }
 
 /// List available models
+///
+/// Scans are cached for `model_list_ttl` so bursts of requests don't hit the
+/// filesystem every time; `?refresh=true` forces a rescan. Models are sorted
+/// by `?sort=name|size|modified` and `?order=asc|desc`, name ascending by
+/// default.
 async fn list_models(
     State(state): State<AppState>,
+    Query(query): Query<ListModelsQuery>,
 ) -> Result<Json<ApiResponse<ModelListResponse>>, ApiError> {
-    let models_dir = &state.models_dir;
+    let cached = if query.refresh {
+        None
+    } else {
+        let cache = state.model_list.lock().unwrap();
+        cache
+            .as_ref()
+            .filter(|cache| cache.scanned_at.elapsed() < state.model_list_ttl)
+            .map(|cache| cache.models.clone())
+    };
+    let mut models = match cached {
+        Some(models) => models,
+        None => {
+            let models = scan_models(state.store.as_ref())?;
+            *state.model_list.lock().unwrap() = Some(ModelListCache {
+                models: models.clone(),
+                scanned_at: Instant::now(),
+            });
+            models
+        }
+    };
+
+    model::sort_models(&mut models, query.sort, query.order, |model| {
+        let modified = chrono::DateTime::parse_from_rfc3339(&model.last_modified)
+            .ok()
+            .map(std::time::SystemTime::from);
+        (&model.name, model.size_bytes, modified)
+    });
+    Ok(Json(ApiResponse::success(ModelListResponse { models })))
+}
+
+/// Read the models in every directory along with their file metadata
+///
+/// Models in subdirectories are named by their path relative to the models
+/// directory, e.g. `llama/llama2-7b.gguf`.
+fn scan_models(store: &dyn ModelStore) -> Result<Vec<ModelInfo>, ApiError> {
     let mut models = Vec::new();
 
-    // Read models from directory
-    if models_dir.exists() {
-        for entry in std::fs::read_dir(models_dir).map_err(|e| ApiError {
-            status: StatusCode::INTERNAL_SERVER_ERROR,
-            message: format!("Failed to read models directory: {}", e),
-        })? {
-            let entry = entry.map_err(|e| ApiError {
-                status: StatusCode::INTERNAL_SERVER_ERROR,
-                message: format!("Failed to read directory entry: {}", e),
-            })?;
-
-            let path = entry.path();
-            if path.is_file() && path.extension().and_then(|ext| ext.to_str()) == Some("gguf") {
-                if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
-                    let metadata = entry.metadata().map_err(|e| ApiError {
-                        status: StatusCode::INTERNAL_SERVER_ERROR,
-                        message: format!("Failed to read file metadata: {}", e),
-                    })?;
-
-                    let last_modified = metadata
-                        .modified()
-                        .map(|time| {
-                            let datetime = chrono::DateTime::<chrono::Utc>::from(time);
-                            datetime.to_rfc3339()
-                        })
-                        .unwrap_or_else(|_| "Unknown".to_string());
-
-                    models.push(ModelInfo {
-                        name: name.to_string(),
-                        size_bytes: metadata.len(),
-                        last_modified,
-                    });
-                }
-            }
-        }
+    // Read models from every directory
+    let model_files = store.list().map_err(|e| ApiError::new(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Failed to read models directory: {}", e),
+    ))?;
+
+    for path in model_files {
+        let name = model::model_display_name(&path, store.dirs());
+        let metadata = std::fs::metadata(&path).map_err(|e| ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to read file metadata: {}", e),
+        ))?;
+
+        let last_modified = metadata
+            .modified()
+            .map(|time| {
+                let datetime = chrono::DateTime::<chrono::Utc>::from(time);
+                datetime.to_rfc3339()
+            })
+            .unwrap_or_else(|_| "Unknown".to_string());
+
+        models.push(ModelInfo {
+            name,
+            size_bytes: metadata.len(),
+            last_modified,
+            directory: path.parent().unwrap_or(&path).to_path_buf(),
+            revision: model::download::stored_revision(&path),
+        });
     }
 
-    Ok(Json(ApiResponse::success(ModelListResponse { models })))
+    Ok(models)
 }
 
 /// Get information about a specific model
@@ -176,22 +1051,14 @@ async fn get_model_info(
     State(state): State<AppState>,
     Path(model_name): Path<String>,
 ) -> Result<Json<ApiResponse<ModelInfo>>, ApiError> {
-    let models_dir = &state.models_dir;
-    let model_path = find_model_path(&model_name, models_dir).map_err(|e| ApiError {
-        status: StatusCode::NOT_FOUND,
-        message: format!("Model not found: {}", e),
-    })?;
+    let model_path = state.store.resolve(&model_name)?;
 
-    let metadata = std::fs::metadata(&model_path).map_err(|e| ApiError {
-        status: StatusCode::INTERNAL_SERVER_ERROR,
-        message: format!("Failed to read file metadata: {}", e),
-    })?;
+    let metadata = std::fs::metadata(&model_path).map_err(|e| ApiError::new(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Failed to read file metadata: {}", e),
+    ))?;
 
-    let name = model_path
-        .file_name()
-        .and_then(|n| n.to_str())
-        .unwrap_or(&model_name)
-        .to_string();
+    let name = model::model_display_name(&model_path, state.store.dirs());
 
     let last_modified = metadata
         .modified()
@@ -205,118 +1072,221 @@ async fn get_model_info(
         name,
         size_bytes: metadata.len(),
         last_modified,
+        directory: model_path.parent().unwrap_or(&model_path).to_path_buf(),
+        revision: model::download::stored_revision(&model_path),
     };
 
     Ok(Json(ApiResponse::success(model_info)))
 }
 
-/// Find a model path from a model name
-fn find_model_path(model_name: &str, models_dir: &FilePath) -> anyhow::Result<PathBuf> {
-    // Check if the exact filename exists
-    let exact_path = models_dir.join(model_name);
-    if exact_path.exists() {
-        return Ok(exact_path);
-    }
-
-    // Check if model_name with .gguf extension exists
-    let with_extension = if model_name.ends_with(".gguf") {
-        models_dir.join(model_name)
-    } else {
-        models_dir.join(format!("{}.gguf", model_name))
-    };
+/// Download model request
+#[derive(Deserialize)]
+struct DownloadModelRequest {
+    force: Option<bool>,
+}
 
-    if with_extension.exists() {
-        return Ok(with_extension);
-    }
+/// A model download running in the background
+struct DownloadJob {
+    model: String,
+    /// Where the model is being written
+    path: PathBuf,
+    progress: DownloadProgress,
+    started_at: Instant,
+    /// When the download ended and its error, if it failed
+    finished: Option<(Instant, Option<String>)>,
+}
 
-    // Try to find a partial match
-    for entry in std::fs::read_dir(models_dir)? {
-        let entry = entry?;
-        let path = entry.path();
+/// How long a finished download job can still be polled before it is dropped
+const FINISHED_DOWNLOAD_TTL: Duration = Duration::from_secs(60 * 60);
 
-        if path.is_file() {
-            if let Some(file_name) = path.file_name().and_then(|n| n.to_str()) {
-                if file_name.contains(model_name) {
-                    return Ok(path);
-                }
-            }
-        }
-    }
+/// State of a download job
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+enum DownloadStatus {
+    Downloading,
+    Completed,
+    Failed,
+}
 
-    anyhow::bail!("Model {} not found in {:?}", model_name, models_dir)
+/// Download job status
+#[derive(Serialize)]
+struct DownloadJobResponse {
+    id: String,
+    model: String,
+    status: DownloadStatus,
+    downloaded_bytes: u64,
+    /// Unknown if the server didn't report the file size
+    total_bytes: Option<u64>,
+    percent: Option<f64>,
+    /// Average speed since the download started
+    bytes_per_sec: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
 }
 
-/// Download model request
-#[derive(Deserialize)]
-struct DownloadModelRequest {
-    force: Option<bool>,
+impl DownloadJob {
+    /// Whether the job finished longer than `FINISHED_DOWNLOAD_TTL` ago
+    fn expired(&self, now: Instant) -> bool {
+        self.finished.as_ref().is_some_and(|(at, _)| now.duration_since(*at) > FINISHED_DOWNLOAD_TTL)
+    }
+
+    fn status(&self, id: &str) -> DownloadJobResponse {
+        let downloaded_bytes = self.progress.downloaded();
+        let total_bytes = self.progress.total();
+        let (status, ended_at, error) = match &self.finished {
+            None => (DownloadStatus::Downloading, Instant::now(), None),
+            Some((at, None)) => (DownloadStatus::Completed, *at, None),
+            Some((at, Some(error))) => (DownloadStatus::Failed, *at, Some(error.clone())),
+        };
+        let elapsed = ended_at.duration_since(self.started_at).as_secs_f64();
+
+        DownloadJobResponse {
+            id: id.to_string(),
+            model: self.model.clone(),
+            status,
+            downloaded_bytes,
+            total_bytes,
+            percent: total_bytes.map(|total| downloaded_bytes as f64 * 100.0 / total as f64),
+            bytes_per_sec: if elapsed > 0.0 { downloaded_bytes as f64 / elapsed } else { 0.0 },
+            error,
+        }
+    }
 }
 
 /// Download a model
+///
+/// Downloads take minutes, so this starts a background job and returns
+/// `202 Accepted` right away; poll `GET /api/downloads/:id` for its progress.
+/// While a job is writing a model file, other requests for it get `409`
+/// with that job's id. Finished jobs are dropped after `FINISHED_DOWNLOAD_TTL`.
 async fn download_model(
     State(state): State<AppState>,
     Path(model_name): Path<String>,
     Json(request): Json<DownloadModelRequest>,
-) -> Result<Json<ApiResponse<String>>, ApiError> {
+) -> Result<Response, ApiError> {
     let force = request.force.unwrap_or(false);
-    let models_dir = &state.models_dir;
+    let models_dir = model::download_dir(state.store.dirs());
 
     // Get model info
-    let model_info = model::download::get_model_info(&model_name)
+    let model_info = model::download::get_model_info(&model_name, &state.download_config)
         .await
-        .map_err(|e| ApiError {
-            status: StatusCode::BAD_REQUEST,
-            message: format!("Failed to get model information: {}", e),
-        })?;
-
-    let model_path = models_dir.join(&model_info.filename);
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, format!("Failed to get model information: {}", e)))?;
+
+    let model_path = model_info
+        .relative_path()
+        .map(|relative| models_dir.join(relative))
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e.to_string()))?;
+
+    // Hold the job list until this job is in it, so two requests for the
+    // same model can't both start writing to its path
+    let mut downloads = state.downloads.lock().unwrap();
+    let now = Instant::now();
+    downloads.retain(|_, job| !job.expired(now));
+    let in_progress = downloads
+        .iter()
+        .find(|(_, job)| job.finished.is_none() && job.path == model_path);
+    if let Some((id, _)) = in_progress {
+        let body = Json(serde_json::json!({
+            "error": format!("Model {} is already downloading", model_name),
+            "job_id": id,
+        }));
+        return Ok((StatusCode::CONFLICT, body).into_response());
+    }
 
     // Check if model already exists
-    if model_path.exists() && !force {
+    if state.store.exists(&model_path) && !force {
         return Ok(Json(ApiResponse::success(format!(
             "Model {} already exists",
             model_name
-        ))));
+        )))
+        .into_response());
     }
 
     // Delete existing model if force is true
-    if model_path.exists() && force {
-        std::fs::remove_file(&model_path).map_err(|e| ApiError {
-            status: StatusCode::INTERNAL_SERVER_ERROR,
-            message: format!("Failed to delete existing model: {}", e),
-        })?;
-    }
-
-    // Download the model
-    model::download::download_model_file(
-        &model_info.download_url,
-        &model_path,
-        &model_info.sha256,
-    )
-    .await
-    .map_err(|e| ApiError {
-        status: StatusCode::INTERNAL_SERVER_ERROR,
-        message: format!("Failed to download model: {}", e),
-    })?;
-
-    Ok(Json(ApiResponse::success(format!(
-        "Model {} downloaded successfully",
-        model_name
-    ))))
-}
+    if state.store.exists(&model_path) && force {
+        state.store.delete(&model_path).map_err(|e| ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to delete existing model: {}", e),
+        ))?;
+        state.invalidate_model_list();
+    }
 
-/// Delete a model
+    let id = uuid::Uuid::new_v4().to_string();
+    let progress = DownloadProgress::default();
+    let job = DownloadJob {
+        model: model_name.clone(),
+        path: model_path.clone(),
+        progress: progress.clone(),
+        started_at: Instant::now(),
+        finished: None,
+    };
+    let response = job.status(&id);
+    downloads.insert(id.clone(), job);
+    drop(downloads);
+
+    // Download the model in the background
+    let download_config = DownloadConfig {
+        progress: Some(progress),
+        ..state.download_config.clone()
+    };
+    let job_id = id.clone();
+    tokio::spawn(
+        async move {
+            let result = model::download::download_model_with_options(
+                &model_info,
+                &model_path,
+                true,
+                &download_config,
+            )
+            .await;
+            let error = match result {
+                Ok(()) => {
+                    info!("Model {} downloaded successfully", model_name);
+                    state.invalidate_model_list();
+                    None
+                }
+                Err(e) => {
+                    warn!("Download of model {} failed: {}", model_name, e);
+                    Some(e.to_string())
+                }
+            };
+            if let Some(job) = state.downloads.lock().unwrap().get_mut(&job_id) {
+                job.finished = Some((Instant::now(), error));
+            }
+        }
+        .instrument(tracing::Span::current()),
+    );
+
+    let location = format!("/api/downloads/{}", id);
+    Ok((
+        StatusCode::ACCEPTED,
+        [(header::LOCATION, location)],
+        Json(ApiResponse::success(response)),
+    )
+        .into_response())
+}
+
+/// Get the progress of a download job
+async fn get_download(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<ApiResponse<DownloadJobResponse>>, ApiError> {
+    let downloads = state.downloads.lock().unwrap();
+    let job = downloads.get(&id).filter(|job| !job.expired(Instant::now())).ok_or_else(|| ApiError::new(
+        StatusCode::NOT_FOUND,
+        format!("No download job {}", id),
+    ))?;
+
+    Ok(Json(ApiResponse::success(job.status(&id))))
+}
+
+/// Delete a model
 async fn delete_model(
     State(state): State<AppState>,
     Path(model_name): Path<String>,
 ) -> Result<Json<ApiResponse<String>>, ApiError> {
-    let models_dir = &state.models_dir;
-
     // Find the model path
-    let model_path = find_model_path(&model_name, models_dir).map_err(|e| ApiError {
-        status: StatusCode::NOT_FOUND,
-        message: format!("Model not found: {}", e),
-    })?;
+    let model_path = state.store.resolve(&model_name)?;
 
     // Remove from model cache if loaded
     {
@@ -325,10 +1295,11 @@ async fn delete_model(
     }
 
     // Delete the file
-    std::fs::remove_file(&model_path).map_err(|e| ApiError {
-        status: StatusCode::INTERNAL_SERVER_ERROR,
-        message: format!("Failed to delete model: {}", e),
-    })?;
+    state.store.delete(&model_path).map_err(|e| ApiError::new(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Failed to delete model: {}", e),
+    ))?;
+    state.invalidate_model_list();
 
     Ok(Json(ApiResponse::success(format!(
         "Model {} deleted successfully",
@@ -339,27 +1310,233 @@ async fn delete_model(
 /// Chat request
 #[derive(Deserialize)]
 struct ChatRequest {
+    /// Model to chat with; the server's default model if left out
+    #[serde(default)]
     model: String,
     messages: Vec<ChatRequestMessage>,
     temperature: Option<f32>,
+    /// Sample from only the `top_k` most likely tokens (0 = off)
+    top_k: Option<usize>,
     max_tokens: Option<usize>,
+    /// Penalize tokens by how often they were already generated (-2.0 to 2.0)
+    frequency_penalty: Option<f32>,
+    /// Penalize tokens that were already generated at all (-2.0 to 2.0)
+    presence_penalty: Option<f32>,
+    /// Number of independent completions to generate
+    n: Option<usize>,
+    /// Base seed; completion `i` samples with `seed + i`
+    seed: Option<u32>,
+    /// Stop a completion that gets stuck in a loop; overrides `--anti-repeat-window`
+    stop_on_repeat: Option<bool>,
+    /// Prompt template to use instead of the one guessed from the model's file name
+    template: Option<PromptTemplate>,
+    /// Order of the sampling steps, e.g. `["top_p", "temp"]`; steps left out are skipped
+    sampler_chain: Option<Vec<String>>,
+    /// GBNF grammar the reply must match, starting from its `root` rule
+    grammar: Option<String>,
+    /// `{"type": "json_object"}` constrains the reply to a JSON object, as in OpenAI's API
+    response_format: Option<ResponseFormat>,
+    /// How long the model stays loaded after this request (see `ModelKeepAlive`)
+    keep_alive: Option<ModelKeepAlive>,
+    /// Text the reply starts with, such as a persona's name; the model continues
+    /// from it and stops if it starts a new user turn
+    prefix: Option<String>,
+    /// Return the reply as generated, without stripping leaked template tokens
+    /// and surrounding whitespace
+    #[serde(default)]
+    raw_output: bool,
+    /// Drop the oldest messages until the prompt and `max_tokens` fit in the
+    /// context window, instead of failing with 400
+    #[serde(default)]
+    trim_history: bool,
     stream: Option<bool>,
+    /// Options for `/api/chat/stream`
+    #[serde(default)]
+    stream_options: StreamOptions,
+}
+
+/// Output format, as in OpenAI's API
+#[derive(Deserialize)]
+struct ResponseFormat {
+    #[serde(rename = "type")]
+    format_type: ResponseFormatType,
+}
+
+#[derive(Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum ResponseFormatType {
+    /// Unconstrained text, the default
+    Text,
+    /// A JSON object, enforced with `JSON_GRAMMAR`
+    JsonObject,
+}
+
+/// Reminder added to the system prompt in JSON mode; the grammar alone makes
+/// the output valid, but models write better JSON when they are told to
+const JSON_MODE_INSTRUCTION: &str = "Respond only with a valid JSON object.";
+
+/// Streaming options, as in OpenAI's API
+#[derive(Default, Deserialize)]
+struct StreamOptions {
+    /// Send a last chunk with the request's token usage before `[DONE]`
+    #[serde(default)]
+    include_usage: bool,
 }
 
 /// Chat message in request
 #[derive(Deserialize)]
 struct ChatRequestMessage {
     role: String,
-    content: String,
+    content: MessageContent,
+    /// Participant who sent the message
+    name: Option<String>,
+}
+
+/// Content of a request message: a string, or an OpenAI-style array of text
+/// and image parts
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+/// One part of an array message content
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentPart {
+    Text { text: String },
+    /// A base64 `data:` URI or an http(s) URL
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Deserialize)]
+struct ImageUrl {
+    url: String,
+}
+
+impl MessageContent {
+    /// The message's text, with an `IMAGE_MARKER` on its own line where each
+    /// image goes
+    fn text(&self) -> String {
+        match self {
+            MessageContent::Text(text) => text.clone(),
+            MessageContent::Parts(parts) => parts
+                .iter()
+                .map(|part| match part {
+                    ContentPart::Text { text } => text.as_str(),
+                    ContentPart::ImageUrl { .. } => IMAGE_MARKER,
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+
+    /// URLs of the message's images, in order
+    fn image_urls(&self) -> impl Iterator<Item = &str> {
+        let parts = match self {
+            MessageContent::Text(_) => &[][..],
+            MessageContent::Parts(parts) => parts.as_slice(),
+        };
+        parts.iter().filter_map(|part| match part {
+            ContentPart::ImageUrl { image_url } => Some(image_url.url.as_str()),
+            ContentPart::Text { .. } => None,
+        })
+    }
+}
+
+/// Largest image a request may include, before decoding
+const MAX_IMAGE_BYTES: usize = 20 * 1024 * 1024;
+
+/// Fetch the images in a request's messages, in order
+///
+/// A request with images fails with 400 before anything is fetched if the
+/// model (already loaded) can't read them.
+async fn load_request_images(request: &ChatRequest, state: &AppState) -> Result<Vec<Vec<u8>>, ApiError> {
+    let urls: Vec<&str> = request.messages.iter().flat_map(|message| message.content.image_urls()).collect();
+    if urls.is_empty() {
+        return Ok(Vec::new());
+    }
+    state.check_image_support(&request.model)?;
+
+    let mut images = Vec::new();
+    for url in urls {
+        images.push(load_image(url, state).await?);
+    }
+    Ok(images)
+}
+
+/// The bytes of an image given as a base64 `data:` URI, or an http(s) URL if
+/// the server allows remote images
+async fn load_image(url: &str, state: &AppState) -> Result<Vec<u8>, ApiError> {
+    let bad_request = |message: String| ApiError::new(StatusCode::BAD_REQUEST, message);
+
+    let image = if let Some(data_uri) = url.strip_prefix("data:") {
+        decode_data_uri(data_uri).map_err(bad_request)?
+    } else if url.starts_with("http://") || url.starts_with("https://") {
+        if !state.allow_remote_images {
+            return Err(bad_request(
+                "Image URLs must be base64 data: URIs (the server doesn't fetch remote images)".to_string(),
+            ));
+        }
+        let too_large = || bad_request(format!("Image {} is larger than {} bytes", url, MAX_IMAGE_BYTES));
+        let mut response = state.download_config.client.get(url).send().await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|e| bad_request(format!("Failed to fetch image {}: {}", url, e)))?;
+        if response.content_length().is_some_and(|length| length > MAX_IMAGE_BYTES as u64) {
+            return Err(too_large());
+        }
+        // The length header may be missing or wrong, so count what arrives
+        let mut image = Vec::new();
+        while let Some(chunk) = response.chunk().await
+            .map_err(|e| bad_request(format!("Failed to fetch image {}: {}", url, e)))?
+        {
+            if image.len() + chunk.len() > MAX_IMAGE_BYTES {
+                return Err(too_large());
+            }
+            image.extend_from_slice(&chunk);
+        }
+        image
+    } else {
+        return Err(bad_request("Image URLs must be base64 data: URIs or http(s) URLs".to_string()));
+    };
+
+    if image.len() > MAX_IMAGE_BYTES {
+        return Err(bad_request(format!("Images must be at most {} bytes", MAX_IMAGE_BYTES)));
+    }
+    Ok(image)
+}
+
+/// Decode the part of a `data:` URI after the scheme, e.g. `image/png;base64,iVBOR...`
+fn decode_data_uri(data_uri: &str) -> Result<Vec<u8>, String> {
+    use base64::Engine;
+
+    let (media_type, data) = data_uri.split_once(',')
+        .ok_or_else(|| "Image data URI has no data".to_string())?;
+    if !media_type.ends_with(";base64") {
+        return Err("Image data URIs must be base64-encoded".to_string());
+    }
+    base64::engine::general_purpose::STANDARD.decode(data.trim())
+        .map_err(|e| format!("Image data URI is not valid base64: {}", e))
 }
 
 /// Chat response
 #[derive(Serialize)]
 struct ChatResponse {
+    /// The first choice, kept for clients that only read a single message
     message: ChatResponseMessage,
+    choices: Vec<ChatChoice>,
     usage: TokenUsage,
 }
 
+/// One of the completions generated for a chat request
+#[derive(Serialize)]
+struct ChatChoice {
+    index: usize,
+    message: ChatResponseMessage,
+    finish_reason: FinishReason,
+}
+
 /// Chat message in response
 #[derive(Serialize)]
 struct ChatResponseMessage {
@@ -368,89 +1545,447 @@ struct ChatResponseMessage {
 }
 
 /// Token usage statistics
-#[derive(Serialize)]
+#[derive(Clone, Copy, Serialize)]
 struct TokenUsage {
     prompt_tokens: usize,
     completion_tokens: usize,
     total_tokens: usize,
 }
 
-/// Chat endpoint for non-streaming responses
-async fn chat(
-    State(state): State<AppState>,
-    Json(request): Json<ChatRequest>,
-) -> Result<Json<ApiResponse<ChatResponse>>, ApiError> {
-    // Load the model
-    let model = load_model(&request.model, &state).await?;
-    let mut model = model.lock().unwrap();
+/// Check a frequency or presence penalty is in OpenAI's range, defaulting to off
+fn validate_penalty(name: &str, penalty: Option<f32>) -> Result<f32, ApiError> {
+    match penalty {
+        Some(penalty) if !(-2.0..=2.0).contains(&penalty) => Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!("{} must be between -2.0 and 2.0, got {}", name, penalty),
+        )),
+        penalty => Ok(penalty.unwrap_or(0.0)),
+    }
+}
+
+/// Add a request's messages to the conversation, in order
+///
+/// A system message leading the conversation becomes its system prompt, later
+/// ones stay where they are as system turns.
+fn add_request_messages(context: &mut ChatContext, messages: &[ChatRequestMessage]) -> Result<(), ApiError> {
+    for message in messages {
+        let content = message.content.text();
+        let chat_message = match (message.role.as_str(), message.name.clone()) {
+            ("user", Some(name)) => ChatMessage::user_named(name, content),
+            ("user", None) => ChatMessage::user(content),
+            ("assistant", Some(name)) => ChatMessage::assistant_named(name, content),
+            ("assistant", None) => ChatMessage::assistant(content),
+            ("system", _) if context.messages.is_empty() => {
+                context.system_prompt = content;
+                continue;
+            }
+            ("system", name) => ChatMessage { role: ChatRole::System, content, name },
+            _ => {
+                return Err(ApiError::new(StatusCode::BAD_REQUEST, format!("Invalid message role: {}", message.role)));
+            }
+        };
+
+        context.add_message(chat_message);
+    }
+
+    Ok(())
+}
 
-    // Set model parameters
+/// A chat request's sampling parameters, checked but not yet applied
+struct ChatSampling {
+    frequency_penalty: f32,
+    presence_penalty: f32,
+    sampler_chain: Vec<SamplerStep>,
+    grammar: Option<String>,
+}
+
+/// Whether a chat request asks for a JSON object reply
+fn is_json_mode(request: &ChatRequest) -> bool {
+    request.response_format.as_ref()
+        .is_some_and(|format| format.format_type == ResponseFormatType::JsonObject)
+}
+
+/// Check a chat request's penalties, sampler chain and grammar
+fn chat_sampling(request: &ChatRequest) -> Result<ChatSampling, ApiError> {
+    let frequency_penalty = validate_penalty("frequency_penalty", request.frequency_penalty)?;
+    let presence_penalty = validate_penalty("presence_penalty", request.presence_penalty)?;
+    let sampler_chain = match &request.sampler_chain {
+        Some(names) => SamplerStep::parse_chain(names).map_err(|message| ApiError::new(
+            StatusCode::BAD_REQUEST,
+            message,
+        ))?,
+        None => SamplerStep::DEFAULT_CHAIN.to_vec(),
+    };
+    let json_mode = is_json_mode(request);
+    let grammar = match &request.grammar {
+        Some(grammar) if json_mode && grammar.trim() != JSON_GRAMMAR.trim() => {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "response_format json_object can't be combined with a custom grammar",
+            ));
+        }
+        Some(grammar) => Some(grammar.clone()),
+        None => json_mode.then(|| JSON_GRAMMAR.to_string()),
+    };
+
+    Ok(ChatSampling { frequency_penalty, presence_penalty, sampler_chain, grammar })
+}
+
+/// Check that a model can chat, apply the request's sampling parameters to it
+/// and build the conversation to generate from
+///
+/// The model's sampling settings are reset first, so only this request's
+/// parameters apply.
+fn prepare_chat(
+    model: &mut Model,
+    request: &ChatRequest,
+    anti_repeat_window: Option<usize>,
+) -> Result<ChatContext, ApiError> {
+    let sampling = chat_sampling(request)?;
+    let context = build_chat_context(model, request)?;
+
+    model.reset_sampling();
     if let Some(temp) = request.temperature {
         model.set_temperature(temp);
     }
 
+    if let Some(top_k) = request.top_k {
+        model.set_top_k(top_k);
+    }
+
     if let Some(max_tokens) = request.max_tokens {
         model.set_max_tokens(max_tokens);
     }
 
-    // Create chat context
-    let mut context = ChatContext::default();
+    model.set_penalties(sampling.frequency_penalty, sampling.presence_penalty);
+    model.set_sampler_chain(sampling.sampler_chain);
+    model.set_grammar(sampling.grammar);
+    model.set_raw_output(request.raw_output);
+
+    // Window used when a request asks for the guard but the server has none configured
+    const DEFAULT_REPEAT_WINDOW: usize = 32;
+    let repeat_window = match request.stop_on_repeat {
+        Some(false) => None,
+        Some(true) => Some(anti_repeat_window.unwrap_or(DEFAULT_REPEAT_WINDOW)),
+        None => anti_repeat_window,
+    };
+    model.set_repeat_window(repeat_window);
 
-    // Add messages to context
-    for message in &request.messages {
-        let role = match message.role.as_str() {
-            "user" => ChatRole::User,
-            "assistant" => ChatRole::Assistant,
-            "system" => {
-                // Handle system message by updating system prompt
-                context.system_prompt = message.content.clone();
-                continue;
-            }
-            _ => {
-                return Err(ApiError {
-                    status: StatusCode::BAD_REQUEST,
-                    message: format!("Invalid message role: {}", message.role),
-                });
-            }
+    Ok(context)
+}
+
+/// Check that a model can chat and build the conversation for a request,
+/// without changing the model
+fn build_chat_context(model: &Model, request: &ChatRequest) -> Result<ChatContext, ApiError> {
+    if !model.is_chat_capable() {
+        return Err(ApiError::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!(
+                "Model {} is not chat-capable (it has no chat template), use /api/completions instead",
+                request.model
+            ),
+        ));
+    }
+
+    let mut context = ChatContext {
+        context_size: model.get_config().context_size,
+        template: request.template.clone(),
+        response_prefix: request.prefix.clone().filter(|prefix| !prefix.is_empty()),
+        ..Default::default()
+    };
+
+    add_request_messages(&mut context, &request.messages)?;
+
+    if is_json_mode(request) {
+        if context.system_prompt.is_empty() {
+            context.system_prompt = JSON_MODE_INSTRUCTION.to_string();
+        } else {
+            context.system_prompt = format!("{}\n\n{}", context.system_prompt, JSON_MODE_INSTRUCTION);
+        }
+    }
+
+    check_prompt_fits(model, &mut context, request)?;
+    Ok(context)
+}
+
+/// The images whose markers are still in the prompt once `trim_history` has
+/// dropped the oldest messages, which took their images with them
+fn images_in_prompt(model: &Model, context: &ChatContext, mut images: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+    let markers = model.format_prompt(context).matches(IMAGE_MARKER).count();
+    images.drain(..images.len().saturating_sub(markers));
+    images
+}
+
+/// Tokenize the formatted prompt and make sure it leaves room for the
+/// request's `max_tokens` (or at least one token), dropping the oldest messages
+/// first when the request allows it. The last message is never dropped.
+fn check_prompt_fits(model: &Model, context: &mut ChatContext, request: &ChatRequest) -> Result<(), ApiError> {
+    let max_tokens = request.max_tokens.unwrap_or(1);
+    let context_size = model.get_config().context_size;
+    loop {
+        let prompt_tokens = model.count_tokens(&model.format_prompt(context))?;
+        if prompt_tokens + max_tokens <= context_size {
+            return Ok(());
+        }
+        if !request.trim_history || context.messages.len() <= 1 {
+            return Err(ModelError::PromptTooLong { prompt_tokens, max_tokens, context_size }.into());
+        }
+        context.messages.remove(0);
+        info!("Dropped the oldest message, the prompt took {} of {} tokens", prompt_tokens, context_size);
+    }
+}
+
+/// Chat endpoint for non-streaming responses
+///
+/// With `?raw=true` the `ChatResponse` is returned as-is instead of inside the
+/// `{ success, data }` envelope. Errors are a bare `{ error }` object either way.
+async fn chat(
+    State(state): State<AppState>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    Extension(access): Extension<AccessLogEntry>,
+    Query(query): Query<ChatQuery>,
+    Json(mut request): Json<ChatRequest>,
+) -> Result<Response, ApiError> {
+    let n = request.n.unwrap_or(1);
+    if n == 0 || n > state.max_completions {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!("n must be between 1 and {}", state.max_completions),
+        ));
+    }
+
+    // Load the model
+    request.model = state.resolve_model(&request.model)?;
+    access.set_model(&request.model);
+    let model = load_model(&request.model, &state).await?;
+    let images = load_request_images(&request, &state).await?;
+    // Images go through the projector one request at a time
+    let queue = state.sequence_queue(&request.model).filter(|_| images.is_empty());
+    // Generation can take minutes, so keep it off the async workers
+    let model_name = request.model.clone();
+    let keep_alive = request.keep_alive;
+    let anti_repeat_window = state.model_config.anti_repeat_window;
+    let generation = state.register_generation(&request_id)?;
+    let slot = state.acquire_generation_slot().await?;
+    let chat_response = run_blocking(state.nice, move || {
+        let _slot = slot;
+        let mut model = model.lock().unwrap();
+
+        let context = prepare_chat(&mut model, &request, anti_repeat_window)?;
+        let images = images_in_prompt(&model, &context, images);
+        model.set_cancellation(Some(generation.token.clone()));
+
+        // Generate responses (sync for API)
+        let seed = request.seed.unwrap_or_else(rand::random);
+        let responses = if let Some(queue) = queue {
+            let jobs = (0..n).map(|i| model.chat_sequence(&context, seed.wrapping_add(i as u32))).collect();
+            // The jobs keep their own copy of the token
+            model.set_cancellation(None);
+            drop(model);
+            run_sequences(&queue, jobs, |_| {})?
+        } else {
+            let responses = if images.is_empty() {
+                model.generate_n(&context, n, seed)
+            } else {
+                (0..n)
+                    .map(|i| model.generate_with_images(&context, &images, seed.wrapping_add(i as u32), |_| {}))
+                    .collect::<Result<Vec<_>, _>>()
+            };
+            model.set_cancellation(None);
+            responses?
         };
 
-        context.add_message(ChatMessage {
-            role,
-            content: message.content.clone(),
-        });
+        // Every choice shares the prompt, so it is only counted once
+        let prompt_tokens = responses[0].prompt_tokens;
+        let completion_tokens = responses.iter().map(|r| r.completion_tokens).sum::<usize>();
+
+        let choices: Vec<ChatChoice> = responses
+            .into_iter()
+            .enumerate()
+            .map(|(index, completion)| ChatChoice {
+                index,
+                message: ChatResponseMessage {
+                    role: "assistant".to_string(),
+                    content: completion.text,
+                },
+                finish_reason: completion.finish_reason,
+            })
+            .collect();
+
+        let chat_response = ChatResponse {
+            message: ChatResponseMessage {
+                role: "assistant".to_string(),
+                content: choices[0].message.content.clone(),
+            },
+            choices,
+            usage: TokenUsage {
+                prompt_tokens,
+                completion_tokens,
+                total_tokens: prompt_tokens + completion_tokens,
+            },
+        };
+
+        Ok(chat_response)
+    })
+    .await?;
+    touch_model(&state, &model_name, keep_alive);
+    access.set_usage(chat_response.usage);
+
+    if query.raw {
+        Ok(Json(chat_response).into_response())
+    } else {
+        Ok(Json(ApiResponse::success(chat_response)).into_response())
     }
+}
 
-    // Generate response (sync for API)
-    let response = model.generate_sync(&context).map_err(|e| ApiError {
-        status: StatusCode::INTERNAL_SERVER_ERROR,
-        message: format!("Failed to generate response: {}", e),
-    })?;
+/// Query parameters for non-streaming chat
+#[derive(Deserialize)]
+struct ChatQuery {
+    /// Return the response without the `ApiResponse` envelope
+    #[serde(default)]
+    raw: bool,
+}
+
+/// Rendered prompt for a chat request
+#[derive(Serialize)]
+struct PreviewResponse {
+    /// Template the prompt was formatted with
+    template: PromptTemplate,
+    /// The prompt exactly as it would be sent to the model
+    prompt: String,
+}
+
+/// Preview endpoint: renders the prompt for a chat request without generating
+async fn preview(
+    State(state): State<AppState>,
+    Json(mut request): Json<ChatRequest>,
+) -> Result<Json<ApiResponse<PreviewResponse>>, ApiError> {
+    request.model = state.resolve_model(&request.model)?;
+    let model = load_model(&request.model, &state).await?;
+    let preview = run_blocking(None, move || {
+        // Check the parameters as chat would, without applying them
+        chat_sampling(&request)?;
+        let model = model.lock().unwrap();
+        let context = build_chat_context(&model, &request)?;
+
+        Ok(PreviewResponse {
+            template: context.template.clone().unwrap_or_else(|| model.template().clone()),
+            prompt: model.format_prompt(&context),
+        })
+    })
+    .await?;
+
+    Ok(Json(ApiResponse::success(preview)))
+}
+
+/// Raw completion request
+#[derive(Deserialize)]
+struct CompletionRequest {
+    /// Model to continue the prompt with; the server's default model if left out
+    #[serde(default)]
+    model: String,
+    prompt: String,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    /// Sample from only the `top_k` most likely tokens (0 = off)
+    top_k: Option<usize>,
+    max_tokens: Option<usize>,
+}
 
-    // Create token usage (estimated)
-    let prompt_tokens = context.format_prompt().len() / 4; // Rough estimate
-    let completion_tokens = response.len() / 4; // Rough estimate
-
-    let chat_response = ChatResponse {
-        message: ChatResponseMessage {
-            role: "assistant".to_string(),
-            content: response,
-        },
-        usage: TokenUsage {
-            prompt_tokens,
-            completion_tokens,
-            total_tokens: prompt_tokens + completion_tokens,
-        },
+/// Raw completion response
+#[derive(Serialize)]
+struct CompletionResponse {
+    text: String,
+    usage: TokenUsage,
+}
+
+/// Completion endpoint: continues the prompt as-is, without a chat template
+async fn completions(
+    State(state): State<AppState>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    Extension(access): Extension<AccessLogEntry>,
+    Json(mut request): Json<CompletionRequest>,
+) -> Result<Json<ApiResponse<CompletionResponse>>, ApiError> {
+    request.model = state.resolve_model(&request.model)?;
+    access.set_model(&request.model);
+    let model = load_model(&request.model, &state).await?;
+    let queue = state.sequence_queue(&request.model);
+    let model_name = request.model.clone();
+    let anti_repeat_window = state.model_config.anti_repeat_window;
+    let generation = state.register_generation(&request_id)?;
+    let slot = state.acquire_generation_slot().await?;
+    let completion = run_blocking(state.nice, move || {
+        let _slot = slot;
+        let mut model = model.lock().unwrap();
+        model.set_cancellation(Some(generation.token.clone()));
+
+        // Earlier requests' settings mustn't apply to this one
+        model.reset_sampling();
+        if let Some(temp) = request.temperature {
+            model.set_temperature(temp);
+        }
+
+        if let Some(top_p) = request.top_p {
+            model.set_top_p(top_p);
+        }
+
+        if let Some(top_k) = request.top_k {
+            model.set_top_k(top_k);
+        }
+
+        if let Some(max_tokens) = request.max_tokens {
+            model.set_max_tokens(max_tokens);
+        }
+
+        model.set_repeat_window(anti_repeat_window);
+
+        match queue {
+            Some(queue) => {
+                let job = model.completion_sequence(&request.prompt, rand::random());
+                model.set_cancellation(None);
+                drop(model);
+                Ok(run_sequences(&queue, vec![job], |_| {})?.remove(0))
+            }
+            None => {
+                let completion = model.complete(&request.prompt);
+                model.set_cancellation(None);
+                Ok(completion?)
+            }
+        }
+    })
+    .await?;
+    touch_model(&state, &model_name, None);
+
+    let usage = TokenUsage {
+        prompt_tokens: completion.prompt_tokens,
+        completion_tokens: completion.completion_tokens,
+        total_tokens: completion.prompt_tokens + completion.completion_tokens,
     };
+    access.set_usage(usage);
+    Ok(Json(ApiResponse::success(CompletionResponse {
+        text: completion.text,
+        usage,
+    })))
+}
+
+/// Single-page chat client, with its styles and script inline
+const WEB_UI: &str = include_str!("ui.html");
 
-    Ok(Json(ApiResponse::success(chat_response)))
+/// Serve the browser chat UI
+async fn web_ui() -> Html<&'static str> {
+    Html(WEB_UI)
 }
 
 /// Stream response chunk
 #[derive(Serialize)]
 struct ChatStreamResponse {
+    /// Id of the request, the same one returned in `X-Request-Id`
     id: String,
     model: String,
     choices: Vec<ChatStreamChoice>,
+    /// Only set on the usage chunk, whose `choices` is empty
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<TokenUsage>,
 }
 
 /// Stream choice
@@ -458,54 +1993,810 @@ struct ChatStreamResponse {
 struct ChatStreamChoice {
     delta: ChatStreamDelta,
     index: usize,
-    finish_reason: Option<String>,
+    finish_reason: Option<FinishReason>,
 }
 
 /// Stream delta
 #[derive(Serialize)]
 struct ChatStreamDelta {
+    /// Set on the first chunk only
+    #[serde(skip_serializing_if = "Option::is_none")]
     role: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     content: Option<String>,
 }
 
-/// Stream chat endpoint
+/// Event format of the chat stream
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum StreamFormat {
+    /// OpenAI-compatible chunks followed by `[DONE]`
+    #[default]
+    OpenAi,
+    /// One `{ "token", "done" }` event per piece of text and a final event
+    /// with `done` set and the token usage
+    Native,
+}
+
+impl StreamFormat {
+    /// The format picked by `?format=`, else by a `format=native` parameter
+    /// in the `Accept` header (e.g. `text/event-stream; format=native`)
+    fn from_request(query: Option<StreamFormat>, headers: &HeaderMap) -> Self {
+        if let Some(format) = query {
+            return format;
+        }
+        let wants_native = headers
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split([',', ';']))
+            .any(|part| part.trim().eq_ignore_ascii_case("format=native"));
+        if wants_native {
+            StreamFormat::Native
+        } else {
+            StreamFormat::OpenAi
+        }
+    }
+}
+
+/// How much text each stream event carries
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum StreamBuffer {
+    /// One event per piece of text the model produces, which may be part of a word
+    #[default]
+    Token,
+    /// Hold text back until it ends in whitespace, so events carry whole words
+    Word,
+}
+
+/// Query parameters for streaming chat
+#[derive(Deserialize)]
+struct ChatStreamQuery {
+    format: Option<StreamFormat>,
+    #[serde(default)]
+    buffer: StreamBuffer,
+}
+
+/// Accumulates streamed text and releases it at word boundaries. Pieces are
+/// whole UTF-8 characters already, so every release is valid UTF-8.
+#[derive(Default)]
+struct WordBuffer {
+    pending: String,
+}
+
+impl WordBuffer {
+    /// Add a piece, returning everything up to and including its last
+    /// whitespace once there is some
+    fn push(&mut self, piece: &str) -> Option<String> {
+        self.pending.push_str(piece);
+        let (index, whitespace) = self.pending.char_indices().rev().find(|(_, c)| c.is_whitespace())?;
+        let rest = self.pending.split_off(index + whitespace.len_utf8());
+        Some(std::mem::replace(&mut self.pending, rest))
+    }
+
+    /// Whatever is still held back, at the end of the stream
+    fn flush(&mut self) -> Option<String> {
+        (!self.pending.is_empty()).then(|| std::mem::take(&mut self.pending))
+    }
+}
+
+/// Event of the native stream format
+#[derive(Serialize)]
+struct NativeStreamEvent {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+    done: bool,
+    /// Only set on the final event
+    #[serde(skip_serializing_if = "Option::is_none")]
+    finish_reason: Option<FinishReason>,
+    /// Only set on the final event
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<TokenUsage>,
+}
+
+/// Stream chat endpoint: sends the reply as server-sent events, one chunk per
+/// piece of text, followed by a final chunk with the finish reason and `[DONE]`
+/// (or the native format's final event)
 async fn chat_stream(
-    State(_state): State<AppState>,
-    Json(_request): Json<ChatRequest>,
-) -> impl IntoResponse {
-    // This would implement SSE streaming, but for now we'll return a simple response
-    // indicating that streaming is not implemented yet
-    (
-        StatusCode::NOT_IMPLEMENTED,
-        Json(ApiResponse::<()>::error(
-            "Streaming responses not yet implemented",
-        )),
-    )
+    State(state): State<AppState>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    Extension(access): Extension<AccessLogEntry>,
+    Query(query): Query<ChatStreamQuery>,
+    headers: HeaderMap,
+    Json(mut request): Json<ChatRequest>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    let format = StreamFormat::from_request(query.format, &headers);
+    if request.n.is_some_and(|n| n != 1) {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "Streaming supports only n = 1"));
+    }
+
+    request.model = state.resolve_model(&request.model)?;
+    access.set_model(&request.model);
+    let model = load_model(&request.model, &state).await?;
+    let images = load_request_images(&request, &state).await?;
+    let anti_repeat_window = state.model_config.anti_repeat_window;
+
+    // Errors before generation starts become a normal error response, later
+    // ones can only be reported inside the stream
+    let (ready_sender, ready_receiver) = tokio::sync::oneshot::channel();
+    let (event_sender, event_receiver) = tokio::sync::mpsc::unbounded_channel();
+    let generation = state.register_generation(&request_id)?;
+    let slot = state.acquire_generation_slot().await?;
+    let span = tracing::Span::current();
+    let nice = state.nice;
+    spawn_generation(nice, move || {
+        let _entered = span.enter();
+        let _slot = slot;
+        let mut model = model.lock().unwrap();
+        let context = match prepare_chat(&mut model, &request, anti_repeat_window) {
+            Ok(context) => context,
+            Err(e) => {
+                let _ = ready_sender.send(Err(e));
+                return;
+            }
+        };
+        let images = images_in_prompt(&model, &context, images);
+        model.set_cancellation(Some(generation.token.clone()));
+        let _ = ready_sender.send(Ok(()));
+
+        let event = |choices: Vec<ChatStreamChoice>, usage: Option<TokenUsage>| {
+            let chunk = ChatStreamResponse {
+                id: request_id.clone(),
+                model: request.model.clone(),
+                choices,
+                usage,
+            };
+            Event::default().json_data(chunk).expect("chunks serialize to JSON")
+        };
+        let chunk = |delta: ChatStreamDelta, finish_reason: Option<FinishReason>| {
+            event(vec![ChatStreamChoice { delta, index: 0, finish_reason }], None)
+        };
+
+        let native = |event: NativeStreamEvent| {
+            Event::default().json_data(event).expect("events serialize to JSON")
+        };
+
+        let mut role = Some("assistant".to_string());
+        let mut send_piece = |piece: &str| {
+            let event = match format {
+                StreamFormat::OpenAi => chunk(
+                    ChatStreamDelta { role: role.take(), content: Some(piece.to_string()) },
+                    None,
+                ),
+                StreamFormat::Native => native(NativeStreamEvent {
+                    token: Some(piece.to_string()),
+                    done: false,
+                    finish_reason: None,
+                    usage: None,
+                }),
+            };
+            let sent = event_sender.send(event);
+            // A send only fails once the client has gone away, so stop generating
+            if sent.is_err() {
+                generation.token.cancel();
+            }
+        };
+        let mut words = (query.buffer == StreamBuffer::Word).then(WordBuffer::default);
+        let mut on_piece = |piece: &str| match words.as_mut() {
+            Some(words) => {
+                if let Some(text) = words.push(piece) {
+                    send_piece(&text);
+                }
+            }
+            None => send_piece(piece),
+        };
+        // Images go through the projector one request at a time
+        let result = match state.sequence_queue(&request.model).filter(|_| images.is_empty()) {
+            Some(queue) => {
+                let job = model.chat_sequence(&context, rand::random());
+                model.set_cancellation(None);
+                drop(model);
+                run_sequences(&queue, vec![job], &mut on_piece).map(|mut replies| replies.remove(0))
+            }
+            None => {
+                let result = model.generate_with_images(&context, &images, rand::random(), &mut on_piece);
+                model.set_cancellation(None);
+                drop(model);
+                result
+            }
+        };
+        if let Some(text) = words.as_mut().and_then(WordBuffer::flush) {
+            send_piece(&text);
+        }
+        match result {
+            Ok(completion) => {
+                let usage = TokenUsage {
+                    prompt_tokens: completion.prompt_tokens,
+                    completion_tokens: completion.completion_tokens,
+                    total_tokens: completion.prompt_tokens + completion.completion_tokens,
+                };
+                access.set_usage(usage);
+                match format {
+                    StreamFormat::OpenAi => {
+                        let _ = event_sender.send(chunk(
+                            ChatStreamDelta { role: role.take(), content: None },
+                            Some(completion.finish_reason),
+                        ));
+                        if request.stream_options.include_usage {
+                            let _ = event_sender.send(event(Vec::new(), Some(usage)));
+                        }
+                        let _ = event_sender.send(Event::default().data("[DONE]"));
+                    }
+                    StreamFormat::Native => {
+                        let _ = event_sender.send(native(NativeStreamEvent {
+                            token: None,
+                            done: true,
+                            finish_reason: Some(completion.finish_reason),
+                            usage: Some(usage),
+                        }));
+                    }
+                }
+            }
+            Err(e) => {
+                warn!("Streaming generation failed: {}", e);
+                let _ = event_sender.send(Event::default().event("error").data(e.to_string()));
+            }
+        }
+        touch_model(&state, &request.model, request.keep_alive);
+    });
+
+    ready_receiver.await.map_err(|_| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Model task failed"))??;
+
+    let events = futures::stream::unfold(event_receiver, |mut receiver| async move {
+        receiver.recv().await.map(|event| (Ok(event), receiver))
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// Request to cancel a generation
+#[derive(Deserialize)]
+struct CancelRequest {
+    /// `X-Request-Id` of the chat or completion request to stop
+    request_id: String,
+}
+
+/// Cancel endpoint: stops an in-flight generation at the next token, and the
+/// cancelled request returns the text generated so far
+async fn cancel_chat(
+    State(state): State<AppState>,
+    Json(request): Json<CancelRequest>,
+) -> Result<Json<ApiResponse<String>>, ApiError> {
+    let generations = state.generations.lock().unwrap();
+    let token = generations.get(&request.request_id).ok_or_else(|| ApiError::new(
+        StatusCode::NOT_FOUND,
+        format!("No generation in progress for request {}", request.request_id),
+    ))?;
+    token.cancel();
+    info!("Cancelled generation for request {}", request.request_id);
+
+    Ok(Json(ApiResponse::success(format!(
+        "Generation for request {} cancelled",
+        request.request_id
+    ))))
+}
+
+/// Header carrying the id used to trace a request through the logs
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Id of the request being handled, available to handlers as an extension
+#[derive(Clone)]
+struct RequestId(String);
+
+/// Middleware that tags each request with an id (the client's `X-Request-Id`
+/// or a new UUID), logs everything it does inside a span carrying the id and
+/// echoes the id back in the response
+async fn request_id(mut request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty() && value.len() <= 128)
+        .map(str::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    
+    let span = info_span!(
+        "request",
+        id = %id,
+        method = %request.method(),
+        path = %request.uri().path(),
+    );
+    request.extensions_mut().insert(RequestId(id.clone()));
+    
+    let mut response = next.run(request).instrument(span).await;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+/// What a handler reports about its request for the access log, shared with
+/// the `access_log` middleware through the request extensions
+#[derive(Clone, Default)]
+struct AccessLogEntry(Arc<Mutex<AccessLogFields>>);
+
+#[derive(Default)]
+struct AccessLogFields {
+    model: Option<String>,
+    usage: Option<TokenUsage>,
+}
+
+impl AccessLogEntry {
+    fn set_model(&self, model: &str) {
+        self.0.lock().unwrap().model = Some(model.to_string());
+    }
+
+    fn set_usage(&self, usage: TokenUsage) {
+        self.0.lock().unwrap().usage = Some(usage);
+    }
+}
+
+/// Logs one access log event once a request is done, for streams when the
+/// stream ends or the client goes away
+struct AccessLogGuard {
+    entry: AccessLogEntry,
+    span: tracing::Span,
+    method: String,
+    path: String,
+    status: u16,
+    started: Instant,
+}
+
+impl Drop for AccessLogGuard {
+    fn drop(&mut self) {
+        let _entered = self.span.enter();
+        let fields = self.entry.0.lock().unwrap();
+        let usage = fields.usage.as_ref();
+        info!(
+            target: "rustllm::access",
+            method = %self.method,
+            path = %self.path,
+            status = self.status,
+            duration_ms = self.started.elapsed().as_millis() as u64,
+            model = fields.model.as_deref().unwrap_or(""),
+            prompt_tokens = usage.map_or(0, |usage| usage.prompt_tokens),
+            completion_tokens = usage.map_or(0, |usage| usage.completion_tokens),
+            "request completed"
+        );
+    }
+}
+
+/// Middleware that logs the method, path, status, duration, model and token
+/// counts of every request as structured fields (target `rustllm::access`)
+async fn access_log(mut request: Request, next: Next) -> Response {
+    let entry = AccessLogEntry::default();
+    request.extensions_mut().insert(entry.clone());
+    let method = request.method().to_string();
+    let path = request.uri().path().to_string();
+    let started = Instant::now();
+
+    let response = next.run(request).await;
+    let guard = AccessLogGuard {
+        entry,
+        span: tracing::Span::current(),
+        method,
+        path,
+        status: response.status().as_u16(),
+        started,
+    };
+    let is_stream = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|value| value.as_bytes().starts_with(b"text/event-stream"));
+    if !is_stream {
+        return response;
+    }
+
+    // Hold the guard in the body so it's dropped after the last event
+    let (parts, body) = response.into_parts();
+    let body = body.into_data_stream().map(move |chunk| {
+        let _ = &guard;
+        chunk
+    });
+    Response::from_parts(parts, axum::body::Body::from_stream(body))
+}
+
+/// Token buckets limiting how many requests each client IP can make
+struct RateLimiter {
+    /// Requests a client can make in a burst, refilled over a minute
+    capacity: f64,
+    /// Tokens added back per second
+    refill_per_sec: f64,
+    trust_proxy: bool,
+    buckets: Mutex<HashMap<IpAddr, TokenBucket>>,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Buckets kept before idle ones are dropped; a bucket idle for a minute is
+/// full again, so dropping it changes nothing
+const RATE_LIMIT_PRUNE_THRESHOLD: usize = 1024;
+
+impl RateLimiter {
+    fn new(per_minute: u32, trust_proxy: bool) -> Self {
+        Self {
+            capacity: per_minute as f64,
+            refill_per_sec: per_minute as f64 / 60.0,
+            trust_proxy,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The client a request counts against: the last `X-Forwarded-For` entry
+    /// (added by the trusted proxy) or else the peer address. Requests over the
+    /// Unix socket have no peer address.
+    fn client_ip(&self, request: &Request) -> Option<IpAddr> {
+        if self.trust_proxy {
+            let forwarded = request
+                .headers()
+                .get_all("x-forwarded-for")
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .last()
+                .and_then(|ip| ip.trim().parse().ok());
+            if forwarded.is_some() {
+                return forwarded;
+            }
+        }
+        request
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip())
+    }
+
+    /// Take a token for `ip`, or return how long until one is available
+    fn acquire(&self, ip: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= RATE_LIMIT_PRUNE_THRESHOLD {
+            buckets.retain(|_, bucket| now.duration_since(bucket.updated) < Duration::from_secs(60));
+        }
+        let bucket = buckets.entry(ip).or_insert(TokenBucket { tokens: self.capacity, updated: now });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.refill_per_sec))
+        }
+    }
+}
+
+/// Middleware that answers 429 with `Retry-After` once a client IP has used
+/// up its requests; the health check is never limited
+async fn rate_limit(State(limiter): State<Arc<RateLimiter>>, request: Request, next: Next) -> Response {
+    if request.uri().path() == "/api/health" {
+        return next.run(request).await;
+    }
+    let Some(ip) = limiter.client_ip(&request) else {
+        return next.run(request).await;
+    };
+    match limiter.acquire(ip) {
+        Ok(()) => next.run(request).await,
+        Err(wait) => {
+            let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+            warn!("Rate limit exceeded for {}, retry in {}s", ip, retry_after);
+            let mut response = ApiError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                format!("Rate limit exceeded, retry in {} seconds", retry_after),
+            )
+            .into_response();
+            response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+            response
+        }
+    }
+}
+
+/// Run blocking model work (loading, generation) off the async workers so it
+/// doesn't stall them while they serve other requests
+///
+/// With `nice` set the work gets a thread of its own at that priority (see
+/// `spawn_generation`).
+async fn run_blocking<T, F>(nice: Option<i32>, work: F) -> Result<T, ApiError>
+where
+    F: FnOnce() -> Result<T, ApiError> + Send + 'static,
+    T: Send + 'static,
+{
+    // Keep the request span so logs from the blocking thread carry its id
+    let span = tracing::Span::current();
+    spawn_generation(nice, move || span.in_scope(work))
+        .await
+        .map_err(|_| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Model task failed: it panicked"))?
+}
+
+/// Run `work` on tokio's blocking pool, or with `nice` set on a new thread at
+/// that priority, and get its result from the returned channel
+///
+/// Pool threads are reused for model loads and file I/O, and an unprivileged
+/// process can't lower a nice value again, so niced work never runs on them.
+/// The channel closes without a value if `work` panics.
+fn spawn_generation<T, F>(nice: Option<i32>, work: F) -> tokio::sync::oneshot::Receiver<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (sender, receiver) = tokio::sync::oneshot::channel();
+    let run = move || {
+        let _ = sender.send(work());
+    };
+    match nice {
+        Some(nice) => {
+            std::thread::spawn(move || {
+                set_generation_priority(Some(nice));
+                run();
+            });
+        }
+        None => {
+            tokio::task::spawn_blocking(run);
+        }
+    }
+    receiver
+}
+
+/// Run the calling thread at nice value `nice`, if set
+///
+/// Only for threads that do nothing but generate, since the priority stays;
+/// llama.cpp's worker threads inherit the priority of the thread that starts
+/// them. Failing (e.g. a negative value without the privilege) only warns.
+fn set_generation_priority(nice: Option<i32>) {
+    if let Some(nice) = nice {
+        if let Err(e) = crate::utils::set_current_thread_nice(nice) {
+            warn!("Failed to set generation thread priority to nice {}: {}", nice, e);
+        }
+    }
 }
 
 /// Load a model from cache or from disk
+///
+/// Requests for a model that is still loading wait for that load instead of
+/// starting their own. The load runs in its own task, so a request that is
+/// cancelled or disconnects doesn't abandon it: the model is cached for the
+/// next request all the same. A failed load is forgotten, and the next request
+/// tries again.
 async fn load_model(model_name: &str, state: &AppState) -> Result<Arc<Mutex<Model>>, ApiError> {
-    // Check if model is already loaded
-    {
-        let models = state.models.lock().unwrap();
-        if let Some(model) = models.get(model_name) {
-            return Ok(Arc::clone(model));
+    let load = {
+        let mut loading = state.loading.lock().unwrap();
+        // Checked under the `loading` lock, so a load that just finished is seen
+        // either here or in `loading`
+        if let Some(cached) = state.models.lock().unwrap().get_mut(model_name) {
+            cached.last_used = Instant::now();
+            return Ok(Arc::clone(&cached.model));
         }
+        loading
+            .entry(model_name.to_string())
+            .or_insert_with(|| {
+                let state = state.clone();
+                let name = model_name.to_string();
+                let task = tokio::spawn(
+                    async move {
+                        let result = load_and_cache(&name, &state).await;
+                        state.loading.lock().unwrap().remove(&name);
+                        result
+                    }
+                    .instrument(tracing::Span::current()),
+                );
+                async move {
+                    task.await.unwrap_or_else(|e| Err(ApiError::new(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        format!("Model load task failed: {}", e),
+                    )))
+                }
+                .boxed()
+                .shared()
+            })
+            .clone()
+    };
+    load.await
+}
+
+/// Start the thread that runs a model's sequences, and return its queue
+///
+/// Jobs start as soon as one of the model's sequences is free, and each step
+/// adds a token to every running sequence in one decode. The model is unlocked
+/// between steps, so requests can queue more jobs meanwhile. The thread ends
+/// once the model has left the cache and the queue's senders are gone.
+fn spawn_sequence_worker(model_name: &str, model: Weak<Mutex<Model>>, nice: Option<i32>) -> mpsc::Sender<SequenceJob> {
+    let (sender, jobs) = mpsc::channel::<SequenceJob>();
+    let span = info_span!("sequences", model = %model_name);
+    std::thread::spawn(move || {
+        let _entered = span.enter();
+        set_generation_priority(nice);
+        let mut waiting = std::collections::VecDeque::new();
+        let mut running = 0;
+        loop {
+            // Only block on the queue while there's nothing to step
+            if running == 0 && waiting.is_empty() {
+                match jobs.recv() {
+                    Ok(job) => waiting.push_back(job),
+                    Err(_) => break,
+                }
+            }
+            waiting.extend(jobs.try_iter());
+
+            let Some(model) = model.upgrade() else {
+                break;
+            };
+            let mut model = model.lock().unwrap();
+            while model.active_sequences() < model.parallel() {
+                match waiting.pop_front() {
+                    Some(job) => model.start_sequence(job),
+                    None => break,
+                }
+            }
+            model.step_sequences();
+            running = model.active_sequences();
+        }
+        info!("Sequence worker stopped");
+    });
+    sender
+}
+
+/// Queue jobs on a model's sequence worker and wait for their replies,
+/// passing the first reply's pieces to `on_token` as they arrive
+fn run_sequences(
+    queue: &mpsc::Sender<SequenceJob>,
+    jobs: Vec<(SequenceJob, mpsc::Receiver<SequenceEvent>)>,
+    mut on_token: impl FnMut(&str),
+) -> Result<Vec<Completion>, ModelError> {
+    let stopped = || ModelError::GenerationFailed("The model's sequence worker stopped".to_string());
+    let mut replies = Vec::new();
+    for (job, events) in jobs {
+        queue.send(job).map_err(|_| stopped())?;
+        replies.push(events);
     }
+    replies
+        .into_iter()
+        .enumerate()
+        .map(|(index, events)| {
+            for event in events {
+                match event {
+                    SequenceEvent::Token(piece) if index == 0 => on_token(&piece),
+                    SequenceEvent::Token(_) => {}
+                    SequenceEvent::Done(result) => return result,
+                }
+            }
+            Err(stopped())
+        })
+        .collect()
+}
 
-    // Load the model from disk
-    let model = model::load_model(model_name, &state.models_dir).map_err(|e| ApiError {
-        status: StatusCode::INTERNAL_SERVER_ERROR,
-        message: format!("Failed to load model: {}", e),
-    })?;
+/// Load a model from disk and add it to the cache
+async fn load_and_cache(model_name: &str, state: &AppState) -> Result<Arc<Mutex<Model>>, ApiError> {
+    let name = model_name.to_string();
+    let store = state.store.clone();
+    let mut model_config = state.model_config.clone();
+    let model = run_blocking(None, move || {
+        // A registry entry's setting for the model wins over --parallel
+        let registry_parallel = store.resolve(&name).ok()
+            .and_then(|path| path.file_name().map(|filename| filename.to_string_lossy().into_owned()))
+            .and_then(|filename| model::download::registry_entry_for_file(&filename))
+            .and_then(|info| info.parallel);
+        if let Some(parallel) = registry_parallel {
+            model_config.parallel = parallel;
+        }
+        Ok(model::load_model(&name, store.as_ref(), model_config)?)
+    })
+    .await?;
+    let path = model.model_path().to_path_buf();
+    let memory = model.memory_footprint();
+    let context_size = model.get_config().context_size;
+    let parallel = model.parallel();
+    let image_support = model.check_image_support().map_err(ApiError::from);
 
     let model = Arc::new(Mutex::new(model));
+    let sequences = (parallel > 1).then(|| spawn_sequence_worker(model_name, Arc::downgrade(&model), state.nice));
 
     // Cache the model
     {
         let mut models = state.models.lock().unwrap();
-        models.insert(model_name.to_string(), Arc::clone(&model));
+        models.insert(
+            model_name.to_string(),
+            CachedModel {
+                model: Arc::clone(&model),
+                path,
+                memory,
+                context_size,
+                loaded_at: chrono::Utc::now(),
+                last_used: Instant::now(),
+                keep_alive: None,
+                sequences,
+                image_support,
+            },
+        );
     }
 
     Ok(model)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: &str, content: &str) -> ChatRequestMessage {
+        ChatRequestMessage { role: role.to_string(), content: MessageContent::Text(content.to_string()), name: None }
+    }
+
+    #[test]
+    fn system_message_after_user_stays_in_place() {
+        let mut context = ChatContext::new("You are a helpful assistant.");
+        let messages = [
+            message("user", "Hi"),
+            message("system", "Answer in French."),
+            message("user", "What is the capital of Italy?"),
+        ];
+
+        assert!(add_request_messages(&mut context, &messages).is_ok());
+
+        assert_eq!(context.system_prompt, "You are a helpful assistant.");
+        assert_eq!(
+            context.format_prompt_with_template(&PromptTemplate::ChatML),
+            "<|im_start|>system\nYou are a helpful assistant.<|im_end|>\n\
+             <|im_start|>user\nHi<|im_end|>\n\
+             <|im_start|>system\nAnswer in French.<|im_end|>\n\
+             <|im_start|>user\nWhat is the capital of Italy?<|im_end|>\n\
+             <|im_start|>assistant\n"
+        );
+    }
+
+    #[test]
+    fn leading_system_message_is_the_system_prompt() {
+        let mut context = ChatContext::default();
+        let messages = [message("system", "Be brief."), message("user", "Hi")];
+
+        assert!(add_request_messages(&mut context, &messages).is_ok());
+
+        assert_eq!(context.system_prompt, "Be brief.");
+        assert_eq!(context.messages.len(), 1);
+    }
+
+    #[test]
+    fn content_parts_mark_where_images_go() {
+        let message: ChatRequestMessage = serde_json::from_value(serde_json::json!({
+            "role": "user",
+            "content": [
+                {"type": "text", "text": "What is in this picture?"},
+                {"type": "image_url", "image_url": {"url": "data:image/png;base64,iVBORw0KGgo="}},
+            ],
+        }))
+        .unwrap();
+
+        assert_eq!(message.content.text(), format!("What is in this picture?\n{}", IMAGE_MARKER));
+        assert_eq!(message.content.image_urls().collect::<Vec<_>>(), ["data:image/png;base64,iVBORw0KGgo="]);
+    }
+
+    #[test]
+    fn keep_alive_parses_seconds_and_durations() {
+        let keep_alive = |value: serde_json::Value| serde_json::from_value::<ModelKeepAlive>(value);
+
+        assert_eq!(keep_alive(serde_json::json!(30)).unwrap(), ModelKeepAlive::For(Duration::from_secs(30)));
+        assert_eq!(keep_alive(serde_json::json!("90")).unwrap(), ModelKeepAlive::For(Duration::from_secs(90)));
+        assert_eq!(keep_alive(serde_json::json!("5m")).unwrap(), ModelKeepAlive::For(Duration::from_secs(300)));
+        assert_eq!(keep_alive(serde_json::json!("1h30m")).unwrap(), ModelKeepAlive::For(Duration::from_secs(5400)));
+        assert_eq!(keep_alive(serde_json::json!(0)).unwrap(), ModelKeepAlive::For(Duration::ZERO));
+        assert_eq!(keep_alive(serde_json::json!("0")).unwrap(), ModelKeepAlive::For(Duration::ZERO));
+    }
+
+    #[test]
+    fn negative_keep_alive_is_forever() {
+        let keep_alive = |value: serde_json::Value| serde_json::from_value::<ModelKeepAlive>(value);
+
+        assert_eq!(keep_alive(serde_json::json!(-1)).unwrap(), ModelKeepAlive::Forever);
+        assert_eq!(keep_alive(serde_json::json!("-1")).unwrap(), ModelKeepAlive::Forever);
+        assert_eq!(keep_alive(serde_json::json!("-5m")).unwrap(), ModelKeepAlive::Forever);
+        for invalid in ["-", "-abc", "-5x", "five minutes", "5 m", ""] {
+            assert!(keep_alive(serde_json::json!(invalid)).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn data_uri_decodes_base64() {
+        assert_eq!(decode_data_uri("image/png;base64,iVBORw0KGgo=").unwrap(), b"\x89PNG\r\n\x1a\n");
+        assert!(decode_data_uri("image/png,rawdata").is_err());
+    }
+}
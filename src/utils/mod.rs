@@ -39,6 +39,19 @@ pub fn format_file_size(size_bytes: u64) -> String {
     }
 }
 
+/// Format a parameter count the way model names do, e.g. `7.24B` or `135M`
+pub fn format_parameter_count(count: u64) -> String {
+    if count >= 1_000_000_000 {
+        format!("{:.2}B", count as f64 / 1e9)
+    } else if count >= 1_000_000 {
+        format!("{:.0}M", count as f64 / 1e6)
+    } else if count >= 1_000 {
+        format!("{:.0}K", count as f64 / 1e3)
+    } else {
+        count.to_string()
+    }
+}
+
 /// Format user and assistant messages with colors
 pub fn format_message(role: &str, content: &str) -> String {
     match role.to_lowercase().as_str() {
@@ -116,12 +129,15 @@ pub fn get_temp_dir() -> Result<PathBuf> {
 }
 
 /// Sanitize a filename by removing invalid characters
+///
+/// Also strips the trailing dots and spaces that Windows silently drops.
 pub fn sanitize_filename(name: &str) -> String {
     let invalid_chars = ['/', '\\', ':', '*', '?', '"', '<', '>', '|'];
     
-    name.chars()
-        .map(|c| if invalid_chars.contains(&c) { '_' } else { c })
-        .collect()
+    let sanitized: String = name.chars()
+        .map(|c| if invalid_chars.contains(&c) || c.is_control() { '_' } else { c })
+        .collect();
+    sanitized.trim_end_matches(['.', ' ']).to_string()
 }
 
 /// Clean temporary files older than specified days
@@ -155,4 +171,62 @@ pub fn clean_temp_files(days: u64) -> Result<()> {
     }
     
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Memory available for new allocations without swapping, in bytes
+///
+/// Read from `MemAvailable` in `/proc/meminfo`, so only known on Linux.
+#[cfg_attr(not(feature = "inference"), allow(dead_code))]
+pub fn available_memory_bytes() -> Option<u64> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|line| line.starts_with("MemAvailable:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+/// Set the scheduling priority of the calling thread to the Unix nice value
+/// `nice`, from -20 (highest) to 19 (lowest); raising it needs privileges
+///
+/// Linux keeps a nice value per thread. Other Unix systems apply it to the
+/// whole process, and on Windows it maps to the nearest thread priority.
+pub fn set_current_thread_nice(nice: i32) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        #[cfg(target_os = "linux")]
+        let who = unsafe { libc::syscall(libc::SYS_gettid) } as libc::id_t;
+        #[cfg(not(target_os = "linux"))]
+        let who = 0;
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS, who, nice) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    #[cfg(windows)]
+    {
+        extern "system" {
+            fn GetCurrentThread() -> isize;
+            fn SetThreadPriority(thread: isize, priority: i32) -> i32;
+        }
+        // THREAD_PRIORITY_LOWEST to THREAD_PRIORITY_HIGHEST
+        let priority = match nice {
+            10.. => -2,
+            1..=9 => -1,
+            0 => 0,
+            -9..=-1 => 1,
+            _ => 2,
+        };
+        if unsafe { SetThreadPriority(GetCurrentThread(), priority) } == 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Memory this process currently has resident, in bytes
+///
+/// Read from `VmRSS` in `/proc/self/status`, so only known on Linux.
+pub fn resident_memory_bytes() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}